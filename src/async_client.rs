@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    future, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket},
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+    time,
+};
+
+use crate::{
+    block::{szx_for, MAX_BLOCK_SIZE},
+    endpoint::{Endpoint, Event},
+    no_response::NO_RESPONSE_WAIT,
+    request::{Request, RequestMethod},
+    response::Response,
+    token::{RandomToken, TokenGenerator},
+    transfer::{Exchange, Step, Transfer, Transfers},
+    transmission::TransmissionParameters,
+};
+
+/// the endpoint and the requests waiting on what it reports
+struct Shared {
+    endpoint: Endpoint,
+    /// events of each open token
+    waiters: HashMap<Vec<u8>, mpsc::UnboundedSender<Event>>,
+    /// replies to each CoAP ping, by message id
+    pings: HashMap<u16, mpsc::UnboundedSender<Event>>,
+}
+
+impl Shared {
+    /// hand every event to whoever waits on it, events nobody waits on are dropped
+    fn dispatch(&mut self) {
+        while let Some(event) = self.endpoint.poll_event() {
+            let waiter = match &event {
+                Event::Pong { message_id, .. } | Event::PingTimedOut { message_id, .. } => self.pings.remove(message_id),
+                _ => event.token().and_then(|token| self.waiters.get(token)).cloned(),
+            };
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(event);
+            }
+        }
+    }
+}
+
+/// closes the token of a request once its future completes or is dropped
+struct Open<'a> {
+    shared: &'a Mutex<Shared>,
+    token: Vec<u8>,
+}
+
+impl Drop for Open<'_> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.waiters.remove(&self.token);
+        shared.endpoint.close(&self.token, Instant::now());
+    }
+}
+
+/// CoAP client for tokio, a driver around the same sans-IO `Endpoint` as
+/// the blocking `CoapClient`
+///
+/// a task spawned on the runtime owns the socket: it sends what the
+/// endpoint queues, feeds it the datagrams that arrive and its timeouts, and
+/// hands each event to the request waiting on its token, so any number of
+/// requests can be in flight at once. bodies go block-wise (RFC 7959) both
+/// ways, 4.01 with Echo (RFC 9175) is answered and No-Response (RFC 7967)
+/// is waited out as by the blocking client; Q-Block and the response cache
+/// are left to the blocking client
+pub struct AsyncCoapClient {
+    socket: Arc<UdpSocket>,
+    shared: Arc<Mutex<Shared>>,
+    /// tells the driver the endpoint has something new to send or wait for
+    wake: Arc<Notify>,
+    driver: JoinHandle<()>,
+    timeout: Duration,
+    no_response_wait: Duration,
+    tokens: Mutex<Box<dyn TokenGenerator + Send>>,
+    block1_size: usize,
+    transfers: Transfers,
+}
+
+impl AsyncCoapClient {
+    /// client on a UDP socket bound to `local`, which must be called within a
+    /// tokio runtime; only peers of the family of `local` can be reached
+    pub async fn bind<A: ToSocketAddrs>(local: A) -> io::Result<AsyncCoapClient> {
+        let socket = Arc::new(UdpSocket::bind(local).await?);
+        let shared = Arc::new(Mutex::new(Shared {
+            endpoint: Endpoint::new(),
+            waiters: HashMap::new(),
+            pings: HashMap::new(),
+        }));
+        let wake = Arc::new(Notify::new());
+        let driver = tokio::spawn(drive(socket.clone(), shared.clone(), wake.clone()));
+        Ok(AsyncCoapClient {
+            socket,
+            shared,
+            wake,
+            driver,
+            timeout: Duration::from_millis(247000),
+            no_response_wait: NO_RESPONSE_WAIT,
+            tokens: Mutex::new(Box::new(RandomToken::default())),
+            block1_size: MAX_BLOCK_SIZE,
+            transfers: Transfers::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// timeout in milliseconds for waiting on a response
+    pub fn set_timeout(&mut self, timeout: u64) {
+        self.timeout = Duration::from_millis(timeout);
+    }
+
+    /// how long to wait for a response No-Response may have suppressed once
+    /// the request is acknowledged, or sent as NON, 5 s by default; the
+    /// request timeout still applies when it is shorter
+    pub fn set_no_response_wait(&mut self, wait: Duration) {
+        self.no_response_wait = wait;
+    }
+
+    /// request bodies larger than this are sent block-wise with Block1,
+    /// rounded down to a power of two between 16 and 1024
+    pub fn set_block1_size(&mut self, size: usize) {
+        self.block1_size = 1 << (szx_for(size) + 4);
+    }
+
+    /// transmission parameters of RFC 7252 section 4.8
+    pub fn set_transmission_parameters(&self, params: TransmissionParameters) {
+        self.shared.lock().unwrap().endpoint.set_transmission_parameters(params);
+    }
+
+    /// generator for the tokens of requests that do not carry one
+    pub fn set_token_generator<G: TokenGenerator + Send + 'static>(&self, generator: G) {
+        *self.tokens.lock().unwrap() = Box::new(generator);
+    }
+
+    /// request for `uri` to be changed as needed before handing it to
+    /// `send_request`
+    pub fn new_request(&self, method: RequestMethod, uri: &str) -> io::Result<Request> {
+        let mut req = Request::for_uri(uri, 0)?;
+        req.set_code(method);
+        Ok(req)
+    }
+
+    pub async fn get(&self, uri: &str) -> io::Result<Response> {
+        self.send(self.new_request(RequestMethod::Get, uri)?).await
+    }
+
+    pub async fn post(&self, uri: &str, body: Vec<u8>) -> io::Result<Response> {
+        self.request_with_body(RequestMethod::Post, uri, body).await
+    }
+
+    pub async fn put(&self, uri: &str, body: Vec<u8>) -> io::Result<Response> {
+        self.request_with_body(RequestMethod::Put, uri, body).await
+    }
+
+    pub async fn delete(&self, uri: &str) -> io::Result<Response> {
+        self.send(self.new_request(RequestMethod::Deleted, uri)?).await
+    }
+
+    async fn request_with_body(&self, method: RequestMethod, uri: &str, body: Vec<u8>) -> io::Result<Response> {
+        let mut req = self.new_request(method, uri)?;
+        req.set_body(body);
+        self.send(req).await
+    }
+
+    async fn send(&self, req: Request) -> io::Result<Response> {
+        self.send_request(req).await?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "response suppressed by No-Response"))
+    }
+
+    /// send a request, block-wise when the body does not fit one block and
+    /// collecting block-wise responses into one body; returns None only when
+    /// the response was suppressed by No-Response
+    pub async fn send_request(&self, req: Request) -> io::Result<Option<Response>> {
+        let peer = self.resolve(&req)?;
+        let max_message_size = self.shared.lock().unwrap().endpoint.get_max_message_size();
+        let mut transfer = Transfer::new(&self.transfers, req, max_message_size, self.block1_size, true)?;
+        let mut step = Step::Send(transfer.first());
+        loop {
+            match step {
+                Step::Send(message) => step = transfer.response(self.exchange(peer, &message).await?)?,
+                Step::Done(res) => return Ok(res),
+            }
+        }
+    }
+
+    /// send one message of a request and wait for its response
+    async fn exchange(&self, peer: SocketAddr, req: &Request) -> io::Result<Option<Response>> {
+        let token = match req.get_token() {
+            Some(token) => token.clone(),
+            None => self.tokens.lock().unwrap().generate(),
+        };
+        let now = Instant::now();
+        let mut exchange = Exchange::new(req, peer, token.clone(), now, self.timeout, self.no_response_wait);
+        if exchange.expects_nothing() {
+            // nothing comes back, the endpoint forgets the token at its deadline
+            exchange.start(&mut self.shared.lock().unwrap().endpoint, req, now)?;
+            self.wake.notify_one();
+            return Ok(None);
+        }
+        let (waiter, mut events) = mpsc::unbounded_channel();
+        let open = {
+            let mut shared = self.shared.lock().unwrap();
+            exchange.start(&mut shared.endpoint, req, now)?;
+            shared.waiters.insert(token.clone(), waiter);
+            Open { shared: &self.shared, token }
+        };
+        self.wake.notify_one();
+        let res = loop {
+            let event = events.recv().await
+                .ok_or_else(|| io::Error::other("client driver stopped"))?;
+            let done = exchange.event(event, &mut self.shared.lock().unwrap().endpoint, Instant::now());
+            match done {
+                Some(res) => break res,
+                // the endpoint may have a new deadline to wait for
+                None => self.wake.notify_one(),
+            }
+        };
+        drop(open);
+        self.wake.notify_one();
+        res
+    }
+
+    /// CoAP ping: send an empty confirmable message and wait for the RST,
+    /// returns the round trip time of the last transmission
+    pub async fn ping(&self, uri: &str) -> io::Result<Duration> {
+        let peer = self.resolve(&Request::for_uri(uri, 0)?)?;
+        let (waiter, mut events) = mpsc::unbounded_channel();
+        {
+            let mut shared = self.shared.lock().unwrap();
+            let msg_id = shared.endpoint.ping(peer, Instant::now())?;
+            shared.pings.insert(msg_id, waiter);
+        }
+        self.wake.notify_one();
+        match events.recv().await {
+            Some(Event::Pong { rtt, .. }) => Ok(rtt),
+            _ => Err(io::Error::new(io::ErrorKind::TimedOut, "no reply to CoAP ping")),
+        }
+    }
+
+    fn resolve(&self, req: &Request) -> io::Result<SocketAddr> {
+        req.resolve(Some(self.socket.local_addr()?.is_ipv4()))
+    }
+}
+
+impl Drop for AsyncCoapClient {
+    fn drop(&mut self) {
+        self.driver.abort();
+    }
+}
+
+/// the task driving the endpoint: send its datagrams, feed it what arrives
+/// and wake it for its timeouts, until the client is dropped
+async fn drive(socket: Arc<UdpSocket>, shared: Arc<Mutex<Shared>>, wake: Arc<Notify>) {
+    let mut buf = Vec::new();
+    loop {
+        let (transmits, timeout) = {
+            let mut shared = shared.lock().unwrap();
+            shared.endpoint.handle_timeout(Instant::now());
+            let mut transmits = Vec::new();
+            while let Some(transmit) = shared.endpoint.poll_transmit() {
+                transmits.push(transmit);
+            }
+            shared.dispatch();
+            // one byte more than fits, to tell a truncated datagram from a full one
+            buf.resize(shared.endpoint.get_max_message_size() + 1, 0);
+            (transmits, shared.endpoint.poll_timeout())
+        };
+        for transmit in transmits {
+            // a datagram that could not be sent is retransmitted or times out like a lost one
+            let _ = socket.send_to(&transmit.data, transmit.peer).await;
+        }
+        let sleep = async {
+            match timeout {
+                Some(at) => time::sleep_until(at.into()).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                if let Ok((len, from)) = received {
+                    shared.lock().unwrap().endpoint.handle_datagram(from, &buf[..len], Instant::now());
+                }
+            }
+            _ = sleep => {}
+            _ = wake.notified() => {}
+        }
+    }
+}
+
@@ -0,0 +1,87 @@
+use crate::common::{bytes_to_uint, uint_to_bytes};
+
+/// largest block size, SZX 6
+pub const MAX_BLOCK_SIZE: usize = 1024;
+
+/// default bound on a body reassembled from blocks
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// block-wise transfers a server keeps for one peer at a time
+pub const MAX_PEER_TRANSFERS: usize = 4;
+
+/// room kept for the Block1/Block2 and Request-Tag options and the payload marker
+const BLOCK_OPTIONS_LEN: usize = 16;
+
+/// value of a Block1/Block2 option, RFC 7959 section 2.2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockValue {
+    pub num: u32,
+    pub more: bool,
+    pub szx: u8,
+}
+
+impl BlockValue {
+
+    pub fn new(num: u32, more: bool, size: usize) -> BlockValue {
+        BlockValue { num, more, szx: szx_for(size) }
+    }
+
+    pub fn from_value(value: &[u8]) -> Option<BlockValue> {
+        if value.len() > 3 {
+            return None;
+        }
+        let v = bytes_to_uint(value);
+        let szx = (v & 0x7) as u8;
+        // SZX 7 is reserved
+        if szx == 7 {
+            return None;
+        }
+        Some(BlockValue { num: v >> 4, more: v & 0x8 != 0, szx })
+    }
+
+    pub fn to_value(&self) -> Vec<u8> {
+        uint_to_bytes(self.num << 4 | (self.more as u32) << 3 | self.szx as u32)
+    }
+
+    pub fn size(&self) -> usize {
+        1 << (self.szx + 4)
+    }
+
+    /// byte offset of this block in the whole body
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+}
+
+/// largest SZX whose block size does not exceed `size`
+pub fn szx_for(size: usize) -> u8 {
+    let size = size.clamp(16, MAX_BLOCK_SIZE);
+    (usize::BITS - 1 - size.leading_zeros()) as u8 - 4
+}
+
+/// largest block size whose message stays within `max_message_size`, when
+/// header, token and the other options take `header_len` bytes
+pub fn block_size_for(max_message_size: usize, header_len: usize) -> usize {
+    1 << (szx_for(max_message_size.saturating_sub(header_len + BLOCK_OPTIONS_LEN)) + 4)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::block::{block_size_for, szx_for, BlockValue};
+
+    #[test]
+    fn block_value() {
+        let block = BlockValue::new(5, true, 64);
+        assert_eq!(block.szx, 2);
+        assert_eq!(block.to_value(), vec![0x5A]);
+        assert_eq!(BlockValue::from_value(&block.to_value()), Some(block));
+        assert_eq!(block.offset(), 320);
+        assert_eq!(BlockValue::from_value(&[]), Some(BlockValue { num: 0, more: false, szx: 0 }));
+        assert!(BlockValue::from_value(&[0x07]).is_none());
+        assert_eq!(szx_for(1152), 6);
+        assert_eq!(szx_for(100), 2);
+        assert_eq!(block_size_for(1152, 40), 1024);
+        assert_eq!(block_size_for(512, 40), 256);
+        assert_eq!(block_size_for(20, 40), 16);
+    }
+}
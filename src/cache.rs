@@ -0,0 +1,288 @@
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use crate::{
+    common::{bytes_to_uint, uint_to_bytes},
+    frame::OptionEnum,
+    request::{Request, RequestMethod},
+    response::{Response, ResponseCode},
+};
+
+/// freshness of a response without Max-Age, RFC 7252 section 5.10.5
+pub const DEFAULT_MAX_AGE: u32 = 60;
+
+/// how many responses a `ResponseCache` keeps by default
+pub const DEFAULT_CACHE_CAPACITY: usize = 256;
+
+/// how long a stale response with an ETag is kept for revalidation by default
+pub const DEFAULT_STALE_GRACE: Duration = Duration::from_secs(300);
+
+/// request method plus every option that is part of the cache key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey(u8, Vec<(u16, Vec<Vec<u8>>)>);
+
+impl CacheKey {
+
+    pub fn new(request: &Request) -> CacheKey {
+        let options = request.get_options().into_iter()
+            .filter(|(number, _)| is_cache_key(*number))
+            .map(|(number, values)| (u16::from(number), values.clone()))
+            .collect();
+        CacheKey(request.get_method() as u8, options)
+    }
+
+    /// the Uri-* and Proxy-* options, naming the resource
+    fn target(&self) -> Vec<&(u16, Vec<Vec<u8>>)> {
+        let uri = [
+            OptionEnum::UriHost, OptionEnum::UriPort, OptionEnum::UriPath, OptionEnum::UriQuery,
+            OptionEnum::ProxyUri, OptionEnum::ProxyScheme,
+        ].map(u16::from);
+        self.1.iter().filter(|(number, _)| uri.contains(number)).collect()
+    }
+}
+
+/// options marked NoCacheKey (RFC 7252 section 5.4.2) are left out, as are
+/// the ones only steering a single exchange: ETag is used for validation,
+/// block options address parts of the same body and Hop-Limit changes per hop
+pub fn is_cache_key(number: OptionEnum) -> bool {
+    let no_cache_key = u16::from(number) & 0x1E == 0x1C;
+    !no_cache_key && !matches!(number,
+        OptionEnum::ETag
+        | OptionEnum::Block1
+        | OptionEnum::Block2
+        | OptionEnum::QBlock1
+        | OptionEnum::QBlock2
+        | OptionEnum::HopLimit
+        | OptionEnum::RequestTag
+    )
+}
+
+/// Max-Age of a response, DEFAULT_MAX_AGE when absent
+pub fn max_age(response: &Response) -> Duration {
+    let seconds = response.get_option(OptionEnum::MaxAge)
+        .and_then(|v| v.first())
+        .map_or(DEFAULT_MAX_AGE, |v| bytes_to_uint(v));
+    Duration::from_secs(seconds as u64)
+}
+
+pub fn etag(response: &Response) -> Option<&Vec<u8>> {
+    response.get_option(OptionEnum::ETag).and_then(|v| v.first())
+}
+
+/// result of looking a request up in a `ResponseCache`
+#[derive(Debug)]
+pub enum Lookup {
+    /// a fresh response, Max-Age set to what is left of its freshness
+    Fresh(Response),
+    /// a stale response that can be revalidated with its ETag
+    Stale(Vec<u8>),
+    Miss,
+}
+
+struct CacheEntry {
+    response: Response,
+    expires: Instant,
+    /// when the entry was last used, in uses of the cache
+    used: u64,
+}
+
+/// responses to GET requests kept until their Max-Age runs out,
+/// RFC 7252 section 5.6; stale ones with an ETag stay for revalidation
+/// during a grace period, and past its capacity the cache drops the least
+/// recently used
+pub struct ResponseCache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    capacity: usize,
+    stale_grace: Duration,
+    uses: u64,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache::new()
+    }
+}
+
+impl ResponseCache {
+
+    pub fn new() -> ResponseCache {
+        ResponseCache {
+            entries: HashMap::new(),
+            capacity: DEFAULT_CACHE_CAPACITY,
+            stale_grace: DEFAULT_STALE_GRACE,
+            uses: 0,
+        }
+    }
+
+    /// how many responses are kept at most, `DEFAULT_CACHE_CAPACITY` by default
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// how long a stale response with an ETag is kept after its Max-Age ran
+    /// out, `DEFAULT_STALE_GRACE` by default
+    pub fn set_stale_grace(&mut self, grace: Duration) {
+        self.stale_grace = grace;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&mut self, key: &CacheKey, now: Instant) -> Lookup {
+        self.uses += 1;
+        let Some(entry) = self.entries.get_mut(key) else {
+            return Lookup::Miss;
+        };
+        entry.used = self.uses;
+        if let Some(left) = entry.expires.checked_duration_since(now).filter(|left| !left.is_zero()) {
+            let mut response = entry.response.clone();
+            response.set_option(OptionEnum::MaxAge, uint_to_bytes(left.as_secs() as u32));
+            return Lookup::Fresh(response);
+        }
+        match etag(&entry.response).filter(|_| entry.expires + self.stale_grace > now) {
+            Some(etag) => Lookup::Stale(etag.clone()),
+            None => {
+                self.entries.remove(key);
+                Lookup::Miss
+            }
+        }
+    }
+
+    /// keep a response to a GET, only 2.05 Content is stored
+    pub fn insert(&mut self, key: CacheKey, response: &Response, now: Instant) {
+        if key.0 != RequestMethod::Get as u8 || response.get_response_code() != ResponseCode::Content {
+            return;
+        }
+        self.uses += 1;
+        let expires = now + max_age(response);
+        self.entries.insert(key, CacheEntry { response: response.clone(), expires, used: self.uses });
+        let grace = self.stale_grace;
+        self.entries.retain(|_, entry| entry.expires > now || (etag(&entry.response).is_some() && entry.expires + grace > now));
+        self.evict();
+    }
+
+    /// drop the least recently used entries beyond the capacity
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let Some(oldest) = self.entries.iter().min_by_key(|(_, entry)| entry.used).map(|(key, _)| key.clone()) else {
+                return;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    /// a 2.03 Valid confirmed the stored response, RFC 7252 section 5.6.2;
+    /// returns it with the fresh Max-Age, or None when the ETag does not match
+    pub fn revalidate(&mut self, key: &CacheKey, valid: &Response, now: Instant) -> Option<Response> {
+        self.uses += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.used = self.uses;
+        if etag(valid).is_some_and(|tag| etag(&entry.response) != Some(tag)) {
+            return None;
+        }
+        entry.expires = now + max_age(valid);
+        if let Some(max_age) = valid.get_option(OptionEnum::MaxAge).and_then(|v| v.first()) {
+            entry.response.set_option(OptionEnum::MaxAge, max_age.clone());
+        }
+        Some(entry.response.clone())
+    }
+
+    pub fn remove(&mut self, key: &CacheKey) {
+        self.entries.remove(key);
+    }
+
+    /// drop every response for the resource `request` targets
+    pub fn invalidate(&mut self, request: &Request) {
+        let key = CacheKey::new(request);
+        let target = key.target();
+        self.entries.retain(|other, _| other.target() != target);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, time::{Duration, Instant}};
+
+    use crate::{
+        cache::{is_cache_key, max_age, CacheKey, Lookup, ResponseCache, DEFAULT_MAX_AGE},
+        frame::{CoAPFrame, Header, MessageType, OptionEnum},
+        request::{Request, RequestMethod},
+        response::{Response, ResponseCode},
+    };
+
+    fn request(options: &[(OptionEnum, &[u8])]) -> Request {
+        let mut map = BTreeMap::new();
+        for (number, value) in options {
+            map.insert(u16::from(*number), vec![value.to_vec()]);
+        }
+        let header = Header::new(MessageType::Con.into(), RequestMethod::Get as u8);
+        Request::from_frame(&CoAPFrame::new(header, map, vec![]), "127.0.0.1:5683".parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn cache_key_skips_no_cache_key_options() {
+        assert!(!is_cache_key(OptionEnum::Size1));
+        assert!(!is_cache_key(OptionEnum::ETag));
+        assert!(is_cache_key(OptionEnum::UriPath));
+        let a = CacheKey::new(&request(&[(OptionEnum::UriPath, b"t"), (OptionEnum::ETag, b"1")]));
+        let b = CacheKey::new(&request(&[(OptionEnum::UriPath, b"t"), (OptionEnum::Size1, b"\x05")]));
+        let c = CacheKey::new(&request(&[(OptionEnum::UriPath, b"u")]));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn freshness_and_revalidation() {
+        let key = CacheKey::new(&request(&[(OptionEnum::UriPath, b"t")]));
+        let mut cache = ResponseCache::new();
+        let mut response = Response::new(ResponseCode::Content);
+        response.set_option(OptionEnum::MaxAge, vec![10]);
+        response.set_option(OptionEnum::ETag, vec![7]);
+        response.set_body(vec![1, 2]);
+        let now = Instant::now();
+        cache.insert(key.clone(), &response, now);
+
+        assert_eq!(max_age(&Response::new(ResponseCode::Content)), Duration::from_secs(DEFAULT_MAX_AGE as u64));
+        let Lookup::Fresh(hit) = cache.get(&key, now + Duration::from_secs(4)) else { panic!("expected a hit") };
+        assert_eq!(hit.get_option(OptionEnum::MaxAge), Some(&vec![vec![6]]));
+        let later = now + Duration::from_secs(11);
+        assert!(matches!(cache.get(&key, later), Lookup::Stale(etag) if etag == vec![7]));
+
+        let mut valid = Response::new(ResponseCode::Valid);
+        valid.set_option(OptionEnum::ETag, vec![7]);
+        valid.set_option(OptionEnum::MaxAge, vec![30]);
+        assert_eq!(cache.revalidate(&key, &valid, later).unwrap().get_body(), &vec![1, 2]);
+        assert!(matches!(cache.get(&key, later + Duration::from_secs(20)), Lookup::Fresh(_)));
+    }
+
+    #[test]
+    fn capacity_and_stale_grace() {
+        let key = |path: &[u8]| CacheKey::new(&request(&[(OptionEnum::UriPath, path)]));
+        let mut response = Response::new(ResponseCode::Content);
+        response.set_option(OptionEnum::MaxAge, vec![10]);
+        response.set_option(OptionEnum::ETag, vec![7]);
+        let mut cache = ResponseCache::new();
+        cache.set_capacity(2);
+        cache.set_stale_grace(Duration::from_secs(5));
+        let now = Instant::now();
+        cache.insert(key(b"a"), &response, now);
+        cache.insert(key(b"b"), &response, now);
+        // "a" was used since, so "b" goes
+        assert!(matches!(cache.get(&key(b"a"), now), Lookup::Fresh(_)));
+        cache.insert(key(b"c"), &response, now);
+        assert_eq!(cache.len(), 2);
+        assert!(matches!(cache.get(&key(b"b"), now), Lookup::Miss));
+        assert!(matches!(cache.get(&key(b"a"), now), Lookup::Fresh(_)));
+
+        // stale and revalidatable for the grace period, then gone
+        assert!(matches!(cache.get(&key(b"a"), now + Duration::from_secs(12)), Lookup::Stale(_)));
+        assert!(matches!(cache.get(&key(b"a"), now + Duration::from_secs(16)), Lookup::Miss));
+        cache.insert(key(b"d"), &response, now + Duration::from_secs(16));
+        assert_eq!(cache.len(), 1);
+    }
+}
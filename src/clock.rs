@@ -0,0 +1,68 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+/// source of the current time for client and server, replaceable so
+/// timeouts and lifetimes can be tested without waiting
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// the monotonic system clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// clock that only moves when told to, clones share the same time
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+
+    pub fn new(start: Instant) -> ManualClock {
+        ManualClock { now: Arc::new(Mutex::new(start)) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    pub fn set(&self, now: Instant) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new(Instant::now())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::clock::{Clock, ManualClock};
+
+    #[test]
+    fn manual_clock_is_shared() {
+        let start = Instant::now();
+        let clock = ManualClock::new(start);
+        let other = clock.clone();
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(other.now(), start + Duration::from_secs(3));
+        other.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
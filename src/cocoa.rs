@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+/// upper bound of the estimated RTO
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// smoothed RTT and its variation, RFC 6298 section 2, in seconds
+#[derive(Debug, Clone, Copy)]
+struct Smoothed {
+    srtt: f64,
+    rttvar: f64,
+}
+
+impl Smoothed {
+
+    fn update(smoothed: &mut Option<Smoothed>, rtt: f64) -> Smoothed {
+        let next = match *smoothed {
+            None => Smoothed { srtt: rtt, rttvar: rtt / 2.0 },
+            Some(Smoothed { srtt, rttvar }) => Smoothed {
+                rttvar: 0.75 * rttvar + 0.25 * (srtt - rtt).abs(),
+                srtt: 0.875 * srtt + 0.125 * rtt,
+            },
+        };
+        *smoothed = Some(next);
+        next
+    }
+}
+
+/// CoCoA retransmission timeout of one peer, RFC 9006 section 4.2
+///
+/// a strong estimator learns from exchanges acknowledged without
+/// retransmission, a weak one from those acknowledged after one or two,
+/// measured from the first transmission; both feed one overall RTO that
+/// ages back towards the default when it is not updated
+#[derive(Debug, Clone)]
+pub struct RtoEstimator {
+    strong: Option<Smoothed>,
+    weak: Option<Smoothed>,
+    rto: Duration,
+    updated: Instant,
+}
+
+impl RtoEstimator {
+
+    pub fn new(initial: Duration, now: Instant) -> RtoEstimator {
+        RtoEstimator { strong: None, weak: None, rto: initial, updated: now }
+    }
+
+    pub fn get_rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// RTT of an exchange acknowledged without retransmission
+    pub fn update_strong(&mut self, rtt: Duration, now: Instant) {
+        let Smoothed { srtt, rttvar } = Smoothed::update(&mut self.strong, rtt.as_secs_f64());
+        self.blend(srtt + 4.0 * rttvar, 0.5, now);
+    }
+
+    /// RTT from the first transmission of an exchange acknowledged after
+    /// one or two retransmissions
+    pub fn update_weak(&mut self, rtt: Duration, now: Instant) {
+        let Smoothed { srtt, rttvar } = Smoothed::update(&mut self.weak, rtt.as_secs_f64());
+        self.blend(srtt + rttvar, 0.25, now);
+    }
+
+    fn blend(&mut self, estimate: f64, weight: f64, now: Instant) {
+        let rto = weight * estimate + (1.0 - weight) * self.rto.as_secs_f64();
+        self.rto = Duration::from_secs_f64(rto).min(MAX_RTO);
+        self.updated = now;
+    }
+
+    /// an RTO below 1 s is doubled after 16 times itself without an update,
+    /// one above 3 s moves halfway to 1 s after 4 times itself
+    pub fn age(&mut self, now: Instant) {
+        let idle = now.saturating_duration_since(self.updated);
+        if self.rto < Duration::from_secs(1) && idle > self.rto * 16 {
+            self.rto *= 2;
+            self.updated = now;
+        } else if self.rto > Duration::from_secs(3) && idle > self.rto * 4 {
+            self.rto = Duration::from_secs(1) + self.rto / 2;
+            self.updated = now;
+        }
+    }
+
+    /// not updated or aged for longer than `idle`
+    pub fn is_idle(&self, idle: Duration, now: Instant) -> bool {
+        now.saturating_duration_since(self.updated) > idle
+    }
+
+    /// timeout of a new message, the RTO dithered up to `random_factor` times
+    pub fn initial_timeout_with<R: Rng + ?Sized>(&self, random_factor: f64, rng: &mut R) -> Duration {
+        let factor = rng.gen_range(1.0..=random_factor.max(1.0));
+        self.rto.mul_f64(factor)
+    }
+
+    /// variable backoff factor: short RTOs grow faster, long ones slower
+    pub fn backoff_factor(&self) -> f64 {
+        if self.rto < Duration::from_secs(1) {
+            3.0
+        } else if self.rto > Duration::from_secs(3) {
+            1.5
+        } else {
+            2.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::cocoa::RtoEstimator;
+
+    #[test]
+    fn estimators_and_aging() {
+        let start = Instant::now();
+        let close = |a: Duration, b: Duration| a.abs_diff(b) < Duration::from_micros(1);
+        let mut estimator = RtoEstimator::new(Duration::from_secs(2), start);
+        assert_eq!(estimator.backoff_factor(), 2.0);
+
+        // 100 ms: RTO_strong = 0.1 + 4 * 0.05, blended half with 2 s
+        estimator.update_strong(Duration::from_millis(100), start);
+        assert!(close(estimator.get_rto(), Duration::from_millis(1150)));
+        for _ in 0..10 {
+            estimator.update_strong(Duration::from_millis(100), start);
+        }
+        assert!(estimator.get_rto() < Duration::from_secs(1));
+        assert_eq!(estimator.backoff_factor(), 3.0);
+
+        // left alone a short RTO doubles
+        let rto = estimator.get_rto();
+        let idle = start + rto * 17;
+        estimator.age(idle);
+        estimator.age(idle);
+        assert_eq!(estimator.get_rto(), rto * 2);
+
+        // a weak measurement only moves it by a quarter
+        let rto = estimator.get_rto();
+        estimator.update_weak(Duration::from_secs(4), idle);
+        assert!(close(estimator.get_rto(), rto.mul_f64(0.75) + Duration::from_millis(1500)));
+
+        let mut slow = RtoEstimator::new(Duration::from_secs(8), start);
+        assert_eq!(slow.backoff_factor(), 1.5);
+        slow.age(start + Duration::from_secs(33));
+        assert_eq!(slow.get_rto(), Duration::from_secs(5));
+        assert!(!slow.is_idle(Duration::from_secs(10), start + Duration::from_secs(43)));
+        assert!(slow.is_idle(Duration::from_secs(10), start + Duration::from_secs(44)));
+    }
+}
@@ -1,32 +1,17 @@
-
-pub fn url_parse(uri: &str) {
-    match url::Url::parse(uri) {
-        Ok(_) => todo!(),
-        Err(_) => todo!(),
-    }
-}
-
-trait Uri {
-    
-    fn parse(&self, uri: &str);
-}
-
-pub fn u16_to_bytes(value: u16) -> Vec<u8> {
-    let first_byte = ((value >> 4) & 0xFF) as u8;
-    let second_byte = (value & 0xFF) as u8;
-    vec![first_byte, second_byte]
+/// decode a CoAP uint option value (big endian, leading zeros may be omitted)
+pub fn bytes_to_uint(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0u32, |acc, b| acc << 8 | *b as u32)
 }
 
-pub fn u32_to_bytes(value: u32) -> Vec<u8> {
-    let byte1 = ((value >> 24) & 0xFF) as u8;
-    let byte2 = ((value >> 16) & 0xFF) as u8;
-    let byte3 = ((value >> 8) & 0xFF) as u8;
-    let byte4 = (value & 0xFF) as u8;
-    vec![byte1, byte2, byte3, byte3, byte4]
+/// encode a CoAP uint option value with leading zero bytes removed
+pub fn uint_to_bytes(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    bytes[start..].to_vec()
 }
 
 pub fn code_from(c: u8, dd: u8) -> u8 {
-        ((c & 0xF7) << 5) | (dd & 0x1F)
+        ((c & 0x07) << 5) | (dd & 0x1F)
 }
 
 pub fn to_code_str(code: u8) -> String{
@@ -42,9 +27,8 @@ mod test {
 
     #[test]
     fn test_code_from() {
-        let i = code_from(4, 00);
-        assert_eq!(code_from(2, 01), 0x41);
-        assert_eq!(code_from(4, 04), 0x84);
+        assert_eq!(code_from(2, 1), 0x41);
+        assert_eq!(code_from(4, 4), 0x84);
         assert_ne!(code_from(5, 0), 0x50)
     }
 }
\ No newline at end of file
@@ -0,0 +1,70 @@
+use crate::{frame::OptionEnum, request::Request};
+
+/// evaluate If-Match and If-None-Match, RFC 7252 section 5.10.8
+///
+/// `current` is the ETag of the target resource's current representation,
+/// None when there is none; an empty If-Match value matches any existing one
+pub fn preconditions_hold(request: &Request, current: Option<&[u8]>) -> bool {
+    if let Some(etags) = request.get_option(OptionEnum::IfMatch) {
+        let matched = current.is_some_and(|current| etags.iter().any(|etag| etag.is_empty() || etag == current));
+        if !matched {
+            return false;
+        }
+    }
+    !(request.get_option(OptionEnum::IfNoneMatch).is_some() && current.is_some())
+}
+
+/// ETag derived from a payload, 64 bit FNV-1a so it stays the same across
+/// restarts and builds
+pub fn payload_etag(payload: &[u8]) -> Vec<u8> {
+    let hash = payload.iter().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3));
+    hash.to_be_bytes().to_vec()
+}
+
+pub fn has_preconditions(request: &Request) -> bool {
+    request.get_option(OptionEnum::IfMatch).is_some() || request.get_option(OptionEnum::IfNoneMatch).is_some()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        conditional::{payload_etag, preconditions_hold},
+        frame::{CoAPFrame, Header, MessageType, OptionEnum},
+        request::{Request, RequestMethod},
+    };
+
+    fn put(options: &[(OptionEnum, Vec<u8>)]) -> Request {
+        let mut map: BTreeMap<u16, Vec<Vec<u8>>> = BTreeMap::new();
+        for (number, value) in options {
+            map.entry(u16::from(*number)).or_default().push(value.clone());
+        }
+        let header = Header::new(MessageType::Con.into(), RequestMethod::Put as u8);
+        Request::from_frame(&CoAPFrame::new(header, map, vec![]), "127.0.0.1:5683".parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn if_match_and_if_none_match() {
+        let if_match = put(&[(OptionEnum::IfMatch, vec![1]), (OptionEnum::IfMatch, vec![2])]);
+        assert!(preconditions_hold(&if_match, Some(&[2])));
+        assert!(!preconditions_hold(&if_match, Some(&[3])));
+        assert!(!preconditions_hold(&if_match, None));
+
+        let exists = put(&[(OptionEnum::IfMatch, vec![])]);
+        assert!(preconditions_hold(&exists, Some(&[3])));
+        assert!(!preconditions_hold(&exists, None));
+
+        let absent = put(&[(OptionEnum::IfNoneMatch, vec![])]);
+        assert!(preconditions_hold(&absent, None));
+        assert!(!preconditions_hold(&absent, Some(&[1])));
+        assert!(preconditions_hold(&put(&[]), None));
+    }
+
+    #[test]
+    fn etag_from_payload() {
+        assert_eq!(payload_etag(b""), 0xcbf29ce484222325u64.to_be_bytes().to_vec());
+        assert_eq!(payload_etag(b"21.5"), payload_etag(b"21.5"));
+        assert_ne!(payload_etag(b"21.5"), payload_etag(b"21.6"));
+    }
+}
@@ -0,0 +1,114 @@
+use std::{collections::HashMap, net::SocketAddr, time::{Duration, Instant}};
+
+use crate::transmission::{EXCHANGE_LIFETIME, NON_LIFETIME};
+
+/// result of looking up a received message in the cache
+#[derive(Debug, PartialEq, Eq)]
+pub enum Dedup {
+    /// first time this message is seen
+    New,
+    /// already seen, carries the reply that was sent for it, if any
+    Duplicate(Option<Vec<u8>>),
+}
+
+struct Entry {
+    expires: Instant,
+    reply: Option<Vec<u8>>,
+}
+
+/// message deduplication, RFC 7252 section 4.5
+///
+/// messages are keyed by (source, message id) and remembered for
+/// EXCHANGE_LIFETIME (CON) or NON_LIFETIME (NON)
+pub struct DedupCache {
+    entries: HashMap<(SocketAddr, u16), Entry>,
+    exchange_lifetime: Duration,
+    non_lifetime: Duration,
+    next_purge: Option<Instant>,
+}
+
+impl Default for DedupCache {
+    fn default() -> Self {
+        DedupCache::new()
+    }
+}
+
+impl DedupCache {
+
+    pub fn new() -> DedupCache {
+        DedupCache::with_lifetimes(EXCHANGE_LIFETIME, NON_LIFETIME)
+    }
+
+    pub fn with_lifetimes(exchange_lifetime: Duration, non_lifetime: Duration) -> DedupCache {
+        DedupCache {
+            entries: HashMap::new(),
+            exchange_lifetime,
+            non_lifetime,
+            next_purge: None,
+        }
+    }
+
+    /// look a message up, remembering it if it is new
+    pub fn check(&mut self, peer: SocketAddr, msg_id: u16, confirmable: bool, now: Instant) -> Dedup {
+        self.purge(now);
+        if let Some(entry) = self.entries.get(&(peer, msg_id)) {
+            if entry.expires > now {
+                return Dedup::Duplicate(entry.reply.clone());
+            }
+        }
+        let lifetime = if confirmable { self.exchange_lifetime } else { self.non_lifetime };
+        self.entries.insert((peer, msg_id), Entry { expires: now + lifetime, reply: None });
+        Dedup::New
+    }
+
+    /// remember the reply sent for a message so duplicates get the same answer
+    pub fn set_reply(&mut self, peer: SocketAddr, msg_id: u16, reply: Vec<u8>) {
+        if let Some(entry) = self.entries.get_mut(&(peer, msg_id)) {
+            entry.reply = Some(reply);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn purge(&mut self, now: Instant) {
+        if self.next_purge.is_some_and(|t| t > now) {
+            return;
+        }
+        self.entries.retain(|_, entry| entry.expires > now);
+        self.next_purge = Some(now + self.non_lifetime.min(self.exchange_lifetime) / 8);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, time::{Duration, Instant}};
+
+    use crate::dedup::{Dedup, DedupCache};
+
+    #[test]
+    fn duplicates_expire() {
+        let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let mut cache = DedupCache::with_lifetimes(Duration::from_secs(10), Duration::from_secs(5));
+        let now = Instant::now();
+
+        assert_eq!(cache.check(peer, 1, true, now), Dedup::New);
+        assert_eq!(cache.check(peer, 1, true, now), Dedup::Duplicate(None));
+        cache.set_reply(peer, 1, vec![0x60, 0x45, 0, 1]);
+        assert_eq!(cache.check(peer, 1, true, now), Dedup::Duplicate(Some(vec![0x60, 0x45, 0, 1])));
+
+        assert_eq!(cache.check(peer, 2, false, now), Dedup::New);
+        let later = now + Duration::from_secs(6);
+        assert_eq!(cache.check(peer, 2, false, later), Dedup::New);
+        assert!(matches!(cache.check(peer, 1, true, later), Dedup::Duplicate(_)));
+
+        let other: SocketAddr = "127.0.0.2:5683".parse().unwrap();
+        assert_eq!(cache.check(other, 1, true, later), Dedup::New);
+        assert_eq!(cache.check(peer, 1, true, now + Duration::from_secs(11)), Dedup::New);
+    }
+}
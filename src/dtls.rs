@@ -0,0 +1,394 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::{c_int, c_void},
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    ptr,
+    sync::{mpsc::{self, Receiver, RecvTimeoutError, Sender}, Arc, Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use foreign_types::ForeignTypeRef;
+use openssl::{
+    error::ErrorStack,
+    ex_data::Index,
+    hash::MessageDigest,
+    memcmp,
+    pkey::PKey,
+    rand::rand_bytes,
+    sign::Signer,
+    ssl::{ErrorCode, Ssl, SslContext, SslContextBuilder, SslOptions, SslRef, SslStream},
+};
+
+use crate::transport::Transport;
+
+/// how long a handshake may take in all; meanwhile its flights are
+/// retransmitted whenever OpenSSL's DTLS timer runs out (1 s, doubling)
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// sessions of a listening transport not heard from for this long are closed
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// records are kept within the IPv6 minimum MTU less IP and UDP headers
+const DTLS_MTU: u32 = 1232;
+
+/// how often a handshake waiting for its peer lets OpenSSL check the
+/// retransmission timer
+const RETRANSMIT_POLL: Duration = Duration::from_millis(100);
+
+/// how often the socket reader of a listening transport looks for idle sessions
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `DTLSv1_handle_timeout` is a macro for this `SSL_ctrl` command
+const DTLS_CTRL_HANDLE_TIMEOUT: c_int = 74;
+
+// not bound by the openssl crate
+extern "C" {
+    fn DTLSv1_listen(ssl: *mut openssl_sys::SSL, client: *mut c_void) -> c_int;
+    fn BIO_ADDR_new() -> *mut c_void;
+    fn BIO_ADDR_free(addr: *mut c_void);
+}
+
+/// datagrams of one peer: OpenSSL reads those queued by the socket reader
+/// and writes straight to the socket
+struct Datagrams {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    queue: VecDeque<Vec<u8>>,
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let datagram = self.queue.pop_front().ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        Ok(len)
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(buf, self.peer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+type Session = Arc<Mutex<SslStream<Datagrams>>>;
+
+/// where the socket reader hands the datagrams of a peer
+struct Peer {
+    sender: Sender<Vec<u8>>,
+    last_seen: Instant,
+}
+
+/// state shared by the transport and its threads
+#[derive(Clone)]
+struct Shared {
+    socket: Arc<UdpSocket>,
+    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    sessions: Arc<Mutex<HashMap<SocketAddr, Session>>>,
+    inbox: Sender<(Vec<u8>, SocketAddr)>,
+    idle_timeout: Arc<Mutex<Duration>>,
+}
+
+/// CoAP over DTLS, RFC 7252 section 9, on OpenSSL
+///
+/// the `SslContext` decides the security mode: a PSK callback, or a
+/// certificate and its verification; it must be built for
+/// `SslMethod::dtls()`. each peer gets a session of its own, messages to a
+/// peer without one fail with `ErrorKind::NotConnected`
+pub struct DtlsTransport {
+    shared: Shared,
+    receiver: Mutex<Receiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl DtlsTransport {
+
+    /// accept handshakes from any number of peers; the transport adds a
+    /// cookie exchange to the context, RFC 6347 section 4.2.1
+    pub fn listen<A: ToSocketAddrs>(addr: A, mut context: SslContextBuilder) -> io::Result<DtlsTransport> {
+        enable_cookies(&mut context)?;
+        // the MTU set on each session has to outlive DTLSv1_listen
+        context.set_options(SslOptions::NO_QUERY_MTU);
+        let transport = DtlsTransport::new(UdpSocket::bind(addr)?);
+        // wakes the socket reader to look for idle sessions
+        transport.shared.socket.set_read_timeout(Some(SWEEP_INTERVAL))?;
+        transport.shared.spawn_reader(Some(context.build()));
+        Ok(transport)
+    }
+
+    /// handshake with a single server, messages can only be sent to its address
+    pub fn connect<A: ToSocketAddrs>(addr: A, context: SslContext) -> io::Result<DtlsTransport> {
+        DtlsTransport::connect_with_timeout(addr, context, HANDSHAKE_TIMEOUT)
+    }
+
+    /// `connect`, failing with `ErrorKind::TimedOut` when the handshake has
+    /// not completed within `timeout`
+    pub fn connect_with_timeout<A: ToSocketAddrs>(addr: A, context: SslContext, timeout: Duration) -> io::Result<DtlsTransport> {
+        let server = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+        let local = match server {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let transport = DtlsTransport::new(UdpSocket::bind(local)?);
+        let (sender, datagrams) = mpsc::channel();
+        transport.shared.peers.lock().unwrap().insert(server, Peer { sender, last_seen: Instant::now() });
+        transport.shared.spawn_reader(None);
+        let stream = handshake(transport.shared.new_stream(&context, server)?, &datagrams, false, timeout)?;
+        transport.shared.spawn_session(server, stream, datagrams);
+        Ok(transport)
+    }
+
+    /// close the sessions of a listening transport whose peers have not been
+    /// heard from for `timeout`, `DEFAULT_IDLE_TIMEOUT` by default
+    pub fn set_idle_timeout(&self, timeout: Duration) {
+        *self.shared.idle_timeout.lock().unwrap() = timeout;
+    }
+
+    fn new(socket: UdpSocket) -> DtlsTransport {
+        let (inbox, receiver) = mpsc::channel();
+        DtlsTransport {
+            shared: Shared {
+                socket: Arc::new(socket),
+                peers: Arc::new(Mutex::new(HashMap::new())),
+                sessions: Arc::new(Mutex::new(HashMap::new())),
+                inbox,
+                idle_timeout: Arc::new(Mutex::new(DEFAULT_IDLE_TIMEOUT)),
+            },
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+impl Shared {
+
+    /// read the socket on a thread of its own, handing each datagram to its
+    /// peer; with a context, unknown peers that pass the cookie exchange
+    /// start a server handshake
+    fn spawn_reader(&self, context: Option<SslContext>) {
+        let shared = self.clone();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; u16::MAX as usize];
+            let mut swept = Instant::now();
+            loop {
+                let received = shared.socket.recv_from(&mut buf);
+                if context.is_some() && swept.elapsed() >= SWEEP_INTERVAL {
+                    shared.evict_idle();
+                    swept = Instant::now();
+                }
+                let (len, peer) = match received {
+                    Ok(received) => received,
+                    // read timeouts only wake the reader, and ICMP errors
+                    // of earlier sends are no concern of the other peers
+                    Err(err) if matches!(err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut |
+                        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset) => continue,
+                    Err(_) => break,
+                };
+                if let Some(known) = shared.peers.lock().unwrap().get_mut(&peer) {
+                    known.last_seen = Instant::now();
+                    let _ = known.sender.send(buf[..len].to_vec());
+                    continue;
+                }
+                let Some(context) = &context else {
+                    continue;
+                };
+                // nothing is kept for a peer before its address is verified
+                let Some(stream) = shared.verify_cookie(context, peer, buf[..len].to_vec()) else {
+                    continue;
+                };
+                let (sender, datagrams) = mpsc::channel();
+                shared.peers.lock().unwrap().insert(peer, Peer { sender, last_seen: Instant::now() });
+                let shared = shared.clone();
+                thread::spawn(move || match handshake(stream, &datagrams, true, HANDSHAKE_TIMEOUT) {
+                    Ok(stream) => shared.spawn_session(peer, stream, datagrams),
+                    // evicted, the peer may have started over meanwhile
+                    Err(err) if err.kind() == io::ErrorKind::NotConnected => {}
+                    Err(_) => {
+                        shared.peers.lock().unwrap().remove(&peer);
+                    }
+                });
+            }
+        });
+    }
+
+    fn new_stream(&self, context: &SslContext, peer: SocketAddr) -> io::Result<SslStream<Datagrams>> {
+        let mut ssl = Ssl::new(context)?;
+        ssl.set_mtu(DTLS_MTU)?;
+        ssl.set_ex_data(peer_index(), peer);
+        Ok(SslStream::new(ssl, Datagrams { socket: self.socket.clone(), peer, queue: VecDeque::new() })?)
+    }
+
+    /// run a datagram of an unknown peer through `DTLSv1_listen`, which
+    /// answers a ClientHello without a valid cookie with a HelloVerifyRequest
+    /// and keeps no state; a stream to continue the handshake on is only
+    /// returned for a valid cookie
+    fn verify_cookie(&self, context: &SslContext, peer: SocketAddr, datagram: Vec<u8>) -> Option<SslStream<Datagrams>> {
+        let mut stream = self.new_stream(context, peer).ok()?;
+        stream.get_mut().queue.push_back(datagram);
+        let verified = unsafe {
+            let client = BIO_ADDR_new();
+            if client.is_null() {
+                return None;
+            }
+            let verified = DTLSv1_listen(stream.ssl().as_ptr(), client);
+            BIO_ADDR_free(client);
+            verified
+        };
+        // whatever DTLSv1_listen queued on the thread is of no further use
+        ErrorStack::get();
+        (verified == 1).then_some(stream)
+    }
+
+    /// forget the peers not heard from within the idle timeout; their
+    /// session threads see the channel close and end the session
+    fn evict_idle(&self) {
+        let idle_timeout = *self.idle_timeout.lock().unwrap();
+        self.peers.lock().unwrap().retain(|_, peer| peer.last_seen.elapsed() < idle_timeout);
+    }
+
+    /// decrypt the datagrams of an established session on a thread of its
+    /// own until the peer closes it or it is evicted
+    fn spawn_session(&self, peer: SocketAddr, stream: SslStream<Datagrams>, datagrams: Receiver<Vec<u8>>) {
+        let session = Arc::new(Mutex::new(stream));
+        self.sessions.lock().unwrap().insert(peer, session.clone());
+        let shared = self.clone();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; u16::MAX as usize];
+            let closed = 'session: {
+                for datagram in &datagrams {
+                    let mut stream = session.lock().unwrap();
+                    stream.get_mut().queue.push_back(datagram);
+                    loop {
+                        match stream.ssl_read(&mut buf) {
+                            Ok(len) => {
+                                if shared.inbox.send((buf[..len].to_vec(), peer)).is_err() {
+                                    break 'session true;
+                                }
+                            }
+                            Err(err) if err.code() == ErrorCode::WANT_READ => break,
+                            // close_notify or a fatal alert
+                            Err(_) => break 'session true,
+                        }
+                    }
+                }
+                false
+            };
+            // close_notify, so an evicted peer knows to handshake again
+            let _ = session.lock().unwrap().shutdown();
+            let mut sessions = shared.sessions.lock().unwrap();
+            if sessions.get(&peer).is_some_and(|current| Arc::ptr_eq(current, &session)) {
+                sessions.remove(&peer);
+            }
+            // an evicted peer is already gone and may have started over
+            if closed {
+                shared.peers.lock().unwrap().remove(&peer);
+            }
+        });
+    }
+}
+
+/// run a handshake to its end; whenever OpenSSL's timer runs out waiting
+/// for the peer the last flight is sent again, RFC 6347 section 4.2.4
+fn handshake(mut stream: SslStream<Datagrams>, datagrams: &Receiver<Vec<u8>>, accept: bool, timeout: Duration) -> io::Result<SslStream<Datagrams>> {
+    let deadline = Instant::now() + timeout;
+    let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "DTLS handshake timed out");
+    loop {
+        let result = match accept {
+            true => stream.accept(),
+            false => stream.connect(),
+        };
+        match result {
+            Ok(()) => return Ok(stream),
+            Err(err) if err.code() == ErrorCode::WANT_READ => loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(timed_out());
+                }
+                match datagrams.recv_timeout(remaining.min(RETRANSMIT_POLL)) {
+                    Ok(datagram) => {
+                        stream.get_mut().queue.push_back(datagram);
+                        break;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        // retransmits if the timer has run out, negative
+                        // once OpenSSL has given up on the peer
+                        let handled = unsafe {
+                            openssl_sys::SSL_ctrl(stream.ssl().as_ptr(), DTLS_CTRL_HANDLE_TIMEOUT, 0, ptr::null_mut())
+                        };
+                        if handled < 0 {
+                            return Err(timed_out());
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return Err(io::Error::from(io::ErrorKind::NotConnected)),
+                }
+            },
+            Err(err) => return Err(io::Error::new(io::ErrorKind::ConnectionRefused, err)),
+        }
+    }
+}
+
+/// the address of the peer an `Ssl` belongs to, for the cookie callbacks
+fn peer_index() -> Index<Ssl, SocketAddr> {
+    static INDEX: OnceLock<Index<Ssl, SocketAddr>> = OnceLock::new();
+    *INDEX.get_or_init(|| Ssl::new_ex_index().expect("no ex_data index for DTLS peers"))
+}
+
+/// stateless cookies: a ClientHello is answered with a HelloVerifyRequest
+/// until it echoes an HMAC of its source address, so a spoofed source costs
+/// neither per-peer state nor an amplified flight
+fn enable_cookies(context: &mut SslContextBuilder) -> io::Result<()> {
+    let mut secret = [0u8; 32];
+    rand_bytes(&mut secret)?;
+    let key = PKey::hmac(&secret)?;
+    let cookie = move |ssl: &SslRef| -> Result<Vec<u8>, ErrorStack> {
+        let peer = ssl.ex_data(peer_index()).map(SocketAddr::to_string).unwrap_or_default();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(peer.as_bytes())?;
+        signer.sign_to_vec()
+    };
+    let expected = cookie.clone();
+    context.set_cookie_generate_cb(move |ssl, buf| {
+        let cookie = cookie(ssl)?;
+        buf[..cookie.len()].copy_from_slice(&cookie);
+        Ok(cookie.len())
+    });
+    context.set_cookie_verify_cb(move |ssl, cookie| {
+        expected(ssl).is_ok_and(|expected| expected.len() == cookie.len() && memcmp::eq(&expected, cookie))
+    });
+    context.set_options(SslOptions::COOKIE_EXCHANGE);
+    Ok(())
+}
+
+impl Transport for DtlsTransport {
+    fn send_to(&self, data: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        let session = self.shared.sessions.lock().unwrap().get(&peer).cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no DTLS session with peer"))?;
+        let mut stream = session.lock().unwrap();
+        stream.ssl_write(data).map_err(|err| match err.into_io_error() {
+            Ok(err) => err,
+            Err(err) => io::Error::other(err),
+        })
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<(usize, SocketAddr)> {
+        let receiver = self.receiver.lock().unwrap();
+        let (data, from) = match timeout {
+            Some(timeout) => receiver.recv_timeout(timeout).map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+            None => receiver.recv().map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?,
+        };
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+}
+
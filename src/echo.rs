@@ -0,0 +1,65 @@
+use std::{collections::HashMap, net::SocketAddr, time::{Duration, Instant}};
+
+use rand::Rng;
+
+/// length of the Echo values handed out by the server
+const ECHO_LEN: usize = 8;
+
+/// Echo challenges issued by a server, RFC 9175 section 2
+///
+/// a request proves freshness by repeating a value issued to the same peer
+/// no longer than `freshness` ago
+pub struct EchoChallenges {
+    issued: HashMap<Vec<u8>, (SocketAddr, Instant)>,
+    freshness: Duration,
+}
+
+impl EchoChallenges {
+
+    pub fn new(freshness: Duration) -> EchoChallenges {
+        EchoChallenges {
+            issued: HashMap::new(),
+            freshness,
+        }
+    }
+
+    /// new value to send back in a 4.01 Unauthorized
+    pub fn issue(&mut self, peer: SocketAddr, now: Instant) -> Vec<u8> {
+        self.issue_with(peer, now, &mut rand::thread_rng())
+    }
+
+    /// `issue` drawing the value from `rng`
+    pub fn issue_with<R: Rng + ?Sized>(&mut self, peer: SocketAddr, now: Instant, rng: &mut R) -> Vec<u8> {
+        let freshness = self.freshness;
+        self.issued.retain(|_, (_, at)| now.duration_since(*at) <= freshness);
+        let value: Vec<u8> = (0..ECHO_LEN).map(|_| rng.gen()).collect();
+        self.issued.insert(value.clone(), (peer, now));
+        value
+    }
+
+    pub fn verify(&self, peer: SocketAddr, value: &[u8], now: Instant) -> bool {
+        self.issued.get(value)
+            .is_some_and(|(issued_to, at)| *issued_to == peer && now.duration_since(*at) <= self.freshness)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, time::{Duration, Instant}};
+
+    use crate::echo::EchoChallenges;
+
+    #[test]
+    fn echo_freshness() {
+        let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let other: SocketAddr = "127.0.0.2:5683".parse().unwrap();
+        let mut echo = EchoChallenges::new(Duration::from_secs(10));
+        let now = Instant::now();
+
+        let value = echo.issue(peer, now);
+        assert!(echo.verify(peer, &value, now + Duration::from_secs(5)));
+        assert!(!echo.verify(other, &value, now));
+        assert!(!echo.verify(peer, &value, now + Duration::from_secs(11)));
+        assert!(!echo.verify(peer, b"guessed", now));
+    }
+}
@@ -0,0 +1,1023 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, io, net::SocketAddr, time::{Duration, Instant}};
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::{
+    cocoa::RtoEstimator,
+    common::uint_to_bytes,
+    dedup::{Dedup, DedupCache},
+    frame::{CoAPFrame, MessageType, OptionEnum, MAX_MESSAGE_SIZE},
+    message_id::MessageIdAllocator,
+    request::Request,
+    response::{Response, ResponseCode},
+    token::{MAX_EXTENDED_TOKEN_LEN, MAX_TOKEN_LEN},
+    transmission::{CongestionParameters, RetransmissionPolicy, TransmissionParameters, EXCHANGE_LIFETIME},
+};
+
+/// datagram the endpoint wants sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transmit {
+    pub peer: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// what the endpoint has to tell the application
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// a response for an open token; with Observe every notification
+    /// arrives this way until the token is closed
+    Response { token: Vec<u8>, response: Response },
+    /// a CON request got an empty ACK, the response follows separately;
+    /// also a separate CON response being acknowledged
+    Acknowledged { token: Vec<u8> },
+    /// the peer rejected a request, or a separate CON response, with RST;
+    /// its token is closed
+    Reset { token: Vec<u8> },
+    /// a CON request or separate CON response was not acknowledged after
+    /// MAX_RETRANSMIT retransmissions, its token is closed
+    NotAcknowledged { token: Vec<u8> },
+    /// the deadline of a token passed, it is closed
+    TimedOut { token: Vec<u8> },
+    /// a response was larger than the maximum message size and could not
+    /// be read, its token is closed
+    TooLarge { token: Vec<u8> },
+    /// a CoAP ping was answered, `rtt` is measured from its last transmission
+    Pong { peer: SocketAddr, message_id: u16, rtt: Duration },
+    /// a CoAP ping was not answered
+    PingTimedOut { peer: SocketAddr, message_id: u16 },
+    /// a request from a peer, answer it with `Endpoint::respond`
+    Request { message_id: u16, request: Request },
+}
+
+impl Event {
+
+    /// token of the exchange the event belongs to
+    pub fn token(&self) -> Option<&[u8]> {
+        match self {
+            Event::Response { token, .. }
+            | Event::Acknowledged { token }
+            | Event::Reset { token }
+            | Event::NotAcknowledged { token }
+            | Event::TimedOut { token }
+            | Event::TooLarge { token } => Some(token),
+            _ => None,
+        }
+    }
+}
+
+/// a CON waiting for its ACK, RFC 7252 section 4.2
+struct Outstanding {
+    data: Vec<u8>,
+    /// None for a ping
+    token: Option<Vec<u8>>,
+    timeout: Duration,
+    /// factor `timeout` grows by on every retransmission
+    backoff: f64,
+    retransmits: u32,
+    next: Instant,
+    first_sent: Instant,
+    sent: Instant,
+}
+
+/// a token responses are accepted for
+struct Open {
+    peer: SocketAddr,
+    deadline: Instant,
+}
+
+/// a message held back by NSTART or PROBING_RATE
+struct Queued {
+    msg_id: u16,
+    data: Vec<u8>,
+    /// None for a ping
+    token: Option<Vec<u8>>,
+    confirmable: bool,
+}
+
+/// congestion control state of one peer, RFC 7252 section 4.7
+#[derive(Default)]
+struct PeerState {
+    queue: VecDeque<Queued>,
+    /// sent a NON request, or gave up on a CON, and heard nothing since
+    unanswered: bool,
+    /// when PROBING_RATE lets the next interaction start while unanswered
+    next_send: Option<Instant>,
+}
+
+/// CoAP message and request/response layer without any I/O
+///
+/// the endpoint consumes received datagrams (`handle_datagram`) and timer
+/// expiries (`handle_timeout`) and emits datagrams to send (`poll_transmit`),
+/// the next time it needs to be woken (`poll_timeout`) and events for the
+/// application (`poll_event`); time is always passed in, so it can be driven
+/// by any event loop or a test
+///
+/// requests to a peer that already has NSTART interactions outstanding are
+/// queued, as are new ones to a peer that stopped answering until
+/// PROBING_RATE allows them
+pub struct Endpoint {
+    params: TransmissionParameters,
+    retransmission: RetransmissionPolicy,
+    estimators: HashMap<SocketAddr, RtoEstimator>,
+    congestion: CongestionParameters,
+    peer_congestion: HashMap<SocketAddr, CongestionParameters>,
+    dedup: DedupCache,
+    message_ids: MessageIdAllocator,
+    accept_requests: bool,
+    max_message_size: usize,
+    max_token_length: usize,
+    rng: Box<dyn RngCore + Send>,
+    outstanding: HashMap<(SocketAddr, u16), Outstanding>,
+    open: HashMap<Vec<u8>, Open>,
+    /// tokens of NON requests still waiting for a response
+    awaiting: HashMap<Vec<u8>, SocketAddr>,
+    peers: HashMap<SocketAddr, PeerState>,
+    transmits: VecDeque<Transmit>,
+    events: VecDeque<Event>,
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Endpoint::new()
+    }
+}
+
+impl Endpoint {
+
+    pub fn new() -> Endpoint {
+        Endpoint {
+            params: TransmissionParameters::default(),
+            retransmission: RetransmissionPolicy::default(),
+            estimators: HashMap::new(),
+            congestion: CongestionParameters::default(),
+            peer_congestion: HashMap::new(),
+            dedup: DedupCache::new(),
+            message_ids: MessageIdAllocator::new(),
+            accept_requests: false,
+            max_message_size: MAX_MESSAGE_SIZE,
+            max_token_length: MAX_TOKEN_LEN,
+            rng: Box::new(StdRng::from_entropy()),
+            outstanding: HashMap::new(),
+            open: HashMap::new(),
+            awaiting: HashMap::new(),
+            peers: HashMap::new(),
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn set_transmission_parameters(&mut self, params: TransmissionParameters) {
+        self.params = params;
+    }
+
+    pub fn get_transmission_parameters(&self) -> &TransmissionParameters {
+        &self.params
+    }
+
+    /// fixed timeouts after RFC 7252, or CoCoA estimating them per peer
+    /// starting from ACK_TIMEOUT
+    pub fn set_retransmission_policy(&mut self, policy: RetransmissionPolicy) {
+        self.retransmission = policy;
+    }
+
+    /// the CoCoA RTO of `peer`, None until it was talked to under CoCoA
+    pub fn get_rto(&self, peer: SocketAddr) -> Option<Duration> {
+        self.estimators.get(&peer).map(|estimator| estimator.get_rto())
+    }
+
+    /// NSTART and PROBING_RATE for peers without parameters of their own
+    pub fn set_congestion_parameters(&mut self, params: CongestionParameters) {
+        self.congestion = params;
+    }
+
+    /// NSTART and PROBING_RATE for `peer`, e.g. a slow device that should
+    /// get one request at a time while others get more
+    pub fn set_peer_congestion_parameters(&mut self, peer: SocketAddr, params: CongestionParameters) {
+        self.peer_congestion.insert(peer, params);
+    }
+
+    pub fn get_congestion_parameters(&self, peer: SocketAddr) -> CongestionParameters {
+        self.peer_congestion.get(&peer).copied().unwrap_or(self.congestion)
+    }
+
+    /// source of the retransmission jitter and message ids, a seeded one
+    /// makes both reproducible
+    pub fn set_rng<R: RngCore + Send + 'static>(&mut self, rng: R) {
+        self.rng = Box::new(rng);
+    }
+
+    /// pass requests from peers on as events, without it a CON request is
+    /// rejected with RST as a pure client would
+    pub fn set_accept_requests(&mut self, enabled: bool) {
+        self.accept_requests = enabled;
+    }
+
+    /// largest message sent or received; larger datagrams are taken as
+    /// truncated, the driver should read up to one byte more to notice them
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    pub fn get_max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// longest token sent or accepted, 8 bytes by default; a message with a
+    /// longer token is dropped, a CON one rejected with RST (RFC 8974 section 2.2.2)
+    pub fn set_max_token_length(&mut self, len: usize) {
+        self.max_token_length = len.min(MAX_EXTENDED_TOKEN_LEN);
+    }
+
+    pub fn get_max_token_length(&self) -> usize {
+        self.max_token_length
+    }
+
+    /// send `request` to `peer` and accept responses carrying `token` until
+    /// `deadline`; a CON is retransmitted until it is acknowledged, and the
+    /// request may wait in the peer's queue first
+    pub fn request(&mut self, peer: SocketAddr, request: &Request, token: Vec<u8>, deadline: Instant, now: Instant) -> io::Result<u16> {
+        if token.len() > self.max_token_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "token longer than max token length"));
+        }
+        let mut frame = request.to_frame();
+        frame.set_token(token.clone())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "token too long"))?;
+        let msg_id = self.next_message_id(peer, now)?;
+        frame.header.set_msg_id(msg_id);
+        let data = frame.to_bytes();
+        if data.len() > self.max_message_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "request larger than the maximum message size"));
+        }
+        let confirmable = matches!(request.get_type(), MessageType::Con);
+        self.listen(peer, token.clone(), deadline);
+        self.submit(peer, Queued { msg_id, data, token: Some(token), confirmable }, now);
+        Ok(msg_id)
+    }
+
+    /// accept responses carrying `token` from `peer` until `deadline`
+    /// without sending anything, e.g. for the rest of a Q-Block2 burst
+    pub fn listen(&mut self, peer: SocketAddr, token: Vec<u8>, deadline: Instant) {
+        self.open.insert(token, Open { peer, deadline });
+    }
+
+    /// stop accepting responses for `token` and retransmitting its requests,
+    /// which lets the next queued request to the peer go; a queued NON is
+    /// still sent, as it would have been without the queue
+    pub fn close(&mut self, token: &[u8], now: Instant) {
+        let peer = self.open.remove(token).map(|open| open.peer);
+        self.awaiting.remove(token);
+        self.outstanding.retain(|_, out| out.token.as_deref() != Some(token));
+        self.events.retain(|event| event.token() != Some(token));
+        for state in self.peers.values_mut() {
+            state.queue.retain(|queued| !queued.confirmable || queued.token.as_deref() != Some(token));
+        }
+        if let Some(peer) = peer {
+            self.release(peer, now);
+        }
+    }
+
+    /// whether a request for `token` still waits for NSTART or PROBING_RATE
+    pub fn is_queued(&self, token: &[u8]) -> bool {
+        self.peers.values().any(|state| state.queue.iter().any(|queued| queued.token.as_deref() == Some(token)))
+    }
+
+    /// CoAP ping, an empty CON the peer answers with RST
+    pub fn ping(&mut self, peer: SocketAddr, now: Instant) -> io::Result<u16> {
+        let msg_id = self.next_message_id(peer, now)?;
+        let data = CoAPFrame::empty(MessageType::Con, msg_id).to_bytes();
+        self.submit(peer, Queued { msg_id, data, token: None, confirmable: true }, now);
+        Ok(msg_id)
+    }
+
+    /// answer a request from an `Event::Request`, piggybacked on the ACK for
+    /// a CON and as a NON of its own for a NON
+    pub fn respond(&mut self, request: &Request, message_id: u16, response: &Response, now: Instant) -> io::Result<()> {
+        let peer = request.get_peer()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "request did not come from a peer"))?;
+        if !matches!(request.get_type(), MessageType::Con) {
+            return self.respond_separately(request, response, now);
+        }
+        let token = request.get_token().cloned().unwrap_or_default();
+        let mut response = response.clone();
+        response.set_type(MessageType::Ack);
+        let data = response.to_frame(message_id, token)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "token too long"))?
+            .to_bytes();
+        self.dedup.set_reply(peer, message_id, data.clone());
+        self.transmits.push_back(Transmit { peer, data });
+        Ok(())
+    }
+
+    /// answer a request in a NON of its own instead of on the ACK, e.g. with
+    /// the further blocks of a Q-Block2 burst
+    pub fn respond_separately(&mut self, request: &Request, response: &Response, now: Instant) -> io::Result<()> {
+        let peer = request.get_peer()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "request did not come from a peer"))?;
+        let token = request.get_token().cloned().unwrap_or_default();
+        let mut response = response.clone();
+        response.set_type(MessageType::Non);
+        let msg_id = self.next_message_id(peer, now)?;
+        let data = response.to_frame(msg_id, token)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "token too long"))?
+            .to_bytes();
+        self.transmits.push_back(Transmit { peer, data });
+        Ok(())
+    }
+
+    /// answer a request acknowledged earlier in a CON of its own, RFC 7252
+    /// section 5.2.2; it is retransmitted until the peer acknowledges it,
+    /// which is reported under the request's token
+    pub fn respond_confirmable(&mut self, request: &Request, response: &Response, now: Instant) -> io::Result<u16> {
+        let peer = request.get_peer()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "request did not come from a peer"))?;
+        let token = request.get_token().cloned().unwrap_or_default();
+        let mut response = response.clone();
+        response.set_type(MessageType::Con);
+        let msg_id = self.next_message_id(peer, now)?;
+        let data = response.to_frame(msg_id, token.clone())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "token too long"))?
+            .to_bytes();
+        self.submit(peer, Queued { msg_id, data, token: Some(token), confirmable: true }, now);
+        Ok(msg_id)
+    }
+
+    /// empty ACK for a CON request that gets no piggybacked response, sent
+    /// again when the request is retransmitted; nothing for a NON
+    pub fn acknowledge(&mut self, request: &Request, message_id: u16) {
+        let Some(peer) = request.get_peer() else {
+            return;
+        };
+        if matches!(request.get_type(), MessageType::Con) {
+            let ack = CoAPFrame::empty(MessageType::Ack, message_id).to_bytes();
+            self.dedup.set_reply(peer, message_id, ack.clone());
+            self.transmits.push_back(Transmit { peer, data: ack });
+        }
+    }
+
+    pub fn handle_datagram(&mut self, peer: SocketAddr, data: &[u8], now: Instant) {
+        // the peer is there, PROBING_RATE no longer holds back what is queued for it
+        if let Some(state) = self.peers.get_mut(&peer) {
+            state.unanswered = false;
+            state.next_send = None;
+        }
+        self.handle_message(peer, data, now);
+        self.release(peer, now);
+    }
+
+    fn handle_message(&mut self, peer: SocketAddr, data: &[u8], now: Instant) {
+        if data.len() < 4 {
+            return;
+        }
+        if data.len() > self.max_message_size {
+            self.handle_too_large(peer, data);
+            return;
+        }
+        let frame = match CoAPFrame::from_bytes(data.to_vec()) {
+            Ok(frame) => frame,
+            // a malformed CON is rejected, anything else silently ignored (RFC 7252 section 4.2)
+            Err(_) => {
+                if let Some(frame) = CoAPFrame::from_truncated(data) {
+                    if frame.header.get_type() == u8::from(MessageType::Con) {
+                        self.reply(peer, CoAPFrame::empty(MessageType::Rst, frame.header.get_msg_id()));
+                    }
+                }
+                return;
+            }
+        };
+        let msg_id = frame.header.get_msg_id();
+        if frame.header.get_tkl() as usize > self.max_token_length {
+            if frame.header.get_type() == u8::from(MessageType::Con) {
+                self.reply(peer, CoAPFrame::empty(MessageType::Rst, msg_id));
+            }
+            return;
+        }
+        match MessageType::try_from(frame.header.get_type()) {
+            Ok(MessageType::Ack) => self.handle_ack(peer, &frame, now),
+            Ok(MessageType::Rst) => {
+                let Some(out) = self.outstanding.remove(&(peer, msg_id)) else {
+                    return;
+                };
+                self.measure(peer, &out, now);
+                match out.token {
+                    Some(token) => {
+                        self.open.remove(&token);
+                        self.events.push_back(Event::Reset { token });
+                    }
+                    None => self.events.push_back(Event::Pong { peer, message_id: msg_id, rtt: now - out.sent }),
+                }
+            }
+            Ok(MessageType::Con) if frame.is_empty_message() => self.reply(peer, CoAPFrame::empty(MessageType::Rst, msg_id)),
+            Ok(msg_type @ (MessageType::Con | MessageType::Non)) if !frame.is_empty_message() => {
+                let confirmable = matches!(msg_type, MessageType::Con);
+                // retransmitted CON gets the same reply again, duplicated NON is dropped
+                if let Dedup::Duplicate(reply) = self.dedup.check(peer, msg_id, confirmable, now) {
+                    if let Some(reply) = reply {
+                        self.transmits.push_back(Transmit { peer, data: reply });
+                    }
+                    return;
+                }
+                if frame.header.get_code() >> 5 == 0 {
+                    self.handle_request(peer, &frame, confirmable, now);
+                } else {
+                    self.handle_response(peer, &frame, confirmable);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// retransmit what is due and close expired tokens
+    pub fn handle_timeout(&mut self, now: Instant) {
+        let due: Vec<(SocketAddr, u16)> = self.outstanding.iter()
+            .filter(|(_, out)| out.next <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in due {
+            let out = self.outstanding.get_mut(&key).unwrap();
+            if out.retransmits < self.params.max_retransmit {
+                out.retransmits += 1;
+                out.timeout = out.timeout.mul_f64(out.backoff);
+                out.next = now + out.timeout;
+                out.sent = now;
+                self.transmits.push_back(Transmit { peer: key.0, data: out.data.clone() });
+                continue;
+            }
+            let out = self.outstanding.remove(&key).unwrap();
+            self.peers.entry(key.0).or_default().unanswered = true;
+            match out.token {
+                Some(token) => {
+                    self.open.remove(&token);
+                    self.events.push_back(Event::NotAcknowledged { token });
+                }
+                None => self.events.push_back(Event::PingTimedOut { peer: key.0, message_id: key.1 }),
+            }
+        }
+
+        let expired: Vec<Vec<u8>> = self.open.iter()
+            .filter(|(_, open)| open.deadline <= now)
+            .map(|(token, _)| token.clone())
+            .collect();
+        for token in expired {
+            self.open.remove(&token);
+            self.awaiting.remove(&token);
+            self.outstanding.retain(|_, out| out.token.as_ref() != Some(&token));
+            // too late for requests still waiting their turn
+            for state in self.peers.values_mut() {
+                state.queue.retain(|queued| queued.token.as_ref() != Some(&token));
+            }
+            self.events.push_back(Event::TimedOut { token });
+        }
+
+        let queued: Vec<SocketAddr> = self.peers.keys().copied().collect();
+        for peer in queued {
+            self.release(peer, now);
+        }
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// first event `f` accepts, the others stay queued for whoever waits on them
+    pub fn poll_event_matching<F: FnMut(&Event) -> bool>(&mut self, f: F) -> Option<Event> {
+        let index = self.events.iter().position(f)?;
+        self.events.remove(index)
+    }
+
+    /// when `handle_timeout` has to be called next, None while idle
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let retransmit = self.outstanding.values().map(|out| out.next);
+        let deadline = self.open.values().map(|open| open.deadline);
+        let paced = self.peers.values()
+            .filter(|state| state.unanswered && !state.queue.is_empty())
+            .filter_map(|state| state.next_send);
+        retransmit.chain(deadline).chain(paced).min()
+    }
+
+    fn next_message_id(&mut self, peer: SocketAddr, now: Instant) -> io::Result<u16> {
+        self.message_ids.next_with(peer, now, &mut *self.rng)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "all message ids in use"))
+    }
+
+    /// queue a message for `peer` and send what may go
+    fn submit(&mut self, peer: SocketAddr, message: Queued, now: Instant) {
+        self.peers.entry(peer).or_default().queue.push_back(message);
+        self.release(peer, now);
+    }
+
+    /// interactions with `peer` still under way: CON messages waiting for
+    /// their ACK and NON requests waiting for a response
+    fn interactions(&self, peer: SocketAddr) -> usize {
+        let confirmable = self.outstanding.keys().filter(|(p, _)| *p == peer).count();
+        let non = self.awaiting.values().filter(|p| **p == peer).count();
+        confirmable + non
+    }
+
+    /// send queued messages to `peer` while NSTART and PROBING_RATE allow
+    fn release(&mut self, peer: SocketAddr, now: Instant) {
+        let params = self.get_congestion_parameters(peer);
+        loop {
+            let Some(state) = self.peers.get(&peer) else {
+                return;
+            };
+            let Some(message) = state.queue.front() else {
+                if !state.unanswered {
+                    self.peers.remove(&peer);
+                }
+                return;
+            };
+            // more of a NON interaction under way, like the rest of a Q-Block burst
+            let continuation = !message.confirmable
+                && message.token.as_ref().is_some_and(|token| self.awaiting.contains_key(token));
+            if !continuation {
+                if self.interactions(peer) >= params.nstart.max(1) {
+                    // pacing that has run out no longer holds the queue back,
+                    // the end of an interaction will release it
+                    if state.next_send.is_some_and(|next| next <= now) {
+                        self.peers.get_mut(&peer).unwrap().next_send = None;
+                    }
+                    return;
+                }
+                if state.unanswered && state.next_send.is_some_and(|next| next > now) {
+                    return;
+                }
+            }
+
+            let state = self.peers.get_mut(&peer).unwrap();
+            let message = state.queue.pop_front().unwrap();
+            // bytes sent without hearing back are spread out at PROBING_RATE
+            if !continuation && (state.unanswered || !message.confirmable) {
+                let start = state.next_send.map_or(now, |next| next.max(now));
+                let rate = params.probing_rate.max(1) as f64;
+                state.next_send = Some(start + Duration::from_secs_f64(message.data.len() as f64 / rate));
+            }
+            if !message.confirmable {
+                state.unanswered = true;
+            }
+            if message.confirmable {
+                self.track(peer, message.msg_id, message.data.clone(), message.token.clone(), now);
+            } else if let Some(token) = message.token.filter(|token| self.open.contains_key(token)) {
+                self.awaiting.insert(token, peer);
+            }
+            self.transmits.push_back(Transmit { peer, data: message.data });
+        }
+    }
+
+    fn track(&mut self, peer: SocketAddr, msg_id: u16, data: Vec<u8>, token: Option<Vec<u8>>, now: Instant) {
+        let (timeout, backoff) = match self.retransmission {
+            RetransmissionPolicy::Fixed => (self.params.initial_timeout_with(&mut *self.rng), 2.0),
+            RetransmissionPolicy::Cocoa => {
+                if !self.estimators.contains_key(&peer) {
+                    self.prune_estimators(now);
+                }
+                let estimator = self.estimators.entry(peer)
+                    .or_insert_with(|| RtoEstimator::new(self.params.ack_timeout, now));
+                estimator.age(now);
+                (estimator.initial_timeout_with(self.params.ack_random_factor, &mut *self.rng), estimator.backoff_factor())
+            }
+        };
+        self.outstanding.insert((peer, msg_id), Outstanding {
+            data,
+            token,
+            timeout,
+            backoff,
+            retransmits: 0,
+            next: now + timeout,
+            first_sent: now,
+            sent: now,
+        });
+    }
+
+    /// forget the estimators of peers not talked to for EXCHANGE_LIFETIME,
+    /// so talking to many peers once does not grow the map without bound;
+    /// such a peer starts over from ACK_TIMEOUT
+    fn prune_estimators(&mut self, now: Instant) {
+        let busy: HashSet<SocketAddr> = self.outstanding.keys().map(|(peer, _)| *peer).collect();
+        self.estimators.retain(|peer, estimator| busy.contains(peer) || !estimator.is_idle(EXCHANGE_LIFETIME, now));
+    }
+
+    /// feed the round trip of an answered CON to the peer's CoCoA estimator;
+    /// after more than two retransmissions it is too ambiguous to use
+    fn measure(&mut self, peer: SocketAddr, out: &Outstanding, now: Instant) {
+        let Some(estimator) = self.estimators.get_mut(&peer) else {
+            return;
+        };
+        match out.retransmits {
+            0 => estimator.update_strong(now - out.first_sent, now),
+            1 | 2 => estimator.update_weak(now - out.first_sent, now),
+            _ => {}
+        }
+    }
+
+    fn reply(&mut self, peer: SocketAddr, frame: CoAPFrame) {
+        self.transmits.push_back(Transmit { peer, data: frame.to_bytes() });
+    }
+
+    fn handle_ack(&mut self, peer: SocketAddr, frame: &CoAPFrame, now: Instant) {
+        let Some(out) = self.outstanding.remove(&(peer, frame.header.get_msg_id())) else {
+            return;
+        };
+        self.measure(peer, &out, now);
+        let Some(token) = out.token else {
+            // an ACK to a ping is not what RFC 7252 asks for, but it is an answer
+            let rtt = now - out.sent;
+            self.events.push_back(Event::Pong { peer, message_id: frame.header.get_msg_id(), rtt });
+            return;
+        };
+        if frame.is_empty_message() {
+            self.events.push_back(Event::Acknowledged { token });
+        } else if frame.get_token() == token && self.open.contains_key(&token) {
+            self.events.push_back(Event::Response { token, response: Response::from_frame(frame) });
+        }
+    }
+
+    fn handle_request(&mut self, peer: SocketAddr, frame: &CoAPFrame, confirmable: bool, now: Instant) {
+        let msg_id = frame.header.get_msg_id();
+        match Request::from_frame(frame, peer) {
+            Ok(request) if self.accept_requests => self.events.push_back(Event::Request { message_id: msg_id, request }),
+            // a method code this endpoint does not know
+            Err(_) if self.accept_requests => {
+                let mut response = Response::new(ResponseCode::MethodNotAllowed);
+                let (msg_type, reply_id) = match confirmable {
+                    true => (MessageType::Ack, msg_id),
+                    false => match self.next_message_id(peer, now) {
+                        Ok(reply_id) => (MessageType::Non, reply_id),
+                        Err(_) => return,
+                    },
+                };
+                response.set_type(msg_type);
+                let Ok(reply) = response.to_frame(reply_id, frame.get_token()) else {
+                    return;
+                };
+                let data = reply.to_bytes();
+                if confirmable {
+                    self.dedup.set_reply(peer, msg_id, data.clone());
+                }
+                self.transmits.push_back(Transmit { peer, data });
+            }
+            _ if confirmable => self.reply(peer, CoAPFrame::empty(MessageType::Rst, msg_id)),
+            _ => {}
+        }
+    }
+
+    /// a truncated datagram: a CON request is answered with 4.13 and the
+    /// size accepted in Size1 (RFC 7959 section 2.9.3), a response closes
+    /// its token as it cannot be read
+    fn handle_too_large(&mut self, peer: SocketAddr, data: &[u8]) {
+        let Some(frame) = CoAPFrame::from_truncated(data) else {
+            return;
+        };
+        let msg_id = frame.header.get_msg_id();
+        let token = frame.get_token();
+        let msg_type = MessageType::try_from(frame.header.get_type());
+        if frame.header.get_code() >> 5 == 0 {
+            if !matches!(msg_type, Ok(MessageType::Con)) {
+                return;
+            }
+            if !self.accept_requests {
+                return self.reply(peer, CoAPFrame::empty(MessageType::Rst, msg_id));
+            }
+            let mut response = Response::new(ResponseCode::RequestEntityTooLarge);
+            response.set_type(MessageType::Ack);
+            response.set_option(OptionEnum::Size1, uint_to_bytes(self.max_message_size as u32));
+            if let Ok(frame) = response.to_frame(msg_id, token) {
+                self.reply(peer, frame);
+            }
+            return;
+        }
+        if self.open.get(&token).is_none_or(|open| open.peer != peer) {
+            return;
+        }
+        // stop the peer from retransmitting what will never fit
+        if matches!(msg_type, Ok(MessageType::Con)) {
+            self.reply(peer, CoAPFrame::empty(MessageType::Rst, msg_id));
+        }
+        self.open.remove(&token);
+        self.awaiting.remove(&token);
+        self.outstanding.retain(|_, out| out.token.as_ref() != Some(&token));
+        self.events.push_back(Event::TooLarge { token });
+    }
+
+    /// a separate response, or a notification, arriving as CON or NON
+    fn handle_response(&mut self, peer: SocketAddr, frame: &CoAPFrame, confirmable: bool) {
+        let msg_id = frame.header.get_msg_id();
+        let token = frame.get_token();
+        let matched = self.open.get(&token).is_some_and(|open| open.peer == peer);
+        if confirmable {
+            let answer_type = if matched { MessageType::Ack } else { MessageType::Rst };
+            let answer = CoAPFrame::empty(answer_type, msg_id).to_bytes();
+            self.dedup.set_reply(peer, msg_id, answer.clone());
+            self.transmits.push_back(Transmit { peer, data: answer });
+        }
+        if matched {
+            // the response implies the request arrived, even if its ACK got lost
+            self.outstanding.retain(|_, out| out.token.as_ref() != Some(&token));
+            self.awaiting.remove(&token);
+            self.events.push_back(Event::Response { token, response: Response::from_frame(frame) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, net::SocketAddr, time::{Duration, Instant}};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        endpoint::{Endpoint, Event},
+        frame::{CoAPFrame, Header, MessageType, OptionEnum},
+        request::{test_request, RequestMethod},
+        response::{Response, ResponseCode},
+        transmission::{CongestionParameters, RetransmissionPolicy, TransmissionParameters},
+    };
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:5683".parse().unwrap()
+    }
+
+    #[test]
+    fn retransmits_until_acknowledged() {
+        let mut endpoint = Endpoint::new();
+        endpoint.set_transmission_parameters(TransmissionParameters {
+            ack_timeout: Duration::from_secs(2),
+            ack_random_factor: 1.0,
+            max_retransmit: 1,
+        });
+        let request = test_request(RequestMethod::Get, peer());
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(60);
+        endpoint.request(peer(), &request, vec![1], deadline, start).unwrap();
+        let first = endpoint.poll_transmit().unwrap();
+        assert!(endpoint.poll_transmit().is_none());
+        assert_eq!(endpoint.poll_timeout(), Some(start + Duration::from_secs(2)));
+
+        endpoint.handle_timeout(start + Duration::from_secs(2));
+        assert_eq!(endpoint.poll_transmit(), Some(first));
+        assert_eq!(endpoint.poll_timeout(), Some(start + Duration::from_secs(6)));
+        endpoint.handle_timeout(start + Duration::from_secs(6));
+        assert!(endpoint.poll_transmit().is_none());
+        assert!(matches!(endpoint.poll_event(), Some(Event::NotAcknowledged { token }) if token == [1]));
+        assert_eq!(endpoint.poll_timeout(), None);
+    }
+
+    #[test]
+    fn idle_estimators_are_forgotten() {
+        let mut endpoint = Endpoint::new();
+        endpoint.set_retransmission_policy(RetransmissionPolicy::Cocoa);
+        let request = test_request(RequestMethod::Get, peer());
+        let other: SocketAddr = "127.0.0.2:5683".parse().unwrap();
+        let start = Instant::now();
+        let msg_id = endpoint.request(peer(), &request, vec![1], start + Duration::from_secs(60), start).unwrap();
+        endpoint.handle_datagram(peer(), &CoAPFrame::empty(MessageType::Ack, msg_id).to_bytes(), start);
+        endpoint.close(&[1], start);
+        assert!(endpoint.get_rto(peer()).is_some());
+
+        let later = start + Duration::from_secs(200);
+        endpoint.request(other, &request, vec![2], later + Duration::from_secs(60), later).unwrap();
+        assert!(endpoint.get_rto(peer()).is_some());
+        endpoint.close(&[2], later);
+        let much_later = start + Duration::from_secs(300);
+        endpoint.request("127.0.0.3:5683".parse().unwrap(), &request, vec![3], much_later + Duration::from_secs(60), much_later).unwrap();
+        assert_eq!(endpoint.get_rto(peer()), None);
+        assert!(endpoint.get_rto(other).is_some());
+    }
+
+    #[test]
+    fn seeded_rng_is_reproducible() {
+        let request = test_request(RequestMethod::Get, peer());
+        let now = Instant::now();
+        let run = |seed| {
+            let mut endpoint = Endpoint::new();
+            endpoint.set_rng(StdRng::seed_from_u64(seed));
+            endpoint.request(peer(), &request, vec![1], now + Duration::from_secs(60), now).unwrap();
+            (endpoint.poll_transmit().unwrap(), endpoint.poll_timeout())
+        };
+        assert_eq!(run(5), run(5));
+        assert_ne!(run(5), run(6));
+    }
+
+    #[test]
+    fn piggybacked_and_separate_responses() {
+        let mut endpoint = Endpoint::new();
+        let request = test_request(RequestMethod::Get, peer());
+        let now = Instant::now();
+        let msg_id = endpoint.request(peer(), &request, vec![1], now + Duration::from_secs(60), now).unwrap();
+        endpoint.poll_transmit().unwrap();
+
+        endpoint.handle_datagram(peer(), &CoAPFrame::empty(MessageType::Ack, msg_id).to_bytes(), now);
+        assert!(matches!(endpoint.poll_event(), Some(Event::Acknowledged { .. })));
+        // only the deadline is left once the request is acknowledged
+        assert_eq!(endpoint.poll_timeout(), Some(now + Duration::from_secs(60)));
+
+        let mut separate = Response::new(ResponseCode::Content);
+        separate.set_type(MessageType::Con);
+        let bytes = separate.to_frame(7, vec![1]).unwrap().to_bytes();
+        endpoint.handle_datagram(peer(), &bytes, now);
+        let ack = CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap();
+        assert_eq!(ack.header.get_type(), u8::from(MessageType::Ack));
+        assert!(matches!(endpoint.poll_event(), Some(Event::Response { response, .. }) if response.get_response_code() == ResponseCode::Content));
+
+        // the retransmitted response is acknowledged again but not reported twice
+        endpoint.handle_datagram(peer(), &bytes, now);
+        assert!(endpoint.poll_transmit().is_some());
+        assert!(endpoint.poll_event().is_none());
+
+        // once closed, responses for the token are rejected
+        endpoint.close(&[1], now);
+        endpoint.handle_datagram(peer(), &separate.to_frame(8, vec![1]).unwrap().to_bytes(), now);
+        let rst = CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap();
+        assert_eq!(rst.header.get_type(), u8::from(MessageType::Rst));
+        assert!(endpoint.poll_event().is_none());
+    }
+
+    #[test]
+    fn requests_from_peers() {
+        let mut endpoint = Endpoint::new();
+        let mut request = test_request(RequestMethod::Get, peer());
+        request.set_type(MessageType::Con);
+        let mut frame = request.to_frame();
+        frame.header.set_msg_id(3);
+        frame.set_token(vec![9]).unwrap();
+        let now = Instant::now();
+
+        endpoint.handle_datagram(peer(), &frame.to_bytes(), now);
+        let rst = CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap();
+        assert_eq!(rst.header.get_type(), u8::from(MessageType::Rst));
+
+        endpoint.set_accept_requests(true);
+        frame.header.set_msg_id(4);
+        endpoint.handle_datagram(peer(), &frame.to_bytes(), now);
+        let Some(Event::Request { message_id, request }) = endpoint.poll_event() else {
+            panic!("no request event");
+        };
+        endpoint.respond(&request, message_id, &Response::new(ResponseCode::Content), now).unwrap();
+        let reply = CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap();
+        assert_eq!(reply.header.get_type(), u8::from(MessageType::Ack));
+        assert_eq!(reply.header.get_msg_id(), 4);
+        assert_eq!(reply.get_token(), vec![9]);
+
+        // an empty ACK first, the response in a message of its own
+        frame.header.set_msg_id(5);
+        endpoint.handle_datagram(peer(), &frame.to_bytes(), now);
+        let Some(Event::Request { message_id, request }) = endpoint.poll_event() else {
+            panic!("no request event");
+        };
+        endpoint.acknowledge(&request, message_id);
+        endpoint.respond_separately(&request, &Response::new(ResponseCode::Content), now).unwrap();
+        let ack = CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap();
+        assert!(ack.is_empty_message());
+        assert_eq!(ack.header.get_msg_id(), 5);
+        let separate = CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap();
+        assert_eq!(separate.header.get_type(), u8::from(MessageType::Non));
+        assert_eq!(separate.get_token(), vec![9]);
+        // a retransmission only gets the ACK again
+        endpoint.handle_datagram(peer(), &frame.to_bytes(), now);
+        assert_eq!(endpoint.poll_transmit().unwrap().data, ack.to_bytes());
+        assert!(endpoint.poll_event().is_none());
+
+        // a separate response as CON is retransmitted until acknowledged
+        let msg_id = endpoint.respond_confirmable(&request, &Response::new(ResponseCode::Content), now).unwrap();
+        let separate = CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap();
+        assert_eq!(separate.header.get_type(), u8::from(MessageType::Con));
+        assert_eq!(separate.header.get_msg_id(), msg_id);
+        assert_eq!(separate.get_token(), vec![9]);
+        let later = now + Duration::from_secs(10);
+        endpoint.handle_timeout(later);
+        assert_eq!(endpoint.poll_transmit().unwrap().data, separate.to_bytes());
+        endpoint.handle_datagram(peer(), &CoAPFrame::empty(MessageType::Ack, msg_id).to_bytes(), later);
+        assert!(matches!(endpoint.poll_event(), Some(Event::Acknowledged { token }) if token == vec![9]));
+        assert_eq!(endpoint.poll_timeout(), None);
+
+        // a method code that is not known
+        let mut header = Header::new(MessageType::Con.into(), 0x1F);
+        header.set_msg_id(6);
+        endpoint.handle_datagram(peer(), &CoAPFrame::new(header, BTreeMap::new(), vec![]).to_bytes(), now);
+        let reply = Response::from_frame(&CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap());
+        assert_eq!(reply.get_response_code(), ResponseCode::MethodNotAllowed);
+        assert!(endpoint.poll_event().is_none());
+    }
+
+    #[test]
+    fn malformed_datagrams() {
+        let mut endpoint = Endpoint::new();
+        let now = Instant::now();
+        // token length 8 but no token
+        endpoint.handle_datagram(peer(), &[0x48, 1, 0, 0], now);
+        assert!(endpoint.poll_transmit().is_none());
+        // a CON whose option runs past the end is rejected
+        endpoint.handle_datagram(peer(), &[0x40, 1, 0, 9, 0xB3, b'a'], now);
+        let rst = CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap();
+        assert_eq!(rst.header.get_type(), u8::from(MessageType::Rst));
+        assert_eq!(rst.header.get_msg_id(), 9);
+        assert!(endpoint.poll_event().is_none());
+    }
+
+    #[test]
+    fn tokens_above_max_token_length() {
+        let mut endpoint = Endpoint::new();
+        let now = Instant::now();
+        let request = test_request(RequestMethod::Get, peer());
+        assert!(endpoint.request(peer(), &request, vec![1; 9], now + Duration::from_secs(60), now).is_err());
+
+        // a separate response with a 9 byte token is refused
+        let mut response = Response::new(ResponseCode::Content);
+        response.set_type(MessageType::Con);
+        endpoint.handle_datagram(peer(), &response.to_frame(3, vec![1; 9]).unwrap().to_bytes(), now);
+        let rst = CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap();
+        assert_eq!(rst.header.get_type(), u8::from(MessageType::Rst));
+        assert_eq!(rst.header.get_msg_id(), 3);
+        assert!(endpoint.poll_event().is_none());
+
+        endpoint.set_max_token_length(9);
+        assert!(endpoint.request(peer(), &request, vec![1; 9], now + Duration::from_secs(60), now).is_ok());
+    }
+
+    #[test]
+    fn datagrams_above_max_message_size() {
+        let mut endpoint = Endpoint::new();
+        endpoint.set_max_message_size(100);
+        let mut request = test_request(RequestMethod::Post, peer());
+        request.set_body(vec![0; 200]);
+        let now = Instant::now();
+        assert!(endpoint.request(peer(), &request, vec![1], now + Duration::from_secs(60), now).is_err());
+
+        request.set_body(vec![]);
+        let msg_id = endpoint.request(peer(), &request, vec![1], now + Duration::from_secs(60), now).unwrap();
+        endpoint.poll_transmit().unwrap();
+        let mut response = Response::new(ResponseCode::Content);
+        response.set_type(MessageType::Ack);
+        response.set_body(vec![0; 200]);
+        // as read into a buffer one byte above the limit
+        let truncated = &response.to_frame(msg_id, vec![1]).unwrap().to_bytes()[..101];
+        endpoint.handle_datagram(peer(), truncated, now);
+        assert!(matches!(endpoint.poll_event(), Some(Event::TooLarge { token }) if token == [1]));
+        assert_eq!(endpoint.poll_timeout(), None);
+
+        // a request that does not fit is answered 4.13 with the size accepted
+        endpoint.set_accept_requests(true);
+        request.set_type(MessageType::Con);
+        request.set_body(vec![0; 200]);
+        let mut frame = request.to_frame();
+        frame.header.set_msg_id(5);
+        frame.set_token(vec![2]).unwrap();
+        endpoint.handle_datagram(peer(), &frame.to_bytes()[..101], now);
+        let reply = CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap();
+        assert_eq!(reply.header.get_code(), u8::from(ResponseCode::RequestEntityTooLarge));
+        assert_eq!(reply.get_token(), vec![2]);
+        assert_eq!(reply.get_options().get(&u16::from(OptionEnum::Size1)), Some(&vec![vec![100]]));
+        assert!(endpoint.poll_event().is_none());
+    }
+
+    #[test]
+    fn nstart_and_probing_rate() {
+        let mut endpoint = Endpoint::new();
+        let mut request = test_request(RequestMethod::Get, peer());
+        request.set_type(MessageType::Con);
+        let now = Instant::now();
+        let deadline = now + Duration::from_secs(60);
+
+        // NSTART 1: the second request waits for the first to be acknowledged
+        let first = endpoint.request(peer(), &request, vec![1], deadline, now).unwrap();
+        endpoint.request(peer(), &request, vec![2], deadline, now).unwrap();
+        assert!(endpoint.poll_transmit().is_some());
+        assert!(endpoint.poll_transmit().is_none());
+        assert!(endpoint.is_queued(&[2]));
+        endpoint.handle_datagram(peer(), &CoAPFrame::empty(MessageType::Ack, first).to_bytes(), now);
+        assert_eq!(CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap().get_token(), vec![2]);
+
+        // a peer of its own may have more
+        let other: SocketAddr = "127.0.0.2:5683".parse().unwrap();
+        let params = CongestionParameters { nstart: 2, probing_rate: 10 };
+        endpoint.set_peer_congestion_parameters(other, params);
+        endpoint.request(other, &request, vec![3], deadline, now).unwrap();
+        endpoint.request(other, &request, vec![4], deadline, now).unwrap();
+        assert!(endpoint.poll_transmit().is_some() && endpoint.poll_transmit().is_some());
+
+        // NON requests to a peer that does not answer go out at PROBING_RATE
+        let quiet: SocketAddr = "127.0.0.3:5683".parse().unwrap();
+        endpoint.set_peer_congestion_parameters(quiet, params);
+        request.set_type(MessageType::Non);
+        endpoint.request(quiet, &request, vec![5], deadline, now).unwrap();
+        endpoint.request(quiet, &request, vec![6], deadline, now).unwrap();
+        let sent = endpoint.poll_transmit().unwrap();
+        assert!(endpoint.poll_transmit().is_none());
+        let paced = now + Duration::from_secs_f64(sent.data.len() as f64 / 10.0);
+        assert_eq!(endpoint.poll_timeout(), Some(paced));
+        endpoint.handle_timeout(paced);
+        assert_eq!(CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap().get_token(), vec![6]);
+
+        // hearing from it lifts the limit
+        endpoint.close(&[5], paced);
+        endpoint.request(quiet, &request, vec![7], deadline, paced).unwrap();
+        assert!(endpoint.poll_transmit().is_none());
+        let mut response = Response::new(ResponseCode::Content);
+        response.set_type(MessageType::Non);
+        endpoint.handle_datagram(quiet, &response.to_frame(9, vec![6]).unwrap().to_bytes(), paced);
+        assert_eq!(CoAPFrame::from_bytes(endpoint.poll_transmit().unwrap().data).unwrap().get_token(), vec![7]);
+    }
+}
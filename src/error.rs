@@ -31,14 +31,27 @@ impl Error for InvalidType {
 }
 
 #[derive(Debug)]
-pub struct InvalidResponseCode;
+pub struct InvalidRequestMethod;
 
-impl Display for InvalidResponseCode {
+impl Display for InvalidRequestMethod {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "CoAP error: invalid response code")
+        write!(f, "CoAP error: invalid request method")
     }
 }
 
-impl Error for InvalidResponseCode {
+impl Error for InvalidRequestMethod {
     
-}
\ No newline at end of file
+}
+
+#[derive(Debug)]
+pub struct InvalidFrame;
+
+impl Display for InvalidFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CoAP error: malformed message")
+    }
+}
+
+impl Error for InvalidFrame {
+    
+}
@@ -1,19 +1,21 @@
 use core::convert::TryFrom;
 use std::collections::BTreeMap;
 
-use rand::Rng;
-
-use crate::error::{InvalidContentFormat, InvalidType};
+use crate::{error::{InvalidContentFormat, InvalidFrame, InvalidType}, token::MAX_EXTENDED_TOKEN_LEN};
 
 /// coap version
 const VER: u8 = 1;
-const TOKEN_LEN: u8 = 8;
+
+/// default upper bound on the size of a message, RFC 7252 section 4.6:
+/// a 1024 byte payload plus room for the header and options
+pub const MAX_MESSAGE_SIZE: usize = 1152;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Header {
     ver: u8,
     msg_type: u8,
-    tkl: u8,
+    /// token length, above 12 it is carried in extended token length bytes (RFC 8974)
+    tkl: u32,
     code: u8,
     msg_id: u16,
 }
@@ -60,7 +62,7 @@ impl TryFrom<u8> for MessageType {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum OptionEnum {
     IfMatch,
     UriHost,
@@ -72,11 +74,20 @@ pub enum OptionEnum {
     ContentFormat,
     MaxAge,
     UriQuery,
+    HopLimit,
     Accept,
+    QBlock1,
     LocationQuery,
+    Block2,
+    Block1,
+    Size2,
+    QBlock2,
     ProxyUri,
     ProxyScheme,
     Size1,
+    Echo,
+    NoResponse,
+    RequestTag,
     Unknown(u16),
 }
 
@@ -93,11 +104,20 @@ impl From<u16> for OptionEnum {
             12 => OptionEnum::ContentFormat,
             14 => OptionEnum::MaxAge,
             15 => OptionEnum::UriQuery,
+            16 => OptionEnum::HopLimit,
             17 => OptionEnum::Accept,
+            19 => OptionEnum::QBlock1,
             20 => OptionEnum::LocationQuery,
+            23 => OptionEnum::Block2,
+            27 => OptionEnum::Block1,
+            28 => OptionEnum::Size2,
+            31 => OptionEnum::QBlock2,
             35 => OptionEnum::ProxyUri,
             39 => OptionEnum::ProxyScheme,
             60 => OptionEnum::Size1,
+            252 => OptionEnum::Echo,
+            258 => OptionEnum::NoResponse,
+            292 => OptionEnum::RequestTag,
             _ => OptionEnum::Unknown(value),
         }
     }
@@ -116,16 +136,59 @@ impl From<OptionEnum> for u16 {
             OptionEnum::ContentFormat => 12,
             OptionEnum::MaxAge => 14,
             OptionEnum::UriQuery => 15,
+            OptionEnum::HopLimit => 16,
             OptionEnum::Accept => 17,
+            OptionEnum::QBlock1 => 19,
             OptionEnum::LocationQuery => 20,
+            OptionEnum::Block2 => 23,
+            OptionEnum::Block1 => 27,
+            OptionEnum::Size2 => 28,
+            OptionEnum::QBlock2 => 31,
             OptionEnum::ProxyUri => 35,
             OptionEnum::ProxyScheme => 39,
             OptionEnum::Size1 => 60,
+            OptionEnum::Echo => 252,
+            OptionEnum::NoResponse => 258,
+            OptionEnum::RequestTag => 292,
             OptionEnum::Unknown(value) => value,
         }
     }
 }
 
+/// options carried by the 7.01 CSM signaling message, RFC 8323 section 5.3
+///
+/// signaling options have their own number space, separate from `OptionEnum`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CsmOption {
+    MaxMessageSize,
+    BlockWiseTransfer,
+    /// largest token length the sender accepts, RFC 8974 section 2.2.1
+    ExtendedTokenLength,
+    Unknown(u16),
+}
+
+impl From<u16> for CsmOption {
+    fn from(value: u16) -> Self {
+        match value {
+            2 => CsmOption::MaxMessageSize,
+            4 => CsmOption::BlockWiseTransfer,
+            6 => CsmOption::ExtendedTokenLength,
+            _ => CsmOption::Unknown(value),
+        }
+    }
+}
+
+impl From<CsmOption> for u16 {
+    fn from(value: CsmOption) -> Self {
+        match value {
+            CsmOption::MaxMessageSize => 2,
+            CsmOption::BlockWiseTransfer => 4,
+            CsmOption::ExtendedTokenLength => 6,
+            CsmOption::Unknown(value) => value,
+        }
+    }
+}
+
 // impl From<OptionEnum> for String {
 //     fn from(value: OptionEnum) -> Self {
 //         match value {
@@ -172,7 +235,7 @@ impl CoapOption {
         }
 
         if delta == 14 {
-            encoded_option.push((self.number - 269_u16).try_into().unwrap())
+            encoded_option.extend_from_slice(&(self.number - 269).to_be_bytes());
         }
 
         if vl == 13 {
@@ -180,7 +243,7 @@ impl CoapOption {
         }
 
         if vl == 14 {
-            encoded_option.push(((length - 269) as u16).try_into().unwrap());
+            encoded_option.extend_from_slice(&((length - 269) as u16).to_be_bytes());
         }
 
         encoded_option.extend_from_slice(&self.value);
@@ -193,18 +256,24 @@ impl CoapOption {
 
 impl Header {
 
+    /// message id 0 and no token, the sender assigns both
     pub fn new(msg_type: u8, code: u8) -> Self {
         Header {
             ver: VER,
-            msg_type: msg_type,
-            tkl: TOKEN_LEN,
+            msg_type,
+            tkl: 0,
             code,
-            msg_id: generate_coap_message_id(),
+            msg_id: 0,
         }
     }
 
-    pub fn set_tkl(&mut self, tkl: u8) {
+    /// token length, at most 65804 bytes (RFC 8974 section 2.1)
+    pub fn set_tkl(&mut self, tkl: u32) -> Result<(), InvalidFrame> {
+        if tkl as usize > MAX_EXTENDED_TOKEN_LEN {
+            return Err(InvalidFrame);
+        }
         self.tkl = tkl;
+        Ok(())
     }
 
     pub fn set_msg_id(&mut self, msg_id: u16) {
@@ -219,10 +288,35 @@ impl Header {
         self.code
     }
 
-    fn to_bytes(&self) -> [u8; 4] {
-        let t = self.ver << 6 | self.msg_type << 4 | self.tkl;
+    pub fn get_msg_id(&self) -> u16 {
+        self.msg_id
+    }
+
+    pub fn get_tkl(&self) -> u32 {
+        self.tkl
+    }
+
+    /// header size on the wire including extended token length bytes
+    pub fn encoded_len(&self) -> usize {
+        match self.tkl {
+            0..=12 => 4,
+            13..=268 => 5,
+            _ => 6,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let (nibble, ext) = match self.tkl {
+            0..=12 => (self.tkl as u8, vec![]),
+            13..=268 => (13, vec![(self.tkl - 13) as u8]),
+            // set_tkl keeps it within 269 + 65535
+            _ => (14, ((self.tkl - 269) as u16).to_be_bytes().to_vec()),
+        };
+        let t = self.ver << 6 | self.msg_type << 4 | nibble;
         let msg_buf = self.msg_id.to_be_bytes();
-        [t, self.code, msg_buf[0], msg_buf[1]]
+        let mut buf = vec![t, self.code, msg_buf[0], msg_buf[1]];
+        buf.extend_from_slice(&ext);
+        buf
     }
 
     fn from_bytes(data: &[u8]) -> Option<Header> {
@@ -231,8 +325,18 @@ impl Header {
         }
 
         let ver = data[0] >> 6;
+        // messages of an unknown version are silently ignored
+        if ver != VER {
+            return None;
+        }
         let msg_type = data[0] >> 4 & 0x3;
-        let tkl = data[0] & 0xF;
+        let tkl = match data[0] & 0xF {
+            13 if data.len() >= 5 => 13 + data[4] as u32,
+            14 if data.len() >= 6 => 269 + u16::from_be_bytes([data[4], data[5]]) as u32,
+            nibble if nibble < 13 => nibble as u32,
+            // 15 is reserved, or the extended length bytes are missing
+            _ => return None,
+        };
 
         let code = data[1];
         let msg_id = u16::from_be_bytes([data[2], data[3]]);
@@ -248,105 +352,80 @@ impl Header {
 
 impl CoAPFrame {
 
+    /// the token is as long as the header says but all zero, the sender
+    /// sets its own with `set_token`
     pub fn new(header: Header, options: BTreeMap<u16, Vec<Vec<u8>>>, payload: Vec<u8>) -> Self {
         let tkl = header.tkl;
         CoAPFrame {
             header,
-            token: generate_coap_token(tkl as usize),
+            token: vec![0; tkl as usize],
             options,
             ff: 0xFF,
             payload,
         }
     }
 
-    pub fn from_bytes(bytes: Vec<u8>) -> Self {
-        let header = Header::from_bytes(&bytes[..4]).unwrap();
-        let token = &bytes[4..(4 + header.tkl as usize)];
+    /// empty message (code 0.00), used for ACK, RST and CoAP ping
+    pub fn empty(msg_type: MessageType, msg_id: u16) -> Self {
+        let mut header = Header::new(msg_type.into(), 0);
+        header.tkl = 0;
+        header.set_msg_id(msg_id);
+        CoAPFrame::new(header, BTreeMap::new(), vec![])
+    }
+
+    pub fn is_empty_message(&self) -> bool {
+        self.header.code == 0
+    }
+
+    /// header and token of a datagram that was cut short, its options and
+    /// payload are left out as they cannot be trusted
+    pub fn from_truncated(bytes: &[u8]) -> Option<Self> {
+        let header = Header::from_bytes(bytes)?;
+        let token_start = header.encoded_len();
+        let token = bytes.get(token_start..token_start + header.tkl as usize)?.to_vec();
+        Some(CoAPFrame {
+            header,
+            token,
+            options: BTreeMap::new(),
+            ff: 0xFF,
+            payload: vec![],
+        })
+    }
+
+    /// parse a received message, anything that breaks the format of
+    /// RFC 7252 section 3 is an error rather than a guess
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, InvalidFrame> {
+        let header = Header::from_bytes(&bytes).ok_or(InvalidFrame)?;
+        let mut offset = header.encoded_len();
+        let token = bytes.get(offset..offset + header.tkl as usize).ok_or(InvalidFrame)?.to_vec();
+        offset += header.tkl as usize;
         let mut options: BTreeMap<u16, Vec<Vec<u8>>> = BTreeMap::new();
-        let mut offset = 4 + header.tkl as usize;
+        let mut number: u32 = 0;
         let mut payload: Vec<u8> = Vec::new();
-        if bytes.len() > offset {
-            if bytes[offset] == 0xFF {
-                payload = bytes[offset + 1..].to_vec();
-            } else {
-                let mut number: u16 = 0;
-                loop {
-                    if bytes[offset] == 0xFF {
-                        break;
-                    }
-                    let first_byte = bytes[offset];
-                    let delta = (first_byte >> 4) & 0xF;
-                    let vl = first_byte & 0xF;
-
-                    let mut length: u16 = vl as u16;
-
-                    if delta < 13 {
-                        number += delta as u16;
-                        if vl < 13 {
-                            offset += 1;
-                        } else if vl == 13 {
-                            length = 13 + bytes[offset + 1] as u16;
-                            offset += 2;
-                        } else {
-                            length = 269
-                                + u16::from_be_bytes([bytes[offset + 1], bytes[offset + 2]]);
-                            offset += 3;
-                        }
-                    } else if delta == 13 {
-                        number = number + 13 + bytes[offset + 1] as u16;
-                        if vl < 13 {
-                            offset += 2;
-                        } else if vl == 13 {
-                            length = 13 + bytes[offset + 2] as u16;
-                            offset += 3;
-                        } else {
-                            length = 269
-                                + u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]);
-                            offset += 4;
-                        }
-                    } else {
-                        number = number
-                            + 269
-                            + u16::from_be_bytes([bytes[offset + 1], bytes[offset + 2]]);
-
-                        if vl < 13 {
-                            offset += 3;
-                        } else if vl == 13 {
-                            length = 13 + bytes[offset + 3] as u16;
-                            offset += 4;
-                        } else {
-                            length = 269
-                                + u16::from_be_bytes([bytes[offset + 3], bytes[offset + 4]]);
-                            offset += 5;
-                        }
-                    };
-                    let value = bytes[offset..(offset + length as usize)].to_vec();
-                    offset += length as usize;
-
-                    let option_list = options.get(&number);
-                    match option_list {
-                        None => {
-                            options.insert(number, vec![value]);
-                        }
-                        Some(option_vec) => {
-                            let mut new_op = option_vec.clone();
-                            new_op.push(value);
-                            options.insert(number, new_op);
-                        }
-                    }
-                }
-                if bytes.len() > offset && bytes[offset] == 0xFF {
-                    payload = bytes[offset + 1..].to_vec();
+        while let Some(&first_byte) = bytes.get(offset) {
+            offset += 1;
+            if first_byte == 0xFF {
+                // a payload marker must be followed by a payload
+                payload = bytes[offset..].to_vec();
+                if payload.is_empty() {
+                    return Err(InvalidFrame);
                 }
+                break;
             }
+            number += option_nibble(first_byte >> 4, &bytes, &mut offset)? as u32;
+            let length = option_nibble(first_byte & 0xF, &bytes, &mut offset)?;
+            let value = bytes.get(offset..offset + length).ok_or(InvalidFrame)?.to_vec();
+            offset += length;
+            let number = u16::try_from(number).map_err(|_| InvalidFrame)?;
+            options.entry(number).or_default().push(value);
         }
-        CoAPFrame {
+        Ok(CoAPFrame {
             header,
-            token: token.to_vec(),
+            token,
             options,
             ff: 0xFF,
             payload,
-        }
+        })
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -376,33 +455,163 @@ impl CoAPFrame {
         buf
     }
 
+    /// the message in the RFC 8323 section 3.2 framing of reliable
+    /// transports, which has a length in place of type and message id
+    pub fn to_tcp_bytes(&self) -> Vec<u8> {
+        let bytes = self.to_bytes();
+        // token length extension, token, options and payload are unchanged
+        let rest = &bytes[4..];
+        let len = rest.len() - (self.header.encoded_len() - 4) - self.token.len();
+        let (nibble, ext) = match len {
+            0..=12 => (len as u8, vec![]),
+            13..=268 => (13, vec![(len - 13) as u8]),
+            269..=65804 => (14, ((len - 269) as u16).to_be_bytes().to_vec()),
+            _ => (15, ((len - 65805) as u32).to_be_bytes().to_vec()),
+        };
+        let mut buf = vec![nibble << 4 | bytes[0] & 0xF];
+        buf.extend_from_slice(&ext);
+        buf.push(self.header.code);
+        buf.extend_from_slice(rest);
+        buf
+    }
+
+    /// parse a whole RFC 8323 message; it carries no type or message id,
+    /// the receiver gives it these
+    pub fn from_tcp_bytes(bytes: &[u8], msg_type: MessageType, msg_id: u16) -> Result<Self, InvalidFrame> {
+        let first = *bytes.first().ok_or(InvalidFrame)?;
+        if tcp_message_len(bytes)? != bytes.len() {
+            return Err(InvalidFrame);
+        }
+        let mut offset = 1;
+        tcp_nibble(first >> 4, bytes, &mut offset)?;
+        let mut udp = vec![VER << 6 | u8::from(msg_type) << 4 | first & 0xF, bytes[offset]];
+        udp.extend_from_slice(&msg_id.to_be_bytes());
+        udp.extend_from_slice(&bytes[offset + 1..]);
+        CoAPFrame::from_bytes(udp)
+    }
+
     pub fn get_body(&self) -> Vec<u8> {
         self.payload.clone()
     }
 
+    pub fn get_token(&self) -> Vec<u8> {
+        self.token.clone()
+    }
+
+    /// a token longer than 65804 bytes cannot be encoded and is refused
+    pub fn set_token(&mut self, token: Vec<u8>) -> Result<(), InvalidFrame> {
+        self.header.set_tkl(u32::try_from(token.len()).map_err(|_| InvalidFrame)?)?;
+        self.token = token;
+        Ok(())
+    }
+
     pub fn get_options(&self) -> BTreeMap<u16, Vec<Vec<u8>>> {
         self.options.clone()
     }
 }
 
-fn generate_coap_message_id() -> u16 {
-    let mut rng = rand::thread_rng();
-    rng.gen()
+/// option delta or length, extended by the bytes at `offset` for 13 and 14
+fn option_nibble(nibble: u8, bytes: &[u8], offset: &mut usize) -> Result<usize, InvalidFrame> {
+    let value = match nibble {
+        0..=12 => return Ok(nibble as usize),
+        13 => 13 + *bytes.get(*offset).ok_or(InvalidFrame)? as usize,
+        14 => {
+            let ext = bytes.get(*offset..*offset + 2).ok_or(InvalidFrame)?;
+            269 + u16::from_be_bytes([ext[0], ext[1]]) as usize
+        }
+        // 15 is reserved outside the payload marker
+        _ => return Err(InvalidFrame),
+    };
+    *offset += if nibble == 13 { 1 } else { 2 };
+    Ok(value)
+}
+
+/// bytes of an RFC 8323 message up to its token, told by the first byte;
+/// None for the reserved token length 15
+pub fn tcp_head_len(first: u8) -> Option<usize> {
+    let ext = |nibble: u8| match nibble {
+        13 => 1,
+        14 => 2,
+        15 => 4,
+        _ => 0,
+    };
+    match first & 0xF {
+        15 => None,
+        tkl => Some(1 + ext(first >> 4) + 1 + ext(tkl)),
+    }
+}
+
+/// size of a whole RFC 8323 message from at least its `tcp_head_len` bytes
+pub fn tcp_message_len(head: &[u8]) -> Result<usize, InvalidFrame> {
+    let first = *head.first().ok_or(InvalidFrame)?;
+    let mut offset = 1;
+    let len = tcp_nibble(first >> 4, head, &mut offset)?;
+    // the code
+    offset += 1;
+    let tkl = tcp_nibble(first & 0xF, head, &mut offset)?;
+    if tkl > MAX_EXTENDED_TOKEN_LEN {
+        return Err(InvalidFrame);
+    }
+    Ok(offset + tkl + len)
 }
 
-fn generate_coap_token(length: usize) -> Vec<u8> {
-    let mut rng = rand::thread_rng();
-    let token: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
-    token
+/// length or token length of the RFC 8323 framing, which unlike options
+/// also uses 15 for a 4 byte extension
+fn tcp_nibble(nibble: u8, bytes: &[u8], offset: &mut usize) -> Result<usize, InvalidFrame> {
+    if nibble != 15 {
+        return option_nibble(nibble, bytes, offset);
+    }
+    let ext = bytes.get(*offset..*offset + 4).ok_or(InvalidFrame)?;
+    *offset += 4;
+    Ok(65805 + u32::from_be_bytes([ext[0], ext[1], ext[2], ext[3]]) as usize)
 }
 
-enum ContentFormat {
+/// Content-Format values, RFC 7252 section 12.3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentFormat {
     TextPlain,
     ApplicationLinkFormat,
     ApplicationXml,
     ApplicationOctetStream,
     ApplicationExi,
     ApplicationJson,
+    ApplicationCbor,
+}
+
+impl ContentFormat {
+
+    /// the matching HTTP media type, RFC 8075 section 6.1
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            ContentFormat::TextPlain => "text/plain; charset=utf-8",
+            ContentFormat::ApplicationLinkFormat => "application/link-format",
+            ContentFormat::ApplicationXml => "application/xml",
+            ContentFormat::ApplicationOctetStream => "application/octet-stream",
+            ContentFormat::ApplicationExi => "application/exi",
+            ContentFormat::ApplicationJson => "application/json",
+            ContentFormat::ApplicationCbor => "application/cbor",
+        }
+    }
+
+    /// parse an HTTP Content-Type, parameters other than a utf-8 charset are not supported
+    pub fn from_media_type(media_type: &str) -> Option<ContentFormat> {
+        let mut parts = media_type.split(';').map(|part| part.trim().to_ascii_lowercase());
+        let format = match parts.next()?.as_str() {
+            "text/plain" => ContentFormat::TextPlain,
+            "application/link-format" => ContentFormat::ApplicationLinkFormat,
+            "application/xml" => ContentFormat::ApplicationXml,
+            "application/octet-stream" => ContentFormat::ApplicationOctetStream,
+            "application/exi" => ContentFormat::ApplicationExi,
+            "application/json" => ContentFormat::ApplicationJson,
+            "application/cbor" => ContentFormat::ApplicationCbor,
+            _ => return None,
+        };
+        match parts.next() {
+            None => Some(format),
+            Some(param) if param.replace(' ', "") == "charset=utf-8" => Some(format),
+            Some(_) => None,
+        }
+    }
 }
 
 impl TryFrom<u16> for ContentFormat {
@@ -410,12 +619,13 @@ impl TryFrom<u16> for ContentFormat {
 
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
-            1 => Ok(ContentFormat::TextPlain),
+            0 => Ok(ContentFormat::TextPlain),
             40 => Ok(ContentFormat::ApplicationLinkFormat),
             41 => Ok(ContentFormat::ApplicationXml),
             42 => Ok(ContentFormat::ApplicationOctetStream),
             47 => Ok(ContentFormat::ApplicationExi),
             50 => Ok(ContentFormat::ApplicationJson),
+            60 => Ok(ContentFormat::ApplicationCbor),
             _ => Err(InvalidContentFormat),
         }
     }
@@ -424,12 +634,13 @@ impl TryFrom<u16> for ContentFormat {
 impl From<ContentFormat> for u16 {
     fn from(value: ContentFormat) -> u16 {
         match value {
-            ContentFormat::TextPlain => 1,
+            ContentFormat::TextPlain => 0,
             ContentFormat::ApplicationLinkFormat => 40,
             ContentFormat::ApplicationXml => 41,
             ContentFormat::ApplicationOctetStream => 42,
             ContentFormat::ApplicationExi => 47,
             ContentFormat::ApplicationJson => 50,
+            ContentFormat::ApplicationCbor => 60,
         }
     }
 }
@@ -440,21 +651,20 @@ mod test {
 
     use crate::{
         frame::{
-            generate_coap_message_id, generate_coap_token, CoAPFrame, ContentFormat, Header,
-            OptionEnum, MessageType
+            tcp_head_len, tcp_message_len, CoAPFrame, ContentFormat, Header, OptionEnum, MessageType
         },
         request::RequestMethod,
     };
 
     #[test]
     fn header_to_bytes() {
-        let msg_id = generate_coap_message_id();
+        let msg_id = 0x1234;
         let mut header = Header {
             ver: 1,
             msg_type: MessageType::Con as u8,
             tkl: 8,
             code: RequestMethod::Get as u8,
-            msg_id: msg_id,
+            msg_id,
         };
 
         let mut bytes = header.to_bytes();
@@ -466,7 +676,7 @@ mod test {
         bytes = header.to_bytes();
         assert_eq!(bytes[1], RequestMethod::Post as u8);
         let hop = Header::from_bytes(&bytes);
-        assert_eq!(hop.is_none(), false);
+        assert!(hop.is_some());
         let h = hop.unwrap();
         assert_eq!(h.code, RequestMethod::Post as u8);
         assert_eq!(h, header)
@@ -474,13 +684,13 @@ mod test {
 
     #[test]
     fn frame_to_bytes() {
-        let token = generate_coap_token(8);
+        let token = vec![0x5A, 1, 2, 3, 4, 5, 6, 0xA5];
         let header = Header {
             ver: 1,
             msg_type: MessageType::Con as u8,
-            tkl: token.len() as u8,
+            tkl: token.len() as u32,
             code: RequestMethod::Get as u8,
-            msg_id: generate_coap_message_id(),
+            msg_id: 0xBEEF,
         };
 
         let mut options = BTreeMap::new();
@@ -495,16 +705,94 @@ mod test {
             vec![Vec::from("hello"), Vec::from("world")],
         );
         let packet = CoAPFrame {
-            header: header,
-            token: token.clone().into(),
-            options: options,
+            header,
+            token: token.clone(),
+            options,
             ff: 0xFF,
             payload: "{\"hello\":\"world\"}".into(),
         };
 
         let encode_buffer = packet.to_bytes();
 
-        let frame = CoAPFrame::from_bytes(encode_buffer.to_vec());
+        let frame = CoAPFrame::from_bytes(encode_buffer.to_vec()).unwrap();
         assert_eq!(frame, packet);
     }
+
+    #[test]
+    fn extended_token_length() {
+        for len in [12usize, 13, 268, 269, 1000] {
+            let mut header = Header::new(MessageType::Con.into(), RequestMethod::Get as u8);
+            header.set_tkl(0).unwrap();
+            let mut packet = CoAPFrame::new(header, BTreeMap::new(), Vec::from("x"));
+            packet.set_token(vec![0xA5; len]).unwrap();
+
+            let bytes = packet.to_bytes();
+            let nibble = bytes[0] & 0xF;
+            assert_eq!(nibble, if len < 13 { len as u8 } else if len < 269 { 13 } else { 14 });
+            assert_eq!(CoAPFrame::from_bytes(bytes).unwrap(), packet);
+        }
+        assert!(Header::from_bytes(&[0x4F, 1, 0, 0]).is_none());
+        assert!(Header::from_bytes(&[0x4D, 1, 0, 0]).is_none());
+
+        // the longest token that can be encoded, and one byte more
+        let mut packet = CoAPFrame::empty(MessageType::Con, 1);
+        assert!(packet.set_token(vec![1; 65804]).is_ok());
+        assert!(packet.set_token(vec![1; 65805]).is_err());
+        assert_eq!(packet.get_token().len(), 65804);
+    }
+
+    #[test]
+    fn tcp_framing() {
+        for len in [0usize, 12, 13, 268, 269, 70000] {
+            let mut header = Header::new(MessageType::Con.into(), RequestMethod::Post as u8);
+            header.set_msg_id(7);
+            let mut options = BTreeMap::new();
+            options.insert(11, vec![b"a".to_vec()]);
+            let mut packet = CoAPFrame::new(header, options, vec![b'x'; len]);
+            packet.set_token(vec![0xA5; 20]).unwrap();
+
+            let bytes = packet.to_tcp_bytes();
+            let head_len = tcp_head_len(bytes[0]).unwrap();
+            assert_eq!(tcp_message_len(&bytes[..head_len]).unwrap(), bytes.len());
+            // type and message id are not sent, the receiver picks them
+            let received = CoAPFrame::from_tcp_bytes(&bytes, MessageType::Con, 7).unwrap();
+            assert_eq!(received, packet);
+        }
+        // an empty message is just the length and code byte: 7.02 Ping
+        let ping = CoAPFrame::new(Header::new(MessageType::Con.into(), 0xE2), BTreeMap::new(), vec![]);
+        assert_eq!(ping.to_tcp_bytes(), vec![0x00, 0xE2]);
+        assert_eq!(tcp_head_len(0x0F), None);
+        // lengths that disagree with the message
+        assert!(CoAPFrame::from_tcp_bytes(&[0x10, 0x01], MessageType::Non, 0).is_err());
+        assert!(CoAPFrame::from_tcp_bytes(&[0x00, 0x01, 0xFF], MessageType::Non, 0).is_err());
+    }
+
+    #[test]
+    fn malformed_datagrams_are_errors() {
+        let malformed: [&[u8]; 9] = [
+            // token length 8 without a token
+            &[0x48, 1, 0, 0],
+            // version 2
+            &[0x80, 1, 0, 0],
+            // reserved token length
+            &[0x4F, 1, 0, 0],
+            // option length past the end
+            &[0x40, 1, 0, 0, 0xB3, b'a'],
+            // extended option delta without its byte
+            &[0x40, 1, 0, 0, 0xD0],
+            // reserved option delta and length
+            &[0x40, 1, 0, 0, 0xF0],
+            &[0x40, 1, 0, 0, 0x0F],
+            // option numbers beyond 65535
+            &[0x40, 1, 0, 0, 0xE0, 0xFF, 0xFF, 0xE0, 0xFF, 0xFF],
+            // payload marker without a payload
+            &[0x40, 1, 0, 0, 0xFF],
+        ];
+        for bytes in malformed {
+            assert!(CoAPFrame::from_bytes(bytes.to_vec()).is_err(), "{:?}", bytes);
+        }
+        let frame = CoAPFrame::from_bytes(vec![0x40, 1, 0, 0, 0xB1, b'a', 0xFF, b'x']).unwrap();
+        assert_eq!(frame.get_options()[&11], vec![b"a".to_vec()]);
+        assert_eq!(frame.get_body(), b"x");
+    }
 }
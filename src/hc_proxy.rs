@@ -0,0 +1,376 @@
+use std::{collections::HashMap, io::{self, BufReader}, net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs}, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, thread, time::Duration};
+
+use url::Url;
+
+use crate::{
+    cache::DEFAULT_MAX_AGE,
+    common::{bytes_to_uint, uint_to_bytes},
+    frame::{ContentFormat, OptionEnum},
+    http::{etag_from_header, etag_to_header, HttpRequest, HttpResponse},
+    request::{CoapClient, RequestMethod},
+    response::{Response, ResponseCode},
+};
+
+/// path prefix in front of the target CoAP URI, RFC 8075 section 5.3
+pub const DEFAULT_PREFIX: &str = "/hc/";
+/// connections served at once by default, more are answered with 503
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+/// how long reading the request or writing the response may stall by default
+pub const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+/// entity tags remembered for the ETags they were mapped to, the oldest are
+/// forgotten all at once beyond this
+const MAX_OPAQUE_ETAGS: usize = 1024;
+
+/// HTTP-to-CoAP proxy, RFC 8075
+///
+/// an HTTP request for `/hc/coap://host/path` is sent to `coap://host/path`
+/// with this crate's client and the CoAP response translated back; one
+/// client, and so one UDP socket, is shared by every connection
+pub struct HcProxy {
+    listener: TcpListener,
+    prefix: String,
+    client: CoapClient,
+    max_connections: usize,
+    http_timeout: Duration,
+    etags: Arc<OpaqueEtags>,
+}
+
+impl HcProxy {
+
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<HcProxy> {
+        Ok(HcProxy {
+            listener: TcpListener::bind(addr)?,
+            prefix: String::from(DEFAULT_PREFIX),
+            client: CoapClient::new(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            http_timeout: DEFAULT_HTTP_TIMEOUT,
+            etags: Arc::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn set_prefix(&mut self, prefix: String) {
+        self.prefix = prefix;
+    }
+
+    /// timeout in milliseconds for the CoAP request
+    pub fn set_timeout(&mut self, timeout: u64) {
+        self.client.set_timeout(timeout);
+    }
+
+    /// connections served at once, each holds a thread
+    pub fn set_max_connections(&mut self, max: usize) {
+        self.max_connections = max;
+    }
+
+    /// how long reading the HTTP request or writing the response may stall
+    /// before the connection is dropped
+    pub fn set_http_timeout(&mut self, timeout: Duration) {
+        self.http_timeout = timeout;
+    }
+
+    /// accept connections until the listener fails, each one is served on
+    /// its own thread and closed after a single request; connections above
+    /// the limit get 503 without a thread
+    pub fn run(self) -> io::Result<()> {
+        let prefix = self.prefix;
+        let client = Arc::new(self.client);
+        let active = Arc::new(AtomicUsize::new(0));
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            stream.set_read_timeout(Some(self.http_timeout))?;
+            stream.set_write_timeout(Some(self.http_timeout))?;
+            if active.fetch_add(1, Ordering::SeqCst) >= self.max_connections {
+                active.fetch_sub(1, Ordering::SeqCst);
+                let mut busy = HttpResponse::new(503);
+                busy.set_header("Connection", String::from("close"));
+                let _ = busy.write_to(&mut &stream);
+                continue;
+            }
+            let slot = Slot(active.clone());
+            let prefix = prefix.clone();
+            let client = client.clone();
+            let etags = self.etags.clone();
+            thread::spawn(move || {
+                let _slot = slot;
+                serve(stream, &prefix, &client, &etags)
+            });
+        }
+        Ok(())
+    }
+
+    /// translate one HTTP request into CoAP and the answer back
+    pub fn translate(&self, request: &HttpRequest) -> HttpResponse {
+        translate(request, &self.prefix, &self.client, &self.etags)
+    }
+}
+
+/// entity tags from HTTP that are no hex string, by the CoAP ETag each was
+/// mapped to, so a response carries the tag the HTTP client knows
+#[derive(Default)]
+struct OpaqueEtags(Mutex<HashMap<Vec<u8>, String>>);
+
+impl OpaqueEtags {
+    /// the ETags for a list of entity tags
+    fn map(&self, tags: &str) -> Vec<Vec<u8>> {
+        tags.split(',').filter_map(|tag| {
+            let etag = etag_from_header(tag)?;
+            let tag = format!("\"{}\"", tag.trim().trim_start_matches("W/").trim_matches('"'));
+            if tag != etag_to_header(&etag) {
+                let mut tags = self.0.lock().unwrap();
+                if tags.len() >= MAX_OPAQUE_ETAGS {
+                    tags.clear();
+                }
+                tags.insert(etag.clone(), tag);
+            }
+            Some(etag)
+        }).collect()
+    }
+
+    /// the entity tag an ETag came from, its hex string otherwise
+    fn header(&self, etag: &[u8]) -> String {
+        self.0.lock().unwrap().get(etag).cloned().unwrap_or_else(|| etag_to_header(etag))
+    }
+}
+
+/// a connection being served, given back when its thread ends
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve(stream: TcpStream, prefix: &str, client: &CoapClient, etags: &OpaqueEtags) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut response = match HttpRequest::read_from(&mut reader) {
+        Ok(request) => translate(&request, prefix, client, etags),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => HttpResponse::new(400),
+        Err(e) => return Err(e),
+    };
+    response.set_header("Connection", String::from("close"));
+    response.write_to(&mut &stream)
+}
+
+fn translate(request: &HttpRequest, prefix: &str, client: &CoapClient, etags: &OpaqueEtags) -> HttpResponse {
+    let Some(target) = request.target.strip_prefix(prefix) else {
+        return HttpResponse::new(404);
+    };
+    let Ok(url) = Url::parse(target) else {
+        return HttpResponse::new(400);
+    };
+    if url.scheme() != "coap" {
+        return HttpResponse::new(501);
+    }
+    let method = match request.method.as_str() {
+        "GET" => RequestMethod::Get,
+        "POST" => RequestMethod::Post,
+        "PUT" => RequestMethod::Put,
+        "DELETE" => RequestMethod::Deleted,
+        _ => return HttpResponse::new(501),
+    };
+
+    let Ok(mut coap) = client.new_request(method, url.as_str()) else {
+        return HttpResponse::new(400);
+    };
+    if let Some(content_type) = request.header("Content-Type") {
+        let Some(format) = ContentFormat::from_media_type(content_type) else {
+            return HttpResponse::new(415);
+        };
+        coap.set_option(OptionEnum::ContentFormat, uint_to_bytes(u16::from(format) as u32));
+    }
+    // the first acceptable type CoAP has a Content-Format for
+    let accept = request.header("Accept")
+        .and_then(|accept| accept.split(',').find_map(|t| ContentFormat::from_media_type(t.split(";q=").next().unwrap_or(t))));
+    if let Some(format) = accept {
+        coap.set_option(OptionEnum::Accept, uint_to_bytes(u16::from(format) as u32));
+    }
+    let validating = match request.header("If-None-Match") {
+        Some("*") => {
+            coap.set_option(OptionEnum::IfNoneMatch, vec![]);
+            false
+        }
+        Some(tags) => {
+            // validators in If-None-Match become ETag options, RFC 8075 section 6.3
+            for etag in etags.map(tags) {
+                coap.add_option(OptionEnum::ETag, etag);
+            }
+            method == RequestMethod::Get
+        }
+        None => false,
+    };
+    match request.header("If-Match") {
+        Some("*") => coap.set_option(OptionEnum::IfMatch, vec![]),
+        Some(tags) => {
+            for etag in etags.map(tags) {
+                coap.add_option(OptionEnum::IfMatch, etag);
+            }
+        }
+        None => {}
+    }
+    coap.set_body(request.body.clone());
+
+    match client.send_request(coap) {
+        Ok(Some(response)) => http_response(&response, validating, etags),
+        Ok(None) => HttpResponse::new(504),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => HttpResponse::new(504),
+        Err(_) => HttpResponse::new(502),
+    }
+}
+
+fn http_response(response: &Response, validating: bool, etags: &OpaqueEtags) -> HttpResponse {
+    let code = response.get_response_code();
+    let mut http = HttpResponse::new(http_status(code, !response.get_body().is_empty(), validating));
+    let option = |number| response.get_option(number).and_then(|v| v.first());
+    if let Some(format) = option(OptionEnum::ContentFormat) {
+        let media_type = ContentFormat::try_from(bytes_to_uint(format) as u16)
+            .map_or("application/octet-stream", |format| format.media_type());
+        http.set_header("Content-Type", String::from(media_type));
+    }
+    if let Some(etag) = option(OptionEnum::ETag) {
+        http.set_header("ETag", etags.header(etag));
+    }
+    if code.is_success() {
+        let max_age = option(OptionEnum::MaxAge).map_or(DEFAULT_MAX_AGE, |v| bytes_to_uint(v));
+        http.set_header("Cache-Control", format!("max-age={}", max_age));
+    }
+    http.body = response.get_body().clone();
+    http
+}
+
+/// status code mapping, RFC 8075 section 7
+pub fn http_status(code: ResponseCode, payload: bool, validating: bool) -> u16 {
+    match code {
+        ResponseCode::Created => 201,
+        ResponseCode::Deleted => 200,
+        ResponseCode::Valid if validating => 304,
+        ResponseCode::Valid => 200,
+        ResponseCode::Changed if payload => 200,
+        ResponseCode::Changed => 204,
+        ResponseCode::Content => 200,
+        ResponseCode::BadRequest | ResponseCode::BadOption | ResponseCode::RequestEntityIncomplete => 400,
+        ResponseCode::Unauthorized | ResponseCode::Forbidden => 403,
+        ResponseCode::NotFound => 404,
+        ResponseCode::MethodNotAllowed => 405,
+        ResponseCode::NotAcceptable => 406,
+        ResponseCode::PreconditionFailed => 412,
+        ResponseCode::RequestEntityTooLarge => 413,
+        ResponseCode::UnsupportedContentFormat => 415,
+        ResponseCode::UnprocessableEntity => 422,
+        ResponseCode::TooManyRequests => 429,
+        ResponseCode::NotImplemented => 501,
+        ResponseCode::BadGateway | ResponseCode::ProxyingNotSupported => 502,
+        ResponseCode::ServiceUnavailable => 503,
+        ResponseCode::GatewayTimeout => 504,
+        _ => 500,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::{BufReader, Read}, net::TcpStream, thread, time::Duration};
+
+    use crate::{
+        frame::OptionEnum,
+        hc_proxy::HcProxy,
+        http::{HttpRequest, HttpResponse},
+        request::{Request, RequestMethod},
+        response::{Response, ResponseCode},
+        server::CoapServer,
+    };
+
+    #[test]
+    fn http_to_coap() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        let coap_port = server.local_addr().unwrap().port();
+        thread::spawn(move || server.run(|req: &Request| match req.get_method() {
+            RequestMethod::Get if req.get_option(OptionEnum::ETag).is_some() => {
+                let mut valid = Response::new(ResponseCode::Valid);
+                valid.set_option(OptionEnum::ETag, req.get_option(OptionEnum::ETag).unwrap()[0].clone());
+                valid
+            }
+            RequestMethod::Get => {
+                let mut response = Response::new(ResponseCode::Content);
+                response.set_option(OptionEnum::ContentFormat, vec![50]);
+                response.set_option(OptionEnum::ETag, vec![1, 2]);
+                response.set_option(OptionEnum::MaxAge, vec![30]);
+                response.set_body(Vec::from("{\"t\":21}"));
+                response
+            }
+            RequestMethod::Put if req.get_option(OptionEnum::ContentFormat).is_some() => Response::new(ResponseCode::Changed),
+            _ => Response::new(ResponseCode::NotFound),
+        }));
+
+        let proxy = HcProxy::bind("127.0.0.1:0").unwrap();
+        let http_port = proxy.local_addr().unwrap().port();
+        thread::spawn(move || proxy.run());
+        let send = |request: HttpRequest| {
+            let stream = TcpStream::connect(("127.0.0.1", http_port)).unwrap();
+            request.write_to(&mut &stream).unwrap();
+            HttpResponse::read_from(&mut BufReader::new(stream)).unwrap()
+        };
+        let target = format!("/hc/coap://127.0.0.1:{}/temp", coap_port);
+
+        let response = send(HttpRequest::new("GET", &target));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+        assert_eq!(response.header("ETag"), Some("\"0102\""));
+        assert_eq!(response.header("Cache-Control"), Some("max-age=30"));
+        assert_eq!(response.body, b"{\"t\":21}");
+
+        let mut conditional = HttpRequest::new("GET", &target);
+        conditional.headers.push((String::from("If-None-Match"), String::from("\"0102\"")));
+        assert_eq!(send(conditional).status, 304);
+
+        // entity tags of other origins are mapped to ETags and back
+        for tag in ["\"5e1f-abc\"", "\"da39a3ee5e6b4b0d3255bfef95601890afd80709\""] {
+            let mut conditional = HttpRequest::new("GET", &target);
+            conditional.headers.push((String::from("If-None-Match"), String::from(tag)));
+            let response = send(conditional);
+            assert_eq!(response.status, 304);
+            assert_eq!(response.header("ETag"), Some(tag));
+        }
+
+        let mut put = HttpRequest::new("PUT", &target);
+        put.headers.push((String::from("Content-Type"), String::from("text/plain; charset=utf-8")));
+        put.body = Vec::from("22");
+        assert_eq!(send(put).status, 204);
+
+        let mut unsupported = HttpRequest::new("PUT", &target);
+        unsupported.headers.push((String::from("Content-Type"), String::from("image/png")));
+        assert_eq!(send(unsupported).status, 415);
+
+        assert_eq!(send(HttpRequest::new("DELETE", &target)).status, 404);
+        assert_eq!(send(HttpRequest::new("PATCH", &target)).status, 501);
+        assert_eq!(send(HttpRequest::new("GET", "/hc/http://127.0.0.1/")).status, 501);
+        assert_eq!(send(HttpRequest::new("GET", "/other")).status, 404);
+    }
+
+    #[test]
+    fn connections_are_bounded() {
+        let mut proxy = HcProxy::bind("127.0.0.1:0").unwrap();
+        proxy.set_max_connections(1);
+        proxy.set_http_timeout(Duration::from_millis(200));
+        let addr = proxy.local_addr().unwrap();
+        thread::spawn(move || proxy.run());
+
+        // a client that never sends its request holds the only slot
+        let quiet = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let busy = TcpStream::connect(addr).unwrap();
+        HttpRequest::new("GET", "/other").write_to(&mut &busy).unwrap();
+        assert_eq!(HttpResponse::read_from(&mut BufReader::new(busy)).unwrap().status, 503);
+
+        // until it is dropped for stalling
+        let mut rest = vec![];
+        assert_eq!((&quiet).read_to_end(&mut rest).unwrap(), 0);
+        thread::sleep(Duration::from_millis(50));
+        let next = TcpStream::connect(addr).unwrap();
+        HttpRequest::new("GET", "/other").write_to(&mut &next).unwrap();
+        assert_eq!(HttpResponse::read_from(&mut BufReader::new(next)).unwrap().status, 404);
+    }
+}
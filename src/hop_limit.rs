@@ -0,0 +1,68 @@
+use crate::{frame::OptionEnum, request::Request, response::{Response, ResponseCode}};
+
+/// initial Hop-Limit a proxy inserts when the request has none, RFC 8768 section 3
+pub const DEFAULT_HOP_LIMIT: u8 = 16;
+
+/// Hop-Limit for a request a proxy is about to forward
+///
+/// the received value is decremented, or DEFAULT_HOP_LIMIT used when absent;
+/// once it would reach 0 the request must not be forwarded and the returned
+/// 5.08 Hop Limit Reached, naming `proxy` in the payload, is sent back instead;
+/// a value that is not one byte from 1 to 255 gets 4.00 Bad Request
+pub fn next_hop_limit(request: &Request, proxy: &str) -> Result<u8, Response> {
+    let Some(value) = request.get_option(OptionEnum::HopLimit).and_then(|v| v.first()) else {
+        return Ok(DEFAULT_HOP_LIMIT);
+    };
+    match value[..] {
+        [limit] if limit > 1 => Ok(limit - 1),
+        [1] => {
+            let mut response = Response::new(ResponseCode::HopLimitReached);
+            response.set_body(Vec::from(proxy));
+            Err(response)
+        }
+        _ => Err(Response::new(ResponseCode::BadRequest)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        frame::{CoAPFrame, Header, MessageType, OptionEnum},
+        hop_limit::{next_hop_limit, DEFAULT_HOP_LIMIT},
+        request::{Request, RequestMethod},
+        response::ResponseCode,
+    };
+
+    fn request(hop_limit: Option<&[u8]>) -> Request {
+        let mut options = BTreeMap::new();
+        if let Some(limit) = hop_limit {
+            options.insert(u16::from(OptionEnum::HopLimit), vec![limit.to_vec()]);
+        }
+        let header = Header::new(MessageType::Con.into(), RequestMethod::Get as u8);
+        let frame = CoAPFrame::new(header, options, vec![]);
+        Request::from_frame(&frame, "127.0.0.1:5683".parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn decrement_on_forward() {
+        assert_eq!(next_hop_limit(&request(None), "p").unwrap(), DEFAULT_HOP_LIMIT);
+        assert_eq!(next_hop_limit(&request(Some(&[5])), "p").unwrap(), 4);
+        assert_eq!(next_hop_limit(&request(Some(&[255])), "p").unwrap(), 254);
+        let reached = next_hop_limit(&request(Some(&[1])), "[::1]:5683").unwrap_err();
+        assert_eq!(reached.get_response_code(), ResponseCode::HopLimitReached);
+        assert_eq!(reached.get_body(), b"[::1]:5683");
+    }
+
+    #[test]
+    fn invalid_values() {
+        // 0 encodes as the empty value, 256 as two bytes; neither may wrap around
+        for value in [&[][..], &[0], &[1, 0], &[0, 5]] {
+            let refused = next_hop_limit(&request(Some(value)), "p").unwrap_err();
+            assert_eq!(refused.get_response_code(), ResponseCode::BadRequest);
+            assert_eq!(request(Some(value)).get_hop_limit(), None);
+        }
+        assert_eq!(request(Some(&[7])).get_hop_limit(), Some(7));
+    }
+}
@@ -0,0 +1,352 @@
+use std::{hash::{DefaultHasher, Hash, Hasher}, io::{self, BufRead, BufReader, Read, Write}, net::{TcpStream, ToSocketAddrs}, time::Duration};
+
+use url::Url;
+
+/// largest body accepted, so a peer cannot make us buffer without end
+const MAX_HTTP_LEN: usize = 16 * 1024 * 1024;
+/// largest head accepted, start line and headers together
+pub const MAX_HEAD_LEN: usize = 8 * 1024;
+/// most header lines accepted in one head
+pub const MAX_HEADERS: usize = 100;
+
+/// minimal HTTP/1.1 request, enough for the cross-protocol proxies
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    /// request target as sent, origin-form or absolute-form
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+
+    pub fn new(method: &str, target: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.to_owned(),
+            target: target.to_owned(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    /// first header named `name`, case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<HttpRequest> {
+        let (start, headers) = read_head(reader)?;
+        let mut parts = start.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(invalid("malformed request line"));
+        };
+        let body = read_body(reader, &headers, false)?;
+        Ok(HttpRequest { method: method.to_owned(), target: target.to_owned(), headers, body })
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, self.target);
+        write_headers(&mut head, &self.headers, self.body.len());
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+impl HttpResponse {
+
+    pub fn new(status: u16) -> HttpResponse {
+        HttpResponse { status, headers: vec![], body: vec![] }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn set_header(&mut self, name: &str, value: String) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_owned(), value));
+    }
+
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<HttpResponse> {
+        let (start, headers) = read_head(reader)?;
+        let status = start.split_whitespace().nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| invalid("malformed status line"))?;
+        // 1xx, 204 and 304 never have a body
+        let body = match status {
+            100..=199 | 204 | 304 => vec![],
+            _ => read_body(reader, &headers, true)?,
+        };
+        Ok(HttpResponse { status, headers, body })
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        write_headers(&mut head, &self.headers, self.body.len());
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+/// sends HTTP requests for the CoAP-to-HTTP proxy, replaceable to add TLS
+/// or connection reuse
+pub trait HttpConnector {
+    /// send `request` to the origin of `url`, the request target is already
+    /// set to the path and query of `url`
+    fn send(&self, url: &Url, request: &HttpRequest, timeout: Duration) -> io::Result<HttpResponse>;
+}
+
+/// plain `http` over a new TCP connection per request, `https` is refused
+/// with `ErrorKind::Unsupported` as this crate has no TLS
+#[derive(Debug, Default)]
+pub struct TcpConnector;
+
+impl HttpConnector for TcpConnector {
+    fn send(&self, url: &Url, request: &HttpRequest, timeout: Duration) -> io::Result<HttpResponse> {
+        if url.scheme() != "http" {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "only plain http is supported"));
+        }
+        let host = url.host_str().ok_or_else(|| invalid("url without host"))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addr = (host, port).to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host did not resolve"))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let mut request = request.clone();
+        let authority = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        };
+        request.headers.retain(|(n, _)| !n.eq_ignore_ascii_case("Host") && !n.eq_ignore_ascii_case("Connection"));
+        request.headers.push((String::from("Host"), authority));
+        request.headers.push((String::from("Connection"), String::from("close")));
+        request.write_to(&mut &stream)?;
+        HttpResponse::read_from(&mut BufReader::new(stream))
+    }
+}
+
+/// ETags travel as quoted hex strings
+pub fn etag_to_header(etag: &[u8]) -> String {
+    format!("\"{}\"", etag.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+/// the CoAP ETag for an entity tag: hex strings are decoded, other tags are
+/// kept as is when they fit the 8 bytes of an ETag option (RFC 7252
+/// section 5.10.6) and hashed to 8 bytes otherwise
+pub fn etag_from_header(value: &str) -> Option<Vec<u8>> {
+    let tag = value.trim().trim_start_matches("W/").trim_matches('"');
+    if tag.is_empty() {
+        return None;
+    }
+    let hex = tag.as_bytes();
+    if hex.len().is_multiple_of(2) && hex.len() <= 16 && hex.iter().all(u8::is_ascii_hexdigit) {
+        return hex.chunks(2)
+            .map(|pair| std::str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+            .collect();
+    }
+    if hex.len() <= 8 {
+        return Some(hex.to_vec());
+    }
+    let mut hasher = DefaultHasher::new();
+    tag.hash(&mut hasher);
+    Some(hasher.finish().to_be_bytes().to_vec())
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// one line of at most `*budget` bytes, which is reduced by what was read
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> io::Result<String> {
+    let mut line = String::new();
+    let len = reader.by_ref().take(*budget as u64).read_line(&mut line)?;
+    if len == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+    }
+    if !line.ends_with('\n') {
+        return Err(invalid("line too long"));
+    }
+    *budget -= len;
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<(String, Vec<(String, String)>)> {
+    let mut budget = MAX_HEAD_LEN;
+    let start = read_line(reader, &mut budget)?;
+    let mut headers = vec![];
+    loop {
+        let line = read_line(reader, &mut budget)?;
+        if line.is_empty() {
+            return Ok((start, headers));
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(invalid("too many headers"));
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+}
+
+/// body framed by Content-Length or chunked encoding, a response without
+/// either runs until the connection closes
+fn read_body<R: BufRead>(reader: &mut R, headers: &[(String, String)], until_close: bool) -> io::Result<Vec<u8>> {
+    let mut body = vec![];
+    let chunked = find_header(headers, "Transfer-Encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        loop {
+            // a chunk size line may be as long as a head, trailers together too
+            let mut budget = MAX_HEAD_LEN;
+            let line = read_line(reader, &mut budget)?;
+            let size = usize::from_str_radix(line.split(';').next().unwrap_or("").trim(), 16)
+                .map_err(|_| invalid("malformed chunk size"))?;
+            if size == 0 {
+                // trailers end with an empty line
+                while !read_line(reader, &mut budget)?.is_empty() {}
+                return Ok(body);
+            }
+            if size > MAX_HTTP_LEN - body.len() {
+                return Err(invalid("body too large"));
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            budget = MAX_HEAD_LEN;
+            read_line(reader, &mut budget)?;
+        }
+    }
+    match find_header(headers, "Content-Length") {
+        Some(len) => {
+            let len: usize = len.parse().map_err(|_| invalid("malformed Content-Length"))?;
+            if len > MAX_HTTP_LEN {
+                return Err(invalid("body too large"));
+            }
+            body.resize(len, 0);
+            reader.read_exact(&mut body)?;
+        }
+        None if until_close => {
+            reader.take(MAX_HTTP_LEN as u64).read_to_end(&mut body)?;
+        }
+        None => {}
+    }
+    Ok(body)
+}
+
+fn write_headers(head: &mut String, headers: &[(String, String)], body_len: usize) {
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding") {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", body_len));
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::BufReader;
+
+    use crate::http::{etag_from_header, etag_to_header, HttpRequest, HttpResponse, MAX_HEADERS, MAX_HEAD_LEN};
+
+    #[test]
+    fn request_round_trip() {
+        let mut request = HttpRequest::new("PUT", "/hc/coap://example.com/a");
+        request.headers.push((String::from("Content-Type"), String::from("application/json")));
+        request.body = Vec::from("{}");
+        let mut bytes = vec![];
+        request.write_to(&mut bytes).unwrap();
+
+        let parsed = HttpRequest::read_from(&mut BufReader::new(&bytes[..])).unwrap();
+        assert_eq!(parsed.method, "PUT");
+        assert_eq!(parsed.target, "/hc/coap://example.com/a");
+        assert_eq!(parsed.header("content-type"), Some("application/json"));
+        assert_eq!(parsed.body, b"{}");
+    }
+
+    #[test]
+    fn chunked_response() {
+        let bytes = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;x=y\r\nde\r\n0\r\n\r\n";
+        let response = HttpResponse::read_from(&mut BufReader::new(&bytes[..])).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"abcde");
+    }
+
+    #[test]
+    fn oversized_chunks_are_rejected() {
+        let bytes = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\nffffffffffffffff\r\n";
+        let error = HttpResponse::read_from(&mut BufReader::new(&bytes[..])).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn heads_are_bounded() {
+        let mut many = String::from("GET / HTTP/1.1\r\n");
+        for i in 0..=MAX_HEADERS {
+            many.push_str(&format!("X-{}: a\r\n", i));
+        }
+        many.push_str("\r\n");
+        let error = HttpRequest::read_from(&mut BufReader::new(many.as_bytes())).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let long = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(MAX_HEAD_LEN));
+        let error = HttpRequest::read_from(&mut BufReader::new(long.as_bytes())).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn etag_header_round_trip() {
+        assert_eq!(etag_to_header(&[0x0a, 0xff]), "\"0aff\"");
+        assert_eq!(etag_from_header(" W/\"0aff\""), Some(vec![0x0a, 0xff]));
+        assert_eq!(etag_from_header("\"\""), None);
+        // a client may send anything, non-ASCII included
+        assert_eq!(etag_from_header("\"xyz\""), Some(b"xyz".to_vec()));
+        assert_eq!(etag_from_header("\"aéb\""), Some("aéb".as_bytes().to_vec()));
+        assert_eq!(etag_from_header("\"+a\""), Some(b"+a".to_vec()));
+        let sha = etag_from_header("\"da39a3ee5e6b4b0d3255bfef95601890afd80709\"").unwrap();
+        assert_eq!(sha.len(), 8);
+        assert_eq!(etag_from_header("W/\"da39a3ee5e6b4b0d3255bfef95601890afd80709\""), Some(sha));
+        assert_ne!(etag_from_header("\"5e1f-abc\""), etag_from_header("\"5e1f-abd\""));
+    }
+}
@@ -0,0 +1,31 @@
+#[cfg(feature = "tokio")]
+pub mod async_client;
+pub mod block;
+pub mod cache;
+pub mod clock;
+pub mod cocoa;
+pub mod common;
+pub mod conditional;
+pub mod dedup;
+#[cfg(feature = "dtls")]
+pub mod dtls;
+pub mod echo;
+pub mod endpoint;
+pub mod error;
+pub mod frame;
+pub mod hc_proxy;
+pub mod hop_limit;
+pub mod http;
+pub mod message_id;
+pub mod middleware;
+pub mod no_response;
+pub mod proxy;
+pub mod q_block;
+pub mod request;
+pub mod response;
+pub mod server;
+pub mod simulator;
+pub mod token;
+mod transfer;
+pub mod transmission;
+pub mod transport;
@@ -0,0 +1,130 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, net::SocketAddr, time::{Duration, Instant}};
+
+use rand::Rng;
+
+use crate::transmission::EXCHANGE_LIFETIME;
+
+struct PeerIds {
+    next: u16,
+    /// ids handed out, oldest first, with the time they may be reused
+    expiry: VecDeque<(u16, Instant)>,
+    in_use: HashSet<u16>,
+}
+
+impl PeerIds {
+    fn new(start: u16) -> Self {
+        PeerIds { next: start, expiry: VecDeque::new(), in_use: HashSet::new() }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((id, free_at)) = self.expiry.front() {
+            if *free_at > now {
+                break;
+            }
+            self.in_use.remove(id);
+            self.expiry.pop_front();
+        }
+    }
+}
+
+/// message id allocation, RFC 7252 section 4.4
+///
+/// every peer gets its own counter starting at a random value, an id is
+/// not handed out again to the same peer within EXCHANGE_LIFETIME
+pub struct MessageIdAllocator {
+    peers: HashMap<SocketAddr, PeerIds>,
+    lifetime: Duration,
+}
+
+impl Default for MessageIdAllocator {
+    fn default() -> Self {
+        MessageIdAllocator::new()
+    }
+}
+
+impl MessageIdAllocator {
+
+    pub fn new() -> MessageIdAllocator {
+        MessageIdAllocator::with_lifetime(EXCHANGE_LIFETIME)
+    }
+
+    pub fn with_lifetime(lifetime: Duration) -> MessageIdAllocator {
+        MessageIdAllocator {
+            peers: HashMap::new(),
+            lifetime,
+        }
+    }
+
+    /// next message id for `peer`, None when all 65536 ids are still in use
+    pub fn next(&mut self, peer: SocketAddr, now: Instant) -> Option<u16> {
+        self.next_with(peer, now, &mut rand::thread_rng())
+    }
+
+    /// `next` drawing the random start of a new peer's counter from `rng`
+    pub fn next_with<R: Rng + ?Sized>(&mut self, peer: SocketAddr, now: Instant, rng: &mut R) -> Option<u16> {
+        let ids = self.peers
+            .entry(peer)
+            .or_insert_with(|| PeerIds::new(rng.gen()));
+        ids.expire(now);
+        if ids.in_use.len() > u16::MAX as usize {
+            return None;
+        }
+        // ids are handed out in order, so the counter only collides with a
+        // live id after wrapping around, skip ahead past those
+        while ids.in_use.contains(&ids.next) {
+            ids.next = ids.next.wrapping_add(1);
+        }
+        let id = ids.next;
+        ids.next = ids.next.wrapping_add(1);
+        ids.in_use.insert(id);
+        ids.expiry.push_back((id, now + self.lifetime));
+        Some(id)
+    }
+
+    /// forget peers without any id in use
+    pub fn purge(&mut self, now: Instant) {
+        self.peers.retain(|_, ids| {
+            ids.expire(now);
+            !ids.in_use.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, time::{Duration, Instant}};
+
+    use crate::message_id::MessageIdAllocator;
+
+    #[test]
+    fn sequential_per_peer() {
+        let a: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let b: SocketAddr = "127.0.0.2:5683".parse().unwrap();
+        let mut ids = MessageIdAllocator::new();
+        let now = Instant::now();
+
+        let first = ids.next(a, now).unwrap();
+        assert_eq!(ids.next(a, now), Some(first.wrapping_add(1)));
+        assert_eq!(ids.next(a, now), Some(first.wrapping_add(2)));
+        let other = ids.next(b, now).unwrap();
+        assert_eq!(ids.next(b, now), Some(other.wrapping_add(1)));
+    }
+
+    #[test]
+    fn ids_are_not_reused_within_lifetime() {
+        let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let mut ids = MessageIdAllocator::with_lifetime(Duration::from_secs(10));
+        let now = Instant::now();
+
+        let first = ids.next(peer, now).unwrap();
+        for _ in 1..=u16::MAX {
+            assert!(ids.next(peer, now).is_some());
+        }
+        assert_eq!(ids.next(peer, now), None);
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(ids.next(peer, later), Some(first));
+        ids.purge(later + Duration::from_secs(10));
+        assert!(ids.peers.is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+use std::{any::{Any, TypeId}, collections::HashMap, fmt, net::SocketAddr, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    common::uint_to_bytes,
+    frame::OptionEnum,
+    request::Request,
+    response::{Response, ResponseCode},
+};
+
+/// values middleware attaches to one request for later layers and the
+/// handler, at most one of each type
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    /// attach `value`, replacing an earlier one of the same type
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| (**value).downcast_ref())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> bool {
+        self.values.remove(&TypeId::of::<T>()).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.values.len()).finish()
+    }
+}
+
+/// layer around the request handler of a server
+///
+/// `pre_dispatch` sees every request message as it arrives, including
+/// each block of a Block1/Q-Block1 body, requests about to be challenged
+/// for Echo and Block2 continuations served from a stored response, but
+/// not retransmissions answered from the deduplication cache; `handle`
+/// sees each complete request once, after those steps
+pub trait Middleware: Send {
+    /// look at a request message before anything else happens to it, a
+    /// response returned answers it at once and nothing else runs
+    fn pre_dispatch(&self, _request: &Request) -> Option<Response> {
+        None
+    }
+
+    /// answer `request`, normally by passing it on with `next.run` and
+    /// looking at or changing the response it returns; answering without
+    /// calling it skips the rest of the chain and the handler
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request, Next<'_>) -> Response + Send,
+{
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+/// the layers after the current one, ending in the handler
+pub struct Next<'a> {
+    chain: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Fn(&Request) -> Response,
+}
+
+impl<'a> Next<'a> {
+
+    pub(crate) fn new(chain: &'a [Box<dyn Middleware>], handler: &'a dyn Fn(&Request) -> Response) -> Next<'a> {
+        Next { chain, handler }
+    }
+
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.chain.split_first() {
+            Some((first, rest)) => first.handle(request, Next { chain: rest, handler: self.handler }),
+            None => (self.handler)(request),
+        }
+    }
+}
+
+/// at most `limit` request messages per peer in each `window`, the rest
+/// are answered 4.29 Too Many Requests with Max-Age set to when the window
+/// ends (RFC 8516); every block of a block-wise transfer counts
+pub struct RateLimit {
+    limit: u32,
+    window: Duration,
+    clock: Box<dyn Clock>,
+    /// start of the current window and requests seen in it
+    peers: Mutex<HashMap<SocketAddr, (Instant, u32)>>,
+}
+
+impl RateLimit {
+
+    pub fn new(limit: u32, window: Duration) -> RateLimit {
+        RateLimit { limit, window, clock: Box::new(SystemClock), peers: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+}
+
+impl Middleware for RateLimit {
+    fn pre_dispatch(&self, request: &Request) -> Option<Response> {
+        let peer = request.get_peer()?;
+        let now = self.clock.now();
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|_, (start, _)| *start + self.window > now);
+        let (start, count) = peers.entry(peer).or_insert((now, 0));
+        if *count >= self.limit {
+            let left = *start + self.window - now;
+            let seconds = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+            let mut response = Response::new(ResponseCode::TooManyRequests);
+            response.set_option(OptionEnum::MaxAge, uint_to_bytes(seconds as u32));
+            return Some(response);
+        }
+        *count += 1;
+        None
+    }
+
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        next.run(request)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, time::{Duration, Instant}};
+
+    use crate::{
+        clock::ManualClock,
+        common::bytes_to_uint,
+        frame::{CoAPFrame, Header, MessageType, OptionEnum},
+        middleware::{Extensions, Middleware, RateLimit},
+        request::{Request, RequestMethod},
+        response::{Response, ResponseCode},
+    };
+
+    fn request_from(peer: &str) -> Request {
+        let header = Header::new(MessageType::Con.into(), RequestMethod::Get as u8);
+        Request::from_frame(&CoAPFrame::new(header, BTreeMap::new(), vec![]), peer.parse().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn extensions_by_type() {
+        let mut extensions = Extensions::new();
+        extensions.insert(String::from("alice"));
+        extensions.insert(7u32);
+        extensions.insert(String::from("bob"));
+        assert_eq!(extensions.get::<String>().unwrap(), "bob");
+        assert_eq!(extensions.get::<u32>(), Some(&7));
+        assert_eq!(extensions.get::<u8>(), None);
+        assert!(extensions.remove::<u32>());
+        assert!(!extensions.remove::<u32>());
+        assert!(!extensions.is_empty());
+    }
+
+    #[test]
+    fn rate_limit_per_peer() {
+        let clock = ManualClock::new(Instant::now());
+        let mut limit = RateLimit::new(2, Duration::from_secs(10));
+        limit.set_clock(clock.clone());
+        let send = |peer| limit.pre_dispatch(&request_from(peer)).unwrap_or_else(|| Response::new(ResponseCode::Content));
+
+        assert_eq!(send("10.0.0.1:5683").get_response_code(), ResponseCode::Content);
+        assert_eq!(send("10.0.0.1:5683").get_response_code(), ResponseCode::Content);
+        clock.advance(Duration::from_millis(2500));
+        let refused = send("10.0.0.1:5683");
+        assert_eq!(refused.get_response_code(), ResponseCode::TooManyRequests);
+        assert_eq!(bytes_to_uint(&refused.get_option(OptionEnum::MaxAge).unwrap()[0]), 8);
+        // other peers have their own budget
+        assert_eq!(send("10.0.0.2:5683").get_response_code(), ResponseCode::Content);
+
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(send("10.0.0.1:5683").get_response_code(), ResponseCode::Content);
+    }
+}
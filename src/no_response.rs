@@ -0,0 +1,73 @@
+use std::{ops::BitOr, time::Duration};
+
+use crate::{common::{bytes_to_uint, uint_to_bytes}, response::ResponseCode};
+
+/// how long a client waits for a response No-Response may have suppressed,
+/// counted from the empty ACK of a CON or from sending a NON
+pub const NO_RESPONSE_WAIT: Duration = Duration::from_secs(5);
+
+/// value of the No-Response option, RFC 7967
+///
+/// each bit set tells the server the client is not interested in
+/// responses of that class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoResponse(u8);
+
+impl NoResponse {
+    /// interested in every response, same as omitting the option
+    pub const NONE: NoResponse = NoResponse(0);
+    /// suppress 2.xx
+    pub const SUCCESS: NoResponse = NoResponse(0x02);
+    /// suppress 4.xx
+    pub const CLIENT_ERROR: NoResponse = NoResponse(0x08);
+    /// suppress 5.xx
+    pub const SERVER_ERROR: NoResponse = NoResponse(0x10);
+    /// fire and forget
+    pub const ALL: NoResponse = NoResponse(0x1A);
+
+    pub fn from_value(value: &[u8]) -> NoResponse {
+        NoResponse(bytes_to_uint(value) as u8)
+    }
+
+    pub fn to_value(&self) -> Vec<u8> {
+        uint_to_bytes(self.0 as u32)
+    }
+
+    pub fn suppresses(&self, code: ResponseCode) -> bool {
+        match code.class() {
+            2 => self.0 & Self::SUCCESS.0 != 0,
+            4 => self.0 & Self::CLIENT_ERROR.0 != 0,
+            5 => self.0 & Self::SERVER_ERROR.0 != 0,
+            _ => false,
+        }
+    }
+
+    pub fn suppresses_all(&self) -> bool {
+        self.0 & Self::ALL.0 == Self::ALL.0
+    }
+}
+
+impl BitOr for NoResponse {
+    type Output = NoResponse;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        NoResponse(self.0 | rhs.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{no_response::NoResponse, response::ResponseCode};
+
+    #[test]
+    fn suppress_by_class() {
+        let nr = NoResponse::SUCCESS | NoResponse::SERVER_ERROR;
+        assert!(nr.suppresses(ResponseCode::Changed));
+        assert!(!nr.suppresses(ResponseCode::NotFound));
+        assert!(nr.suppresses(ResponseCode::BadGateway));
+        assert!(!nr.suppresses_all());
+        assert!((nr | NoResponse::CLIENT_ERROR).suppresses_all());
+        assert_eq!(NoResponse::from_value(&NoResponse::ALL.to_value()), NoResponse::ALL);
+        assert!(NoResponse::NONE.to_value().is_empty());
+    }
+}
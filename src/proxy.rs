@@ -0,0 +1,437 @@
+use std::{io, sync::Mutex, time::Duration};
+
+use url::{Position, Url};
+
+use crate::{
+    cache::{self, CacheKey, Lookup, ResponseCache},
+    clock::{Clock, SystemClock},
+    common::{bytes_to_uint, uint_to_bytes},
+    frame::{ContentFormat, OptionEnum},
+    hop_limit,
+    http::{etag_from_header, etag_to_header, HttpConnector, HttpRequest, HttpResponse, TcpConnector},
+    request::{CoapClient, Request, RequestMethod},
+    response::{Response, ResponseCode},
+};
+
+/// options the proxy consumes or rebuilds instead of copying to the forwarded request
+const NOT_FORWARDED: [OptionEnum; 14] = [
+    OptionEnum::ProxyUri,
+    OptionEnum::ProxyScheme,
+    OptionEnum::UriHost,
+    OptionEnum::UriPort,
+    OptionEnum::UriPath,
+    OptionEnum::UriQuery,
+    OptionEnum::Block1,
+    OptionEnum::Block2,
+    OptionEnum::QBlock1,
+    OptionEnum::QBlock2,
+    OptionEnum::Echo,
+    OptionEnum::RequestTag,
+    OptionEnum::NoResponse,
+    OptionEnum::HopLimit,
+];
+
+/// forward proxy, RFC 7252 section 5.7.2
+///
+/// requests carrying Proxy-Uri or Proxy-Scheme are sent on with a
+/// `CoapClient`, or as HTTP requests for `http`/`https` targets (RFC 7252
+/// section 10.1); fresh responses to GET are served from a cache and stale
+/// ones revalidated with their ETag; CoAP targets are all reached through
+/// one client and its socket
+pub struct ForwardProxy {
+    name: String,
+    timeout: u64,
+    client: CoapClient,
+    cache: Mutex<ResponseCache>,
+    clock: Box<dyn Clock>,
+    http: Box<dyn HttpConnector + Send + Sync>,
+}
+
+impl Default for ForwardProxy {
+    fn default() -> Self {
+        ForwardProxy::new()
+    }
+}
+
+impl ForwardProxy {
+
+    pub fn new() -> ForwardProxy {
+        ForwardProxy {
+            name: String::from("coap-proxy"),
+            timeout: 247000,
+            client: CoapClient::new(),
+            cache: Mutex::new(ResponseCache::new()),
+            clock: Box::new(SystemClock),
+            http: Box::new(TcpConnector),
+        }
+    }
+
+    /// how the proxy names itself in 5.08 Hop Limit Reached
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    /// timeout in milliseconds for the forwarded request
+    pub fn set_timeout(&mut self, timeout: u64) {
+        self.timeout = timeout;
+        self.client.set_timeout(timeout);
+    }
+
+    /// how many responses the cache keeps at most, least recently used go first
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache.lock().unwrap().set_capacity(capacity);
+    }
+
+    /// time source for the freshness of cached responses
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// replace the plain TCP connector used for `http` targets, e.g. with one
+    /// that speaks TLS so `https` targets work too
+    pub fn set_http_connector<T: HttpConnector + Send + Sync + 'static>(&mut self, connector: T) {
+        self.http = Box::new(connector);
+    }
+
+    pub fn is_proxy_request(request: &Request) -> bool {
+        request.get_option(OptionEnum::ProxyUri).is_some() || request.get_option(OptionEnum::ProxyScheme).is_some()
+    }
+
+    /// answer a request carrying Proxy-Uri or Proxy-Scheme
+    pub fn forward(&self, request: &Request) -> Response {
+        let url = match target_url(request) {
+            Ok(url) => url,
+            Err(response) => return response,
+        };
+        if !matches!(url.scheme(), "coap" | "http" | "https") {
+            return Response::new(ResponseCode::ProxyingNotSupported);
+        }
+        // unsafe options the proxy does not understand must not be passed on
+        let unknown_unsafe = request.get_options().keys().any(|number| {
+            matches!(number, OptionEnum::Unknown(n) if n & 0x02 != 0)
+        });
+        if unknown_unsafe {
+            return Response::new(ResponseCode::BadGateway);
+        }
+        let hop_limit = match hop_limit::next_hop_limit(request, &self.name) {
+            Ok(hop_limit) => hop_limit,
+            Err(response) => return response,
+        };
+
+        let now = self.clock.now();
+        let key = CacheKey::new(request);
+        let requested_etags = request.get_option(OptionEnum::ETag).cloned().unwrap_or_default();
+        let stale_etag = match self.cache.lock().unwrap().get(&key, now) {
+            Lookup::Fresh(response) => return validated(response, &requested_etags),
+            Lookup::Stale(etag) => Some(etag),
+            Lookup::Miss => None,
+        };
+        // ask the origin for our stale copy too, so a 2.03 can refresh it
+        let mut etags = requested_etags.clone();
+        if let Some(etag) = stale_etag {
+            if !etags.contains(&etag) {
+                etags.push(etag);
+            }
+        }
+
+        let forwarded = match url.scheme() {
+            "coap" => self.forward_coap(&url, request, hop_limit, &etags),
+            _ => self.forward_http(&url, request, &etags),
+        };
+        let response = match forwarded {
+            Ok(response) => response,
+            Err(e) => return Response::new(match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ResponseCode::GatewayTimeout,
+                io::ErrorKind::Unsupported => ResponseCode::ProxyingNotSupported,
+                _ => ResponseCode::BadGateway,
+            }),
+        };
+        let mut cache = self.cache.lock().unwrap();
+        // an unsafe request that succeeded changed the resource, RFC 7252 section 5.9
+        if !request.get_method().is_safe() && response.get_response_code().is_success() {
+            cache.invalidate(request);
+            return response;
+        }
+        if response.get_response_code() == ResponseCode::Valid {
+            return match cache.revalidate(&key, &response, now) {
+                Some(cached) => validated(cached, &requested_etags),
+                None => response,
+            };
+        }
+        cache.insert(key, &response, now);
+        response
+    }
+
+    fn forward_coap(&self, url: &Url, request: &Request, hop_limit: u8, etags: &[Vec<u8>]) -> io::Result<Response> {
+        let mut forward = self.client.new_request(request.get_method(), url.as_str())?;
+        for (number, values) in request.get_options() {
+            if NOT_FORWARDED.contains(&number) || number == OptionEnum::ETag {
+                continue;
+            }
+            for value in values {
+                forward.add_option(number, value.clone());
+            }
+        }
+        for etag in etags {
+            forward.add_option(OptionEnum::ETag, etag.clone());
+        }
+        forward.set_hop_limit(hop_limit);
+        forward.set_body(request.get_body().clone());
+        self.client.send_request(forward)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "response suppressed"))
+    }
+
+    /// CoAP-to-HTTP mapping, RFC 7252 section 10.1
+    fn forward_http(&self, url: &Url, request: &Request, etags: &[Vec<u8>]) -> io::Result<Response> {
+        let method = match request.get_method() {
+            RequestMethod::Get => "GET",
+            RequestMethod::Post => "POST",
+            RequestMethod::Put => "PUT",
+            RequestMethod::Deleted => "DELETE",
+            RequestMethod::Patch | RequestMethod::IPatch => "PATCH",
+            // HTTP has no method carrying a body that only retrieves
+            RequestMethod::Fetch => return Err(io::Error::new(io::ErrorKind::Unsupported, "FETCH has no HTTP mapping")),
+        };
+        let mut http = HttpRequest::new(method, &url[Position::BeforePath..Position::AfterQuery]);
+        let option = |number| request.get_option(number).and_then(|v| v.first());
+        if let Some(format) = option(OptionEnum::ContentFormat) {
+            let Ok(format) = ContentFormat::try_from(bytes_to_uint(format) as u16) else {
+                return Ok(Response::new(ResponseCode::UnsupportedContentFormat));
+            };
+            http.headers.push((String::from("Content-Type"), String::from(format.media_type())));
+        }
+        if let Some(format) = option(OptionEnum::Accept).and_then(|v| ContentFormat::try_from(bytes_to_uint(v) as u16).ok()) {
+            http.headers.push((String::from("Accept"), String::from(format.media_type())));
+        }
+        if option(OptionEnum::IfNoneMatch).is_some() {
+            http.headers.push((String::from("If-None-Match"), String::from("*")));
+        } else if !etags.is_empty() {
+            let etags: Vec<String> = etags.iter().map(|etag| etag_to_header(etag)).collect();
+            http.headers.push((String::from("If-None-Match"), etags.join(", ")));
+        }
+        if let Some(if_match) = request.get_option(OptionEnum::IfMatch) {
+            let etags: Vec<String> = if_match.iter()
+                .map(|etag| if etag.is_empty() { String::from("*") } else { etag_to_header(etag) })
+                .collect();
+            http.headers.push((String::from("If-Match"), etags.join(", ")));
+        }
+        http.body = request.get_body().clone();
+
+        let response = self.http.send(url, &http, Duration::from_millis(self.timeout))?;
+        Ok(coap_response(&response, request.get_method()))
+    }
+}
+
+fn coap_response(http: &HttpResponse, method: RequestMethod) -> Response {
+    let mut response = Response::new(coap_code(http.status, method));
+    if let Some(format) = http.header("Content-Type").and_then(ContentFormat::from_media_type) {
+        response.set_option(OptionEnum::ContentFormat, uint_to_bytes(u16::from(format) as u32));
+    }
+    if let Some(etag) = http.header("ETag").and_then(etag_from_header) {
+        response.set_option(OptionEnum::ETag, etag);
+    }
+    if let Some(cache_control) = http.header("Cache-Control") {
+        let max_age = cache_control.split(',').map(str::trim).find_map(|directive| match directive {
+            "no-store" | "no-cache" => Some(0),
+            _ => directive.strip_prefix("max-age=").and_then(|v| v.parse::<u32>().ok()),
+        });
+        if let Some(max_age) = max_age {
+            response.set_option(OptionEnum::MaxAge, uint_to_bytes(max_age));
+        }
+    }
+    response.set_body(http.body.clone());
+    response
+}
+
+/// HTTP status to CoAP response code, RFC 7252 section 10.1
+pub fn coap_code(status: u16, method: RequestMethod) -> ResponseCode {
+    match (status, method) {
+        (201, _) => ResponseCode::Created,
+        (200 | 203 | 204, RequestMethod::Get | RequestMethod::Fetch) => ResponseCode::Content,
+        (200 | 203 | 204, RequestMethod::Deleted) => ResponseCode::Deleted,
+        (200..=299, _) => ResponseCode::Changed,
+        (304, _) => ResponseCode::Valid,
+        (400, _) => ResponseCode::BadRequest,
+        (401, _) => ResponseCode::Unauthorized,
+        (403, _) => ResponseCode::Forbidden,
+        (404 | 410, _) => ResponseCode::NotFound,
+        (405, _) => ResponseCode::MethodNotAllowed,
+        (406, _) => ResponseCode::NotAcceptable,
+        (412, _) => ResponseCode::PreconditionFailed,
+        (413, _) => ResponseCode::RequestEntityTooLarge,
+        (415, _) => ResponseCode::UnsupportedContentFormat,
+        (422, _) => ResponseCode::UnprocessableEntity,
+        (429, _) => ResponseCode::TooManyRequests,
+        (400..=499, _) => ResponseCode::BadRequest,
+        (501, _) => ResponseCode::NotImplemented,
+        (503, _) => ResponseCode::ServiceUnavailable,
+        (504, _) => ResponseCode::GatewayTimeout,
+        (500..=599, _) => ResponseCode::InternalServerError,
+        // redirects and anything else the proxy cannot pass on
+        _ => ResponseCode::BadGateway,
+    }
+}
+
+/// 2.03 Valid when the client already holds this representation
+fn validated(response: Response, requested_etags: &[Vec<u8>]) -> Response {
+    match cache::etag(&response) {
+        Some(etag) if requested_etags.contains(etag) => {
+            let mut valid = Response::new(ResponseCode::Valid);
+            valid.set_option(OptionEnum::ETag, etag.clone());
+            if let Some(max_age) = response.get_option(OptionEnum::MaxAge).and_then(|v| v.first()) {
+                valid.set_option(OptionEnum::MaxAge, max_age.clone());
+            }
+            valid
+        }
+        _ => response,
+    }
+}
+
+/// absolute URI of the request, from Proxy-Uri or Proxy-Scheme plus the Uri-* options
+fn target_url(request: &Request) -> Result<Url, Response> {
+    let option = |number| request.get_option(number)
+        .and_then(|v| v.first())
+        .map(|v| String::from_utf8_lossy(v).into_owned());
+    let uri = match option(OptionEnum::ProxyUri) {
+        Some(uri) => uri,
+        None => {
+            let scheme = option(OptionEnum::ProxyScheme).unwrap_or_default();
+            let Some(host) = option(OptionEnum::UriHost) else {
+                return Err(Response::new(ResponseCode::BadRequest));
+            };
+            let port = request.get_option(OptionEnum::UriPort)
+                .and_then(|v| v.first())
+                .map(|v| format!(":{}", bytes_to_uint(v)))
+                .unwrap_or_default();
+            let query = request.get_option(OptionEnum::UriQuery)
+                .map(|qs| qs.iter().map(|q| String::from_utf8_lossy(q).into_owned()).collect::<Vec<String>>().join("&"))
+                .map(|q| format!("?{}", q))
+                .unwrap_or_default();
+            format!("{}://{}{}/{}{}", scheme, host, port, request.get_path(), query)
+        }
+    };
+    Url::parse(&uri).map_err(|_| Response::new(ResponseCode::BadRequest))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        io::{self, BufReader},
+        net::TcpListener,
+        sync::{atomic::{AtomicUsize, Ordering}, Arc},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use url::Url;
+
+    use crate::{
+        clock::ManualClock,
+        frame::{CoAPFrame, Header, MessageType, OptionEnum},
+        http::{HttpConnector, HttpRequest, HttpResponse},
+        proxy::{coap_code, ForwardProxy},
+        request::{CoapClient, Request, RequestMethod},
+        response::{Response, ResponseCode},
+        server::CoapServer,
+    };
+
+    /// origin answering every request with 2.05 fresh for a minute
+    struct CountingOrigin(Arc<AtomicUsize>);
+
+    impl HttpConnector for CountingOrigin {
+        fn send(&self, _: &Url, _: &HttpRequest, _: Duration) -> io::Result<HttpResponse> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            let headers = vec![(String::from("Cache-Control"), String::from("max-age=60"))];
+            Ok(HttpResponse { status: 200, headers, body: Vec::from("v") })
+        }
+    }
+
+    #[test]
+    fn cache_follows_injected_clock() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let clock = ManualClock::new(Instant::now());
+        let mut proxy = ForwardProxy::new();
+        proxy.set_clock(clock.clone());
+        proxy.set_http_connector(CountingOrigin(requests.clone()));
+        let proxied = |method: RequestMethod| {
+            let mut options = BTreeMap::new();
+            options.insert(u16::from(OptionEnum::ProxyUri), vec![Vec::from("http://origin.invalid/a")]);
+            let header = Header::new(MessageType::Con.into(), method as u8);
+            Request::from_frame(&CoAPFrame::new(header, options, vec![]), "127.0.0.1:5683".parse().unwrap()).unwrap()
+        };
+        let request = proxied(RequestMethod::Get);
+
+        assert_eq!(proxy.forward(&request).get_response_code(), ResponseCode::Content);
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(proxy.forward(&request).get_response_code(), ResponseCode::Content);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(proxy.forward(&request).get_body(), b"v");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // a successful POST to the resource drops what was cached for it
+        assert!(proxy.forward(&proxied(RequestMethod::Post)).get_response_code().is_success());
+        assert_eq!(proxy.forward(&request).get_body(), b"v");
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn http_status_mapping() {
+        assert_eq!(coap_code(200, RequestMethod::Get), ResponseCode::Content);
+        assert_eq!(coap_code(204, RequestMethod::Put), ResponseCode::Changed);
+        assert_eq!(coap_code(204, RequestMethod::Deleted), ResponseCode::Deleted);
+        assert_eq!(coap_code(304, RequestMethod::Get), ResponseCode::Valid);
+        assert_eq!(coap_code(418, RequestMethod::Get), ResponseCode::BadRequest);
+        assert_eq!(coap_code(302, RequestMethod::Get), ResponseCode::BadGateway);
+    }
+
+    #[test]
+    fn coap_to_http() {
+        let origin = TcpListener::bind("127.0.0.1:0").unwrap();
+        let http_port = origin.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in origin.incoming() {
+                let stream = stream.unwrap();
+                let request = HttpRequest::read_from(&mut BufReader::new(&stream)).unwrap();
+                let mut response = match request.method.as_str() {
+                    "GET" => HttpResponse::new(200),
+                    _ => HttpResponse::new(201),
+                };
+                if request.method == "GET" {
+                    response.set_header("Content-Type", String::from("application/json"));
+                    response.set_header("ETag", String::from("\"beef\""));
+                    response.set_header("Cache-Control", String::from("public, max-age=30"));
+                    response.body = vec![b'7'; 3000];
+                }
+                response.write_to(&mut &stream).unwrap();
+            }
+        });
+
+        let mut proxy = CoapServer::bind("127.0.0.1:0").unwrap();
+        proxy.set_forward_proxy(Some(ForwardProxy::new()));
+        let proxy_port = proxy.local_addr().unwrap().port();
+        thread::spawn(move || proxy.run(|_: &Request| Response::new(ResponseCode::NotFound)));
+
+        let client = CoapClient::new();
+        let proxy_uri = format!("coap://127.0.0.1:{}/", proxy_port);
+        let send = |method: RequestMethod, uri: String| {
+            let mut req = client.new_request(method, &proxy_uri).unwrap();
+            req.set_option(OptionEnum::ProxyUri, uri.into_bytes());
+            client.send_request(req).unwrap().unwrap()
+        };
+
+        // 3000 bytes reach the client block-wise
+        let res = send(RequestMethod::Get, format!("http://127.0.0.1:{}/readings?last=1", http_port));
+        assert_eq!(res.get_response_code(), ResponseCode::Content);
+        assert_eq!(res.get_body(), &vec![b'7'; 3000]);
+        assert_eq!(res.get_option(OptionEnum::ContentFormat), Some(&vec![vec![50]]));
+        assert_eq!(res.get_option(OptionEnum::ETag), Some(&vec![vec![0xbe, 0xef]]));
+        assert_eq!(res.get_option(OptionEnum::MaxAge), Some(&vec![vec![30]]));
+
+        let res = send(RequestMethod::Post, format!("http://127.0.0.1:{}/readings", http_port));
+        assert_eq!(res.get_response_code(), ResponseCode::Created);
+        let res = send(RequestMethod::Get, String::from("https://127.0.0.1/"));
+        assert_eq!(res.get_response_code(), ResponseCode::ProxyingNotSupported);
+    }
+}
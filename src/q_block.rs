@@ -0,0 +1,199 @@
+use std::{collections::BTreeMap, time::Duration};
+
+/// Q-Block transmission parameters, RFC 9177 section 7.2
+pub const MAX_PAYLOADS: usize = 10;
+pub const NON_TIMEOUT: Duration = Duration::from_secs(2);
+pub const NON_RECEIVE_TIMEOUT: Duration = Duration::from_secs(4);
+pub const NON_MAX_RETRANSMIT: u32 = 4;
+
+/// Content-Format of the missing block list in a 4.08 response
+pub const MISSING_BLOCKS_CBOR_SEQ: u16 = 272;
+
+/// tunables for Q-Block1/Q-Block2 bursts
+#[derive(Debug, Clone, Copy)]
+pub struct QBlockParameters {
+    /// blocks sent back to back before waiting for the peer
+    pub max_payloads: usize,
+    /// how long to wait for a response after a burst
+    pub non_timeout: Duration,
+    pub non_receive_timeout: Duration,
+    pub non_max_retransmit: u32,
+}
+
+impl Default for QBlockParameters {
+    fn default() -> Self {
+        QBlockParameters {
+            max_payloads: MAX_PAYLOADS,
+            non_timeout: NON_TIMEOUT,
+            non_receive_timeout: NON_RECEIVE_TIMEOUT,
+            non_max_retransmit: NON_MAX_RETRANSMIT,
+        }
+    }
+}
+
+/// blocks of one body received out of order
+#[derive(Debug, Default)]
+pub struct QBlockBody {
+    blocks: BTreeMap<u32, Vec<u8>>,
+    last: Option<u32>,
+    /// payload bytes held
+    len: usize,
+}
+
+impl QBlockBody {
+
+    /// keep a block, false when it contradicts the last block seen
+    pub fn insert(&mut self, num: u32, more: bool, payload: Vec<u8>) -> bool {
+        let beyond_last = self.last.is_some_and(|last| num > last || (!more && num != last));
+        let below_highest = !more && self.highest().is_some_and(|highest| highest > num);
+        if beyond_last || below_highest {
+            return false;
+        }
+        if !more {
+            self.last = Some(num);
+        }
+        self.len += payload.len();
+        if let Some(old) = self.blocks.insert(num, payload) {
+            self.len -= old.len();
+        }
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn last(&self) -> Option<u32> {
+        self.last
+    }
+
+    pub fn highest(&self) -> Option<u32> {
+        self.blocks.keys().next_back().copied()
+    }
+
+    /// blocks not received yet, up to the last block or the highest one seen
+    pub fn missing(&self) -> impl Iterator<Item = u32> + '_ {
+        let mut next = 0;
+        self.blocks.keys().copied()
+            .chain(std::iter::once(self.end()))
+            .flat_map(move |num| {
+                let gap = next..num;
+                next = num + 1;
+                gap
+            })
+    }
+
+    pub fn missing_count(&self) -> usize {
+        self.end() as usize - self.blocks.len()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.last.is_some() && self.missing_count() == 0
+    }
+
+    /// one past the last block, or past the highest one while the last is unknown
+    fn end(&self) -> u32 {
+        self.last.or(self.highest()).map_or(0, |num| num + 1)
+    }
+
+    pub fn assemble(self) -> Vec<u8> {
+        self.blocks.into_values().flatten().collect()
+    }
+}
+
+/// encode block numbers as a CBOR sequence of unsigned integers
+pub fn encode_missing(nums: &[u32]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for num in nums {
+        match *num {
+            n @ 0..=23 => buf.push(n as u8),
+            n @ 24..=0xFF => buf.extend_from_slice(&[0x18, n as u8]),
+            n @ 0x100..=0xFFFF => {
+                buf.push(0x19);
+                buf.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                buf.push(0x1A);
+                buf.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+    }
+    buf
+}
+
+/// encode as many of `nums` as fit in `max_len` bytes, the peer asks
+/// again for the rest (RFC 9177 section 5)
+pub fn encode_missing_within<I: IntoIterator<Item = u32>>(nums: I, max_len: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for num in nums {
+        let encoded = encode_missing(&[num]);
+        if buf.len() + encoded.len() > max_len {
+            break;
+        }
+        buf.extend_from_slice(&encoded);
+    }
+    buf
+}
+
+/// decode a CBOR sequence of unsigned integers, stops at anything else
+pub fn decode_missing(data: &[u8]) -> Vec<u32> {
+    let mut nums = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let (num, len) = match data[offset] {
+            n @ 0..=23 => (n as u32, 1),
+            0x18 if offset + 2 <= data.len() => (data[offset + 1] as u32, 2),
+            0x19 if offset + 3 <= data.len() => (u16::from_be_bytes([data[offset + 1], data[offset + 2]]) as u32, 3),
+            0x1A if offset + 5 <= data.len() => {
+                let b = &data[offset + 1..offset + 5];
+                (u32::from_be_bytes([b[0], b[1], b[2], b[3]]), 5)
+            }
+            _ => break,
+        };
+        nums.push(num);
+        offset += len;
+    }
+    nums
+}
+
+#[cfg(test)]
+mod test {
+    use crate::q_block::{decode_missing, encode_missing, encode_missing_within, QBlockBody};
+
+    #[test]
+    fn missing_blocks_cbor() {
+        let nums = vec![0, 23, 24, 255, 256, 70000];
+        let encoded = encode_missing(&nums);
+        assert_eq!(&encoded[..3], &[0x00, 0x17, 0x18]);
+        assert_eq!(decode_missing(&encoded), nums);
+        // 1 + 2 + 3 bytes fit, the 5 byte one does not
+        assert_eq!(decode_missing(&encode_missing_within(vec![1, 24, 256, 70000], 8)), vec![1, 24, 256]);
+    }
+
+    #[test]
+    fn out_of_order_body() {
+        let mut body = QBlockBody::default();
+        assert!(body.insert(2, false, vec![3]));
+        assert!(body.insert(0, true, vec![1]));
+        assert_eq!(body.missing().collect::<Vec<u32>>(), vec![1]);
+        assert!(!body.is_complete());
+        // nothing comes after the last block
+        assert!(!body.insert(3, true, vec![4]));
+        assert!(!body.insert(1, false, vec![2]));
+        assert!(body.insert(1, true, vec![2]));
+        assert!(body.is_complete());
+        assert_eq!(body.len(), 3);
+        assert_eq!(body.assemble(), vec![1, 2, 3]);
+
+        let mut body = QBlockBody::default();
+        body.insert(5, true, vec![1]);
+        body.insert(9, true, vec![1]);
+        assert_eq!(body.missing().collect::<Vec<u32>>(), vec![0, 1, 2, 3, 4, 6, 7, 8]);
+        assert_eq!(body.missing_count(), 8);
+        assert!(!body.insert(7, false, vec![1]));
+    }
+}
@@ -1,56 +1,668 @@
-use std::{collections::BTreeMap, vec, net::UdpSocket};
+use std::{collections::BTreeMap, vec, net::{Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs}, time::{Duration, Instant}, io, sync::Mutex};
 
+use rand::{rngs::StdRng, RngCore, SeedableRng};
 use url::Url;
 
-use crate::{frame::{
+use crate::{block::{BlockValue, szx_for, MAX_BLOCK_SIZE}, cache::{CacheKey, Lookup, ResponseCache}, clock::{Clock, SystemClock}, frame::{
     Header, MessageType, CoAPFrame,
     OptionEnum
-}, common::u16_to_bytes, response::Response};
+}, common::{bytes_to_uint, uint_to_bytes}, endpoint::{Endpoint, Event}, error::InvalidRequestMethod, middleware::Extensions,
+no_response::{NoResponse, NO_RESPONSE_WAIT}, q_block::{self, QBlockBody, QBlockParameters}, response::{Response, ResponseCode}, token::{RandomToken, TokenGenerator, MAX_TOKEN_LEN},
+transfer::{Exchange, Step, Transfer, Transfers},
+transmission::{CongestionParameters, RetransmissionPolicy, TransmissionParameters}, transport::{interface_index, Transport, UdpTransport}};
 
-#[derive(Debug, Clone, Copy)]
+/// longest a waiting request blocks on the transport before looking at
+/// events another thread sharing the client may have received
+const DRIVE_SLICE: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RequestMethod {
     Get = 1,
     Post,
     Put,
     Deleted,
+    /// RFC 8132
+    Fetch,
+    Patch,
+    IPatch,
+}
+
+impl RequestMethod {
+
+    /// GET and FETCH only retrieve, they change nothing on the server (RFC 7252 section 5.1)
+    pub fn is_safe(self) -> bool {
+        matches!(self, RequestMethod::Get | RequestMethod::Fetch)
+    }
 }
 
-pub struct CoapClient {
-    uri: String,
-    data_url: Url,
+impl TryFrom<u8> for RequestMethod {
+    type Error = InvalidRequestMethod;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(RequestMethod::Get),
+            2 => Ok(RequestMethod::Post),
+            3 => Ok(RequestMethod::Put),
+            4 => Ok(RequestMethod::Deleted),
+            5 => Ok(RequestMethod::Fetch),
+            6 => Ok(RequestMethod::Patch),
+            7 => Ok(RequestMethod::IPatch),
+            _ => Err(InvalidRequestMethod)
+        }
+    }
+}
+
+/// the peer of one request: its address and the host:port it was resolved from
+struct Peer {
+    addr: SocketAddr,
+    key: String,
+}
+
+pub struct CoapClient<T: Transport = UdpTransport> {
+    transport: T,
+    /// interface for IPv6 link-local peers without a zone, 0 when not chosen
+    scope_id: u32,
     timeout: u64,
+    no_response_wait: Duration,
     message_type: MessageType,
+    endpoint: Mutex<Endpoint>,
+    clock: Box<dyn Clock>,
+    tokens: Mutex<Box<dyn TokenGenerator + Send>>,
+    block1_size: usize,
+    transfers: Transfers,
+    q_block: Option<QBlockParameters>,
+    cache: Option<Mutex<ResponseCache>>,
 }
 
 impl CoapClient {
-    pub fn new(uri: String) -> Self {
-        let data_url = Url::parse(&uri).expect("parse url error");
-        if data_url.scheme() != "" && data_url.scheme() != "coap" && data_url.scheme() != "coaps" {
-            panic!("url scheme not support, must coap or coaps")
-        }
+    /// client on UDP sockets bound to ephemeral ports and reused for every
+    /// request, to any host; one socket per address family, so IPv4 and IPv6
+    /// peers can both be reached wherever the host has IPv6
+    pub fn new() -> Self {
+        CoapClient::with_transport(UdpTransport::dual().expect("bind udp socket error"))
+    }
+
+    /// client on a UDP socket bound to `local`, to choose the local address,
+    /// port and, with a scope id, the interface of an IPv6 link-local address
+    pub fn bind<A: ToSocketAddrs>(local: A) -> io::Result<Self> {
+        Ok(CoapClient::with_transport(UdpTransport::bind(local)?))
+    }
+}
+
+impl Default for CoapClient {
+    fn default() -> Self {
+        CoapClient::new()
+    }
+}
+
+impl<T: Transport> CoapClient<T> {
+    /// client sending its requests over `transport`
+    pub fn with_transport(transport: T) -> Self {
         CoapClient {
-            uri,
-            data_url,
+            transport,
+            scope_id: 0,
             timeout: 247000,
+            no_response_wait: NO_RESPONSE_WAIT,
             message_type: MessageType::Con,
+            endpoint: Mutex::new(Endpoint::new()),
+            clock: Box::new(SystemClock),
+            tokens: Mutex::new(Box::new(RandomToken::default())),
+            block1_size: MAX_BLOCK_SIZE,
+            transfers: Transfers::default(),
+            q_block: None,
+            cache: None,
+        }
+    }
+
+    pub fn get_transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// interface link-local IPv6 peers are reached on when the URI names
+    /// none, by name or index
+    pub fn set_interface(&mut self, interface: &str) -> io::Result<()> {
+        self.scope_id = interface_index(interface)?;
+        Ok(())
+    }
+
+    /// timeout in milliseconds for waiting on a response
+    pub fn set_timeout(&mut self, timeout: u64) {
+        self.timeout = timeout;
+    }
+
+    /// how long to wait for a response No-Response may have suppressed once
+    /// the request is acknowledged, or sent as NON, 5 s by default; the
+    /// request timeout still applies when it is shorter
+    pub fn set_no_response_wait(&mut self, wait: Duration) {
+        self.no_response_wait = wait;
+    }
+
+    pub fn set_type(&mut self, message_type: MessageType) {
+        self.message_type = message_type;
+    }
+
+    pub fn set_transmission_parameters(&mut self, params: TransmissionParameters) {
+        self.endpoint.lock().unwrap().set_transmission_parameters(params);
+    }
+
+    /// fixed retransmission timeouts (RFC 7252) or CoCoA estimating them
+    /// per peer from measured round trips
+    pub fn set_retransmission_policy(&mut self, policy: RetransmissionPolicy) {
+        self.endpoint.lock().unwrap().set_retransmission_policy(policy);
+    }
+
+    /// NSTART and PROBING_RATE (RFC 7252 section 4.7) for every peer
+    /// without parameters of its own
+    pub fn set_congestion_parameters(&mut self, params: CongestionParameters) {
+        self.endpoint.lock().unwrap().set_congestion_parameters(params);
+    }
+
+    /// NSTART and PROBING_RATE for one peer
+    pub fn set_peer_congestion_parameters(&mut self, peer: SocketAddr, params: CongestionParameters) {
+        self.endpoint.lock().unwrap().set_peer_congestion_parameters(peer, params);
+    }
+
+    /// largest message sent or received, 1152 bytes by default; larger
+    /// request bodies go block-wise and responses are asked for in blocks
+    /// that fit, a response that still does not fit fails the request
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.endpoint.lock().unwrap().set_max_message_size(size);
+    }
+
+    pub fn get_max_message_size(&self) -> usize {
+        self.endpoint.lock().unwrap().get_max_message_size()
+    }
+
+    /// time source for timeouts, deadlines and cache freshness; waiting
+    /// on the transport still takes real time, and no wait lasts longer
+    /// than the client timeout in real time either
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// draw tokens, message ids and retransmission jitter from `rng`, a
+    /// seeded one makes them reproducible; replaces the token generator
+    pub fn set_rng<R: RngCore + Send + 'static>(&mut self, mut rng: R) {
+        let token_rng = StdRng::from_rng(&mut rng).expect("seed token rng");
+        self.tokens = Mutex::new(Box::new(RandomToken::with_rng(MAX_TOKEN_LEN, token_rng)));
+        self.endpoint.lock().unwrap().set_rng(rng);
+    }
+
+    /// replace the default 8 byte random tokens
+    pub fn set_token_generator<G: TokenGenerator + Send + 'static>(&mut self, generator: G) {
+        self.tokens = Mutex::new(Box::new(generator));
+    }
+
+    /// longest token this endpoint sends and accepts, tokens above 8 bytes
+    /// need a peer supporting extended tokens (RFC 8974)
+    pub fn set_max_token_length(&mut self, len: usize) {
+        self.endpoint.lock().unwrap().set_max_token_length(len);
+    }
+
+    pub fn get_max_token_length(&self) -> usize {
+        self.endpoint.lock().unwrap().get_max_token_length()
+    }
+
+    /// request bodies larger than this are sent block-wise with Block1,
+    /// rounded down to a power of two between 16 and 1024
+    pub fn set_block1_size(&mut self, size: usize) {
+        self.block1_size = 1 << (szx_for(size) + 4);
+    }
+
+    /// use Q-Block1/Q-Block2 (RFC 9177) bursts for bodies spanning several
+    /// blocks, falling back to Block1/Block2 when the server answers 4.02
+    pub fn set_q_block(&mut self, params: Option<QBlockParameters>) {
+        self.q_block = params;
+    }
+
+    /// keep responses to GET requests for their Max-Age and revalidate
+    /// stale ones with their ETag (RFC 7252 section 5.6)
+    pub fn set_cache(&mut self, enabled: bool) {
+        self.cache = enabled.then(|| Mutex::new(ResponseCache::new()));
+    }
+
+    fn next_token(&self) -> io::Result<Vec<u8>> {
+        let token = self.tokens.lock().unwrap().generate();
+        if token.len() > self.get_max_token_length() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "token longer than max token length"));
+        }
+        Ok(token)
+    }
+
+    /// GET request for `uri`
+    fn new_req(&self, uri: &str) -> io::Result<Request> {
+        let mut req = Request::for_uri(uri, self.scope_id)?;
+        req.message_type = self.message_type;
+        req.timeout = self.timeout;
+        Ok(req)
+    }
+
+    pub fn get(&self, uri: &str) -> io::Result<Response> {
+        let req = self.new_req(uri)?;
+        self.send(req)
+    }
+
+    pub fn get_accept(&self, uri: &str, accept: u16) -> io::Result<Response> {
+        let mut req = self.new_req(uri)?;
+        req.set_option(OptionEnum::Accept, accept.to_be_bytes().to_vec());
+        self.send(req)
+    }
+
+    pub fn post(&self, uri: &str, body: Vec<u8>) -> io::Result<Response> {
+        let mut req = self.new_req(uri)?;
+        req.set_code(RequestMethod::Post);
+        req.set_body(body);
+        self.send(req)
+    }
+
+    pub fn put(&self, uri: &str, body: Vec<u8>) -> io::Result<Response> {
+        let mut req = self.new_req(uri)?;
+        req.set_code(RequestMethod::Put);
+        req.set_body(body);
+        self.send(req)
+    }
+
+    pub fn delete(&self, uri: &str) -> io::Result<Response> {
+        let mut req = self.new_req(uri)?;
+        req.set_code(RequestMethod::Deleted);
+        self.send(req)
+    }
+
+    /// PUT only if the resource still has `etag`, otherwise the server
+    /// answers 4.12 Precondition Failed
+    pub fn put_if_match(&self, uri: &str, body: Vec<u8>, etag: Vec<u8>) -> io::Result<Response> {
+        let mut req = self.new_req(uri)?;
+        req.set_code(RequestMethod::Put);
+        req.add_if_match(etag);
+        req.set_body(body);
+        self.send(req)
+    }
+
+    /// PUT only if the resource does not exist yet
+    pub fn put_if_none_match(&self, uri: &str, body: Vec<u8>) -> io::Result<Response> {
+        let mut req = self.new_req(uri)?;
+        req.set_code(RequestMethod::Put);
+        req.set_if_none_match();
+        req.set_body(body);
+        self.send(req)
+    }
+
+    /// DELETE only if the resource still has `etag`
+    pub fn delete_if_match(&self, uri: &str, etag: Vec<u8>) -> io::Result<Response> {
+        let mut req = self.new_req(uri)?;
+        req.set_code(RequestMethod::Deleted);
+        req.add_if_match(etag);
+        self.send(req)
+    }
+
+    /// request for `uri` that the caller can add options to before handing
+    /// it to `send_request`
+    pub fn new_request(&self, method: RequestMethod, uri: &str) -> io::Result<Request> {
+        let mut req = self.new_req(uri)?;
+        req.set_code(method);
+        Ok(req)
+    }
+
+    /// returns None only when the response was suppressed by No-Response
+    pub fn send_request(&self, req: Request) -> io::Result<Option<Response>> {
+        self.exchange(req)
+    }
+
+    /// send a request carrying No-Response (RFC 7967), returns None when the
+    /// server suppressed its response, or immediately for a NON that
+    /// suppresses every class; a suppressed response cannot be told from a
+    /// late one, so None comes after waiting `set_no_response_wait`
+    pub fn request_no_response(&self, method: RequestMethod, uri: &str, body: Vec<u8>, no_response: NoResponse) -> io::Result<Option<Response>> {
+        let mut req = self.new_req(uri)?;
+        req.set_code(method);
+        req.set_body(body);
+        req.set_no_response(no_response);
+        self.exchange(req)
+    }
+
+    fn send(&self, req: Request) -> io::Result<Response> {
+        self.exchange(req)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "response suppressed by No-Response"))
+    }
+
+    /// send a request, answering GETs from the cache when enabled
+    fn exchange(&self, req: Request) -> io::Result<Option<Response>> {
+        let Some(cache) = &self.cache else {
+            return self.transfer(req);
+        };
+        if req.code != RequestMethod::Get {
+            let res = self.transfer(req.clone())?;
+            // a successful unsafe request changes the resource, RFC 7252 section 5.9.1
+            let code = res.as_ref().map(|res| res.get_response_code());
+            if matches!(code, Some(ResponseCode::Created | ResponseCode::Deleted | ResponseCode::Changed)) {
+                cache.lock().unwrap().invalidate(&req);
+            }
+            return Ok(res);
+        }
+
+        // ETags set by the caller mean it validates its own copy
+        let own_etags = req.get_option(OptionEnum::ETag).is_some();
+        let key = CacheKey::new(&req);
+        let mut req = req;
+        match cache.lock().unwrap().get(&key, self.clock.now()) {
+            Lookup::Fresh(res) if !own_etags => return Ok(Some(res)),
+            Lookup::Stale(etag) if !own_etags => req.set_option(OptionEnum::ETag, etag),
+            _ => {}
+        }
+        let Some(res) = self.transfer(req)? else {
+            return Ok(None);
+        };
+        let now = self.clock.now();
+        let mut cache = cache.lock().unwrap();
+        if res.get_response_code() == ResponseCode::Valid && !own_etags {
+            if let Some(cached) = cache.revalidate(&key, &res, now) {
+                return Ok(Some(cached));
+            }
         }
+        cache.insert(key, &res, now);
+        Ok(Some(res))
     }
 
-    fn new_req(&self) -> Request {
-        let host = self.data_url.host_str().unwrap();
-        let port = self.data_url.port().unwrap_or(5683);
-        let path = self.data_url.path();
-        
+    /// send a request, block-wise when the body does not fit one block and
+    /// collecting block-wise responses into one body
+    fn transfer(&self, req: Request) -> io::Result<Option<Response>> {
+        let peer = self.resolve(&req)?;
+        // Q-Block2 asks for response blocks of its own
+        let mut transfer = Transfer::new(&self.transfers, req, self.get_max_message_size(), self.block1_size, self.q_block.is_none())?;
+        let mut step = match (self.q_block, transfer.upload()) {
+            (Some(params), None) => match self.send_q_block2(&peer, transfer.request(), transfer.block_size(), params)? {
+                Some(res) => transfer.download(res),
+                None => Step::Done(None),
+            },
+            (Some(params), Some((body, tag))) => match self.send_q_block1(&peer, transfer.request(), body, tag, transfer.block_size(), params)? {
+                Some(Some(res)) => transfer.download(res),
+                Some(None) => Step::Done(None),
+                // the server does not support it, fall back to Block1
+                None => Step::Send(transfer.first()),
+            },
+            (None, _) => Step::Send(transfer.first()),
+        };
+        loop {
+            match step {
+                Step::Send(message) => step = transfer.response(message.send(self, peer.addr)?)?,
+                Step::Done(res) => return Ok(res),
+            }
+        }
+    }
+
+    /// Q-Block1 upload, RFC 9177 section 4.3: blocks go out as NON in bursts
+    /// of max_payloads, the server asks for the next burst with 2.31 and for
+    /// lost blocks with 4.08; returns None when the server does not support it
+    fn send_q_block1(&self, peer: &Peer, req: &Request, body: &[u8], tag: &[u8], size: usize, params: QBlockParameters) -> io::Result<Option<Option<Response>>> {
+        let last = (body.len().div_ceil(size).max(1) - 1) as u32;
+        let mut base = req.clone();
+        base.set_type(MessageType::Non);
+        base.set_option(OptionEnum::RequestTag, tag.to_vec());
+        base.token = Some(self.next_token()?);
+        self.transfers.add_echo(&peer.key, &mut base);
+        let send_block = |num: u32| {
+            let offset = num as usize * size;
+            let mut block_req = base.clone();
+            block_req.set_body(body[offset..(offset + size).min(body.len())].to_vec());
+            block_req.set_option(OptionEnum::QBlock1, BlockValue::new(num, num < last, size).to_value());
+            block_req.transmit(self, peer.addr)
+        };
+
+        let mut next = 0;
+        let mut confirmed = false;
+        let mut retransmits = 0;
+        loop {
+            let set_end = (next + params.max_payloads as u32).min(last + 1);
+            for num in next..set_end {
+                send_block(num)?;
+            }
+            next = set_end;
+            loop {
+                let Some(res) = self.receive_response(peer.addr, base.token.as_ref().unwrap(), self.clock.now() + params.non_timeout)? else {
+                    // nothing heard, poke the server with the last block of the burst
+                    if retransmits >= params.non_max_retransmit {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "no response to Q-Block1 burst"));
+                    }
+                    retransmits += 1;
+                    send_block(next - 1)?;
+                    continue;
+                };
+                retransmits = 0;
+                match res.get_response_code() {
+                    ResponseCode::BadOption if !confirmed => return Ok(None),
+                    ResponseCode::Continue => {
+                        confirmed = true;
+                        if next <= last {
+                            break;
+                        }
+                    }
+                    ResponseCode::RequestEntityIncomplete => {
+                        confirmed = true;
+                        for num in q_block::decode_missing(res.get_body()) {
+                            if num <= last {
+                                send_block(num)?;
+                            }
+                        }
+                    }
+                    _ => return Ok(Some(Some(res))),
+                }
+            }
+        }
+    }
+
+    /// request with Q-Block2 (RFC 9177 section 4.4), collecting the NON burst
+    /// the server answers with and asking again for lost blocks; servers
+    /// that do not support it get the request again without Q-Block2
+    fn send_q_block2(&self, peer: &Peer, req: &Request, size: usize, params: QBlockParameters) -> io::Result<Option<Response>> {
+        let mut first = req.clone();
+        let token = self.next_token()?;
+        first.token = Some(token.clone());
+        first.set_option(OptionEnum::QBlock2, BlockValue::new(0, false, size).to_value());
+        let Some(mut res) = self.exchange_fresh(peer, first)? else {
+            return Ok(None);
+        };
+        if res.get_response_code() == ResponseCode::BadOption {
+            return self.exchange_fresh(peer, req.clone());
+        }
+        let Some(block) = block_option(res.get_option(OptionEnum::QBlock2)) else {
+            return Ok(Some(res));
+        };
+
+        let mut body = QBlockBody::default();
+        body.insert(block.num, block.more, res.get_body().clone());
+        let mut retransmits = 0;
+        loop {
+            let mut progress = false;
+            while !body.is_complete() {
+                // a whole set arrived, no need to wait for the timeout
+                let set_done = body.highest().is_some_and(|h| (h + 1).is_multiple_of(params.max_payloads as u32));
+                if set_done && progress && body.missing_count() == 0 {
+                    break;
+                }
+                let Some(more) = self.receive_response(peer.addr, &token, self.clock.now() + params.non_timeout)? else {
+                    break;
+                };
+                if let Some(block) = block_option(more.get_option(OptionEnum::QBlock2)) {
+                    body.insert(block.num, block.more, more.get_body().clone());
+                    progress = true;
+                }
+            }
+            if body.is_complete() {
+                res.remove_option(OptionEnum::QBlock2);
+                res.set_body(body.assemble());
+                return Ok(Some(res));
+            }
+            if !progress {
+                if retransmits >= params.non_max_retransmit {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "Q-Block2 blocks missing"));
+                }
+                retransmits += 1;
+            } else {
+                retransmits = 0;
+            }
+
+            // ask for the lost blocks, or for the next burst once all seen so far arrived
+            let mut again = req.clone();
+            again.set_type(MessageType::Non);
+            again.token = Some(token.clone());
+            if body.missing_count() == 0 {
+                let next = body.highest().map_or(0, |n| n + 1);
+                again.set_option(OptionEnum::QBlock2, BlockValue { num: next, more: true, szx: block.szx }.to_value());
+            } else {
+                // a burst worth at a time, so the request stays small
+                for num in body.missing().take(params.max_payloads) {
+                    again.add_option(OptionEnum::QBlock2, BlockValue { num, more: false, szx: block.szx }.to_value());
+                }
+            }
+            again.transmit(self, peer.addr)?;
+        }
+    }
+
+    /// wait for a response carrying `token` until `deadline`
+    fn receive_response(&self, peer: SocketAddr, token: &[u8], deadline: Instant) -> io::Result<Option<Response>> {
+        self.endpoint.lock().unwrap().listen(peer, token.to_vec(), deadline);
+        let event = self.drive(|event| event.token() == Some(token));
+        self.endpoint.lock().unwrap().close(token, self.clock.now());
+        match event? {
+            Event::Response { response, .. } => Ok(Some(response)),
+            Event::TooLarge { .. } => Err(too_large()),
+            _ => Ok(None),
+        }
+    }
+
+    /// send one request, repeating it once with the Echo value when the server
+    /// asks for proof of freshness with 4.01 (RFC 9175 section 2)
+    fn exchange_fresh(&self, peer: &Peer, mut req: Request) -> io::Result<Option<Response>> {
+        self.transfers.add_echo(&peer.key, &mut req);
+        let Some(res) = req.send(self, peer.addr)? else {
+            return Ok(None);
+        };
+        match self.transfers.echo_retry(&peer.key, &req, &res) {
+            Some(again) => again.send(self, peer.addr),
+            None => Ok(Some(res)),
+        }
+    }
+
+    fn resolve(&self, req: &Request) -> io::Result<Peer> {
+        // an IPv4 socket can only reach IPv4 peers, a dual-stack one either
+        let ipv4 = self.transport.local_addr()?.is_ipv4().then_some(true);
+        Ok(Peer { addr: req.resolve(ipv4)?, key: req.authority() })
+    }
+
+    /// send what the endpoint has queued
+    fn flush(&self, endpoint: &mut Endpoint) -> io::Result<()> {
+        while let Some(transmit) = endpoint.poll_transmit() {
+            self.transport.send_to(&transmit.data, transmit.peer)?;
+        }
+        Ok(())
+    }
+
+    /// wait for an event `pick` wants, events for other requests stay queued
+    fn drive<F>(&self, mut pick: F) -> io::Result<Event>
+    where
+        F: FnMut(&Event) -> bool,
+    {
+        self.drive_until(|endpoint| endpoint.poll_event_matching(&mut pick))
+    }
+
+    /// blocking driver for the endpoint: send its datagrams, feed it what
+    /// arrives and its timeouts until `done` has a result
+    ///
+    /// waits are cut into short slices, so threads sharing the client pick
+    /// up what another received; the client timeout also holds in real
+    /// time, so a clock nobody advances cannot keep the caller forever
+    fn drive_until<R, F>(&self, mut done: F) -> io::Result<R>
+    where
+        F: FnMut(&mut Endpoint) -> Option<R>,
+    {
+        let give_up = Instant::now() + Duration::from_millis(self.timeout);
+        // one byte more than fits, to tell a truncated datagram from a full one
+        let mut buf = vec![0u8; self.get_max_message_size() + 1];
+        loop {
+            if Instant::now() >= give_up {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "nothing within the client timeout"));
+            }
+            let wake = {
+                let mut endpoint = self.endpoint.lock().unwrap();
+                endpoint.handle_timeout(self.clock.now());
+                self.flush(&mut endpoint)?;
+                if let Some(result) = done(&mut endpoint) {
+                    return Ok(result);
+                }
+                endpoint.poll_timeout()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "nothing left to wait for"))?
+            };
+            let timeout = wake.saturating_duration_since(self.clock.now()).clamp(Duration::from_millis(1), DRIVE_SLICE);
+            match self.transport.recv_from(&mut buf, Some(timeout)) {
+                Ok((len, from)) => self.endpoint.lock().unwrap().handle_datagram(from, &buf[..len], self.clock.now()),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// CoAP ping: send an empty confirmable message and wait for the RST,
+    /// returns the round trip time of the last transmission
+    pub fn ping(&self, uri: &str) -> io::Result<Duration> {
+        let req = self.new_req(uri)?;
+        let peer = self.resolve(&req)?.addr;
+        let msg_id = self.endpoint.lock().unwrap().ping(peer, self.clock.now())?;
+        let event = self.drive(|event| matches!(event,
+            Event::Pong { message_id, .. } | Event::PingTimedOut { message_id, .. } if *message_id == msg_id))?;
+        match event {
+            Event::Pong { rtt, .. } => Ok(rtt),
+            _ => Err(io::Error::new(io::ErrorKind::TimedOut, "no reply to CoAP ping")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    message_type: MessageType,
+    code: RequestMethod,
+    host: String,
+    port: u16,
+    /// interface of an IPv6 link-local host, from the zone of the URI
+    scope_id: u32,
+    options: BTreeMap<u16, Vec<Vec<u8>>>,
+    body: Vec<u8>,
+    timeout: u64,
+    peer: Option<SocketAddr>,
+    token: Option<Vec<u8>>,
+    /// values attached by server middleware, never sent
+    extensions: Extensions,
+}
+
+impl Request {
+
+    /// confirmable GET for `uri`; `scope_id` is the interface of an IPv6
+    /// link-local host whose URI has no zone
+    pub(crate) fn for_uri(uri: &str, scope_id: u32) -> io::Result<Request> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message.to_owned());
+        // the zone of an IPv6 literal (RFC 6874) is not understood by the url crate
+        let (uri, zone) = split_zone(uri);
+        let url = Url::parse(&uri).map_err(|_| invalid("invalid uri"))?;
+        if url.scheme() != "coap" && url.scheme() != "coaps" {
+            return Err(invalid("url scheme not support, must coap or coaps"));
+        }
+        let host = url.host_str().ok_or_else(|| invalid("uri without host"))?;
+        let port = url.port().unwrap_or(5683);
+        let path = url.path();
+        let scope_id = match zone {
+            Some(zone) => interface_index(&zone)?,
+            None => scope_id,
+        };
+
         let mut options = BTreeMap::new();
         options.insert(u16::from(OptionEnum::UriHost), vec![Vec::from(host)]);
-        options.insert(u16::from(OptionEnum::UriPort), vec![u16_to_bytes(port)]);
+        options.insert(u16::from(OptionEnum::UriPort), vec![uint_to_bytes(port as u32)]);
         if !path.is_empty() {
             let ps: Vec<Vec<u8>> = path.split('/')
             .filter(|f|!f.is_empty())
             .map(Vec::from).collect();
             options.insert(u16::from(OptionEnum::UriPath,), ps);
         }
-        if let Some(query) = self.data_url.query() {
+        if let Some(query) = url.query() {
             if !query.is_empty() {
                 let qs = query.split('&').map(|f| {
                     Vec::from(f)
@@ -58,40 +670,144 @@ impl CoapClient {
                 options.insert(u16::from(OptionEnum::UriQuery), qs);
             }
         }
-        Request {
+        Ok(Request {
             message_type: MessageType::Con,
             code: RequestMethod::Get,
-            scheme: self.data_url.scheme().to_owned(),
             host: host.to_owned(),
-            port: port,
-            options, 
-            data_url: self.data_url.clone(),
+            port,
+            scope_id,
+            options,
             body: vec![],
-        }
+            timeout: 247000,
+            peer: None,
+            token: None,
+            extensions: Extensions::new(),
+        })
     }
 
-    pub fn get(&self) -> Response{
-        let req = self.new_req();
-        req.send()
+    /// address of the host the request is for; `ipv4` picks the family
+    /// when a name has both
+    pub(crate) fn resolve(&self, ipv4: Option<bool>) -> io::Result<SocketAddr> {
+        resolve_host(&self.host, self.port, self.scope_id, ipv4)
     }
 
-    pub fn get_accept(&self, accept: u16) {
+    /// host:port the request is for, which Echo values are kept by
+    pub(crate) fn authority(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
 
+    /// build a request from a received frame, `peer` is the address it came from
+    pub fn from_frame(frame: &CoAPFrame, peer: SocketAddr) -> Result<Request, InvalidRequestMethod> {
+        let code = RequestMethod::try_from(frame.header.get_code())?;
+        let options = frame.get_options();
+        let host = options.get(&u16::from(OptionEnum::UriHost))
+            .and_then(|v| v.first())
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+            .unwrap_or_else(|| peer.ip().to_string());
+        let port = options.get(&u16::from(OptionEnum::UriPort))
+            .and_then(|v| v.first())
+            .map(|v| bytes_to_uint(v) as u16)
+            .unwrap_or(peer.port());
+        Ok(Request {
+            message_type: frame.header.get_type().try_into().unwrap(),
+            code,
+            host,
+            port,
+            scope_id: match peer {
+                SocketAddr::V6(addr) => addr.scope_id(),
+                SocketAddr::V4(_) => 0,
+            },
+            options,
+            body: frame.get_body(),
+            timeout: 0,
+            peer: Some(peer),
+            token: Some(frame.get_token()),
+            extensions: Extensions::new(),
+        })
     }
-}
 
-struct Request {
-    message_type: MessageType,
-    code: RequestMethod,
-    scheme: String,
-    host: String,
-    port: u16,
-    options: BTreeMap<u16, Vec<Vec<u8>>>,
-    data_url: Url,
-    body: Vec<u8>,
-}
+    pub fn get_type(&self) -> MessageType {
+        self.message_type
+    }
 
-impl Request {
+    pub fn get_method(&self) -> RequestMethod {
+        self.code
+    }
+
+    /// token of a received request
+    pub fn get_token(&self) -> Option<&Vec<u8>> {
+        self.token.as_ref()
+    }
+
+    /// the address a received request came from
+    pub fn get_peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub fn get_extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn get_extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Uri-Path segments joined with '/'
+    pub fn get_path(&self) -> String {
+        self.options.get(&u16::from(OptionEnum::UriPath))
+            .map(|ps| ps.iter()
+                .map(|p| String::from_utf8_lossy(p).into_owned())
+                .collect::<Vec<String>>()
+                .join("/"))
+            .unwrap_or_default()
+    }
+
+    pub fn get_option(&self, number: OptionEnum) -> Option<&Vec<Vec<u8>>> {
+        self.options.get(&u16::from(number))
+    }
+
+    pub fn set_no_response(&mut self, no_response: NoResponse) {
+        self.set_option(OptionEnum::NoResponse, no_response.to_value());
+    }
+
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.set_option(OptionEnum::HopLimit, vec![hop_limit]);
+    }
+
+    /// an empty `etag` matches any current representation
+    pub fn add_if_match(&mut self, etag: Vec<u8>) {
+        self.add_option(OptionEnum::IfMatch, etag);
+    }
+
+    pub fn set_if_none_match(&mut self) {
+        self.set_option(OptionEnum::IfNoneMatch, vec![]);
+    }
+
+    /// None also when the option is not a single byte from 1 to 255 (RFC 8768 section 3)
+    pub fn get_hop_limit(&self) -> Option<u8> {
+        match self.get_option(OptionEnum::HopLimit)?.first()?[..] {
+            [limit] if limit > 0 => Some(limit),
+            _ => None,
+        }
+    }
+
+    pub fn get_no_response(&self) -> Option<NoResponse> {
+        self.get_option(OptionEnum::NoResponse)
+            .and_then(|v| v.first())
+            .map(|v| NoResponse::from_value(v))
+    }
+
+    pub fn get_options(&self) -> BTreeMap<OptionEnum, &Vec<Vec<u8>>> {
+        let mut options = BTreeMap::new();
+        for ele in &self.options {
+            options.insert(OptionEnum::from(*ele.0), ele.1);
+        }
+        options
+    }
+
+    pub fn get_body(&self) -> &Vec<u8> {
+        &self.body
+    }
 
     pub fn set_body(&mut self, body: Vec<u8>) {
         self.body = body;
@@ -104,8 +820,20 @@ impl Request {
     pub fn set_code(&mut self, code: RequestMethod) {
         self.code = code;
     }
+
+    pub fn set_option(&mut self, number: OptionEnum, value: Vec<u8>) {
+        self.options.insert(u16::from(number), vec![value]);
+    }
+
+    pub fn add_option(&mut self, number: OptionEnum, value: Vec<u8>) {
+        self.options.entry(u16::from(number)).or_default().push(value);
+    }
+
+    pub fn remove_option(&mut self, number: OptionEnum) {
+        self.options.remove(&u16::from(number));
+    }
     
-    fn to_frame(&self) -> CoAPFrame {
+    pub fn to_frame(&self) -> CoAPFrame {
         let header = Header::new(
             self.message_type.into(),
             self.code as u8
@@ -114,16 +842,297 @@ impl Request {
         CoAPFrame::new(header, self.options.clone(), self.body.clone())
     }
 
-    fn send(&self) -> Response{
-        let frame = self.to_frame();
-        let socket = UdpSocket::bind("0.0.0.0:0").expect("client bind error");
-        socket.connect(format!("{}:{}", self.host, self.port)).expect("client connect error");
-        let send_size = socket.send(&frame.to_bytes()).expect("send coap message error");
-        let mut buf = [0u8;1024];
-        let recv_len = socket.recv(&mut buf).expect("udp recv error");
+    /// send once as is, without waiting for anything; the token stays open
+    /// for `receive_response`
+    fn transmit<T: Transport>(&self, client: &CoapClient<T>, peer: SocketAddr) -> io::Result<()> {
+        let token = match &self.token {
+            Some(token) => token.clone(),
+            None => client.next_token()?,
+        };
+        let mut endpoint = client.endpoint.lock().unwrap();
+        let now = client.clock.now();
+        endpoint.request(peer, self, token.clone(), now + Duration::from_millis(self.timeout), now)?;
+        client.flush(&mut endpoint)?;
+        Ok(())
+    }
+
+    fn send<T: Transport>(&self, client: &CoapClient<T>, peer: SocketAddr) -> io::Result<Option<Response>> {
+        let token = match &self.token {
+            Some(token) => token.clone(),
+            None => client.next_token()?,
+        };
+        let now = client.clock.now();
+        let mut exchange = Exchange::new(self, peer, token.clone(), now, Duration::from_millis(self.timeout), client.no_response_wait);
+        exchange.start(&mut client.endpoint.lock().unwrap(), self, now)?;
+        if exchange.expects_nothing() {
+            // nothing comes back, but the request may first have to wait its turn
+            client.drive_until(|endpoint| (!endpoint.is_queued(&token)).then_some(()))?;
+            let mut endpoint = client.endpoint.lock().unwrap();
+            client.flush(&mut endpoint)?;
+            endpoint.close(&token, client.clock.now());
+            return Ok(None);
+        }
+
+        // a CON is retransmitted by the endpoint until it is acknowledged, a NON is sent once
+        let res = loop {
+            let event = match client.drive(|event| event.token() == Some(&token[..])) {
+                Ok(event) => event,
+                Err(e) => break Err(e),
+            };
+            if let Some(res) = exchange.event(event, &mut client.endpoint.lock().unwrap(), client.clock.now()) {
+                break res;
+            }
+        };
+        client.endpoint.lock().unwrap().close(&token, client.clock.now());
+        res
+    }
+}
+
+/// split the zone off an IPv6 literal host, `[fe80::1%eth0]` or the
+/// percent-encoded `[fe80::1%25eth0]` of RFC 6874
+fn split_zone(uri: &str) -> (String, Option<String>) {
+    let (Some(open), Some(close)) = (uri.find('['), uri.find(']')) else {
+        return (uri.to_owned(), None);
+    };
+    let Some(percent) = uri[open..close].find('%').map(|i| open + i) else {
+        return (uri.to_owned(), None);
+    };
+    let zone = &uri[percent + 1..close];
+    let zone = zone.strip_prefix("25").filter(|z| !z.is_empty()).unwrap_or(zone);
+    (format!("{}{}", &uri[..percent], &uri[close..]), Some(zone.to_owned()))
+}
+
+/// address of `host`, an IP literal or a name; `scope_id` is applied to
+/// IPv6 link-local addresses, `ipv4` picks the family when a name has both
+fn resolve_host(host: &str, port: u16, scope_id: u32, ipv4: Option<bool>) -> io::Result<SocketAddr> {
+    if let Some(literal) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        let ip: Ipv6Addr = literal.parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid IPv6 address"))?;
+        // fe80::/10, the only addresses a scope id makes sense for
+        let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+        return Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, if link_local { scope_id } else { 0 })));
+    }
+    let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+    let preferred = ipv4.and_then(|ipv4| addrs.iter().find(|addr| addr.is_ipv4() == ipv4));
+    preferred.or(addrs.first()).copied()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host did not resolve"))
+}
+
+pub(crate) fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "response larger than the maximum message size")
+}
+
+pub(crate) fn block_option(values: Option<&Vec<Vec<u8>>>) -> Option<BlockValue> {
+    values.and_then(|v| v.first()).and_then(|v| BlockValue::from_value(v))
+}
+
+/// CON request for `/a` from `peer`, built without a client so no socket is
+/// bound; a fixture of the endpoint and simulator tests
+#[cfg(test)]
+pub(crate) fn test_request(method: RequestMethod, peer: SocketAddr) -> Request {
+    let mut options = BTreeMap::new();
+    options.insert(u16::from(OptionEnum::UriPath), vec![Vec::from("a")]);
+    let header = Header::new(MessageType::Con.into(), method as u8);
+    Request::from_frame(&CoAPFrame::new(header, options, vec![]), peer).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, io, net::SocketAddr, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+    use crate::{
+        block::BlockValue,
+        clock::ManualClock,
+        frame::{CoAPFrame, Header, MessageType, OptionEnum},
+        q_block::QBlockParameters,
+        request::{resolve_host, split_zone, CoapClient, RequestMethod},
+        response::ResponseCode,
+        transport::{MemoryNetwork, MemoryTransport, Transport},
+    };
+
+    type Log = Arc<Mutex<Vec<CoAPFrame>>>;
+
+    fn server() -> SocketAddr {
+        "10.0.0.1:5683".parse().unwrap()
+    }
+
+    /// client on an in-memory network whose peer at `server()` sends back
+    /// what `script` makes of each message, all messages it got are logged
+    fn scripted<F>(mut script: F) -> (CoapClient<MemoryTransport>, Log)
+    where
+        F: FnMut(&CoAPFrame) -> Vec<CoAPFrame> + Send + 'static,
+    {
+        let network = MemoryNetwork::new();
+        let peer = network.bind(server()).unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let seen = log.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            while let Ok((len, from)) = peer.recv_from(&mut buf, None) {
+                let frame = CoAPFrame::from_bytes(buf[..len].to_vec()).unwrap();
+                for reply in script(&frame) {
+                    peer.send_to(&reply.to_bytes(), from).unwrap();
+                }
+                seen.lock().unwrap().push(frame);
+            }
+        });
+        (CoapClient::with_transport(network.bind("10.0.0.2:0".parse().unwrap()).unwrap()), log)
+    }
+
+    /// piggybacked response to `request`
+    fn answer(request: &CoAPFrame, code: ResponseCode, options: Vec<(OptionEnum, Vec<u8>)>, body: &[u8]) -> CoAPFrame {
+        let mut header = Header::new(MessageType::Ack.into(), code.into());
+        header.set_msg_id(request.header.get_msg_id());
+        let mut values = BTreeMap::new();
+        for (number, value) in options {
+            values.entry(u16::from(number)).or_insert_with(Vec::new).push(value);
+        }
+        let mut frame = CoAPFrame::new(header, values, body.to_vec());
+        frame.set_token(request.get_token()).unwrap();
+        frame
+    }
+
+    fn option(frame: &CoAPFrame, number: OptionEnum) -> Option<Vec<u8>> {
+        frame.get_options().get(&u16::from(number)).and_then(|values| values.first()).cloned()
+    }
+
+    fn block(frame: &CoAPFrame, number: OptionEnum) -> Option<BlockValue> {
+        option(frame, number).and_then(|value| BlockValue::from_value(&value))
+    }
+
+    #[test]
+    fn uri_options() {
+        let request = CoapClient::new().new_request(RequestMethod::Get, "coap://127.0.0.1:5684/a/b?x=1").unwrap();
+        assert_eq!(request.get_option(OptionEnum::UriPort), Some(&vec![vec![0x16, 0x34]]));
+        assert_eq!(request.get_path(), "a/b");
+        assert_eq!(request.get_option(OptionEnum::UriQuery), Some(&vec![b"x=1".to_vec()]));
+    }
+
+    #[test]
+    fn ipv6_zones() {
+        assert_eq!(split_zone("coap://[fe80::1%eth0]:5683/a"), (String::from("coap://[fe80::1]:5683/a"), Some(String::from("eth0"))));
+        assert_eq!(split_zone("coap://[fe80::1%253]/a"), (String::from("coap://[fe80::1]/a"), Some(String::from("3"))));
+        assert_eq!(split_zone("coap://[::1]/a%20b"), (String::from("coap://[::1]/a%20b"), None));
+
+        let link_local = resolve_host("[fe80::1]", 5683, 3, None).unwrap();
+        assert_eq!(link_local, "[fe80::1%3]:5683".parse::<SocketAddr>().unwrap());
+        // global addresses have no scope
+        assert_eq!(resolve_host("[2001:db8::1]", 1, 3, None).unwrap(), "[2001:db8::1]:1".parse::<SocketAddr>().unwrap());
+        assert_eq!(resolve_host("127.0.0.1", 1, 3, Some(false)).unwrap(), "127.0.0.1:1".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    fn echo_is_repeated_and_remembered() {
+        let (client, log) = scripted(|req| match option(req, OptionEnum::Echo) {
+            Some(echo) => vec![answer(req, ResponseCode::Changed, vec![], &echo)],
+            None => vec![answer(req, ResponseCode::Unauthorized, vec![(OptionEnum::Echo, vec![7])], b"")],
+        });
+        assert_eq!(client.put("coap://10.0.0.1/valve", vec![1]).unwrap().get_body(), &vec![7]);
+        // the value is sent right away from then on
+        assert_eq!(client.put("coap://10.0.0.1/valve", vec![2]).unwrap().get_body(), &vec![7]);
+        let echoes: Vec<_> = log.lock().unwrap().iter().map(|req| option(req, OptionEnum::Echo)).collect();
+        assert_eq!(echoes, vec![None, Some(vec![7]), Some(vec![7])]);
+    }
+
+    #[test]
+    fn silent_peer_with_a_stopped_clock() {
+        let (mut client, log) = scripted(|_| vec![]);
+        // retransmissions and the deadline never come due
+        client.set_clock(ManualClock::new(Instant::now()));
+        client.set_timeout(300);
+        let start = Instant::now();
+        let err = client.get("coap://10.0.0.1/silent").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(log.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn options_beyond_the_message_size() {
+        let (mut client, log) = scripted(|req| vec![answer(req, ResponseCode::Content, vec![], b"")]);
+        client.set_max_message_size(32);
+        client.set_q_block(Some(QBlockParameters::default()));
+        let long = format!("coap://10.0.0.1/{}", "p".repeat(40));
+        let err = client.send_request(client.new_request(RequestMethod::Post, &long).unwrap()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(log.lock().unwrap().is_empty());
+        // an empty body is never split into blocks, even with no room for a
+        // payload marker; the token and Block2 leave no room at all here
+        let req = client.new_request(RequestMethod::Post, "coap://10.0.0.1/short").unwrap();
+        client.set_max_message_size(req.to_frame().to_bytes().len());
+        assert_eq!(client.send_request(req.clone()).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        client.set_max_message_size(req.to_frame().to_bytes().len() + 16);
+        let res = client.send_request(req).unwrap().unwrap();
+        assert_eq!(res.get_response_code(), ResponseCode::Content);
+    }
+
+    #[test]
+    fn block1_follows_the_size_the_server_asks_for() {
+        let (mut client, log) = scripted(|req| {
+            let block1 = block(req, OptionEnum::Block1).unwrap();
+            match (block1.num, block1.more) {
+                (0, _) => vec![answer(req, ResponseCode::Continue, vec![(OptionEnum::Block1, BlockValue::new(0, true, 32).to_value())], b"")],
+                (_, true) => vec![answer(req, ResponseCode::Continue, vec![(OptionEnum::Block1, block1.to_value())], b"")],
+                (_, false) => vec![answer(req, ResponseCode::Changed, vec![(OptionEnum::Block1, block1.to_value())], b"done")],
+            }
+        });
+        client.set_block1_size(64);
+        assert_eq!(client.post("coap://10.0.0.1/firmware", vec![0x55; 200]).unwrap().get_body(), b"done");
+
+        let log = log.lock().unwrap();
+        let blocks: Vec<_> = log.iter().map(|req| {
+            let block1 = block(req, OptionEnum::Block1).unwrap();
+            (block1.num, block1.size(), req.get_body().len())
+        }).collect();
+        // 64 bytes, then on in blocks of 32 from the same offset
+        assert_eq!(blocks, vec![(0, 64, 64), (2, 32, 32), (3, 32, 32), (4, 32, 32), (5, 32, 32), (6, 32, 8)]);
+        let tag = option(&log[0], OptionEnum::RequestTag);
+        assert!(log.iter().all(|req| option(req, OptionEnum::RequestTag) == tag));
+    }
+
+    #[test]
+    fn block2_is_collected() {
+        let (client, log) = scripted(|req| {
+            let num = block(req, OptionEnum::Block2).map_or(0, |block2| block2.num);
+            let block2 = BlockValue::new(num, num < 2, 16);
+            vec![answer(req, ResponseCode::Content, vec![(OptionEnum::Block2, block2.to_value())], &[num as u8; 16])]
+        });
+        let res = client.get("coap://10.0.0.1/log").unwrap();
+        assert_eq!(res.get_body(), &[[0u8; 16], [1; 16], [2; 16]].concat());
+        assert!(res.get_option(OptionEnum::Block2).is_none());
+        let asked: Vec<_> = log.lock().unwrap().iter().map(|req| block(req, OptionEnum::Block2).map(|block2| block2.num)).collect();
+        assert_eq!(asked, vec![None, Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn separate_responses_and_resets() {
+        let (client, log) = scripted(|req| {
+            if req.is_empty_message() {
+                return vec![];
+            }
+            let path = option(req, OptionEnum::UriPath).unwrap_or_default();
+            let msg_id = req.header.get_msg_id();
+            match &path[..] {
+                b"rejected" => vec![CoAPFrame::empty(MessageType::Rst, msg_id)],
+                _ => {
+                    // an empty ACK, the response follows as a CON of its own
+                    let mut header = Header::new(MessageType::Con.into(), ResponseCode::Content.into());
+                    header.set_msg_id(0x99);
+                    let mut separate = CoAPFrame::new(header, BTreeMap::new(), b"later".to_vec());
+                    separate.set_token(req.get_token()).unwrap();
+                    vec![CoAPFrame::empty(MessageType::Ack, msg_id), separate]
+                }
+            }
+        });
+        assert_eq!(client.get("coap://10.0.0.1/slow").unwrap().get_body(), b"later");
+        let deadline = Instant::now() + Duration::from_secs(1);
+        // the separate response is acknowledged
+        while !log.lock().unwrap().iter().any(|frame| frame.is_empty_message() && frame.header.get_msg_id() == 0x99) {
+            assert!(Instant::now() < deadline, "separate response not acknowledged");
+            thread::sleep(Duration::from_millis(1));
+        }
 
-        let data = &buf[..recv_len];
-        let buf = Vec::from(data);
-        Response::from(buf)
+        let req = client.new_request(RequestMethod::Get, "coap://10.0.0.1/rejected").unwrap();
+        assert_eq!(client.send_request(req).err().unwrap().kind(), io::ErrorKind::ConnectionReset);
     }
-}
\ No newline at end of file
+}
@@ -1,17 +1,23 @@
 use std::collections::BTreeMap;
+use std::fmt::Display;
 
-use crate::{common::{code_from, self}, error::InvalidResponseCode, frame::{MessageType, CoAPFrame, OptionEnum}};
+use crate::{common::{code_from, self}, error::InvalidFrame, frame::{Header, MessageType, CoAPFrame, OptionEnum}};
 
 /// response code
-#[derive(Debug)]
-enum ResponseCode {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCode {
     ///2.01
     Created,
     ///2.02
     Deleted,
+    ///2.03
     Valid,
+    ///2.04
     Changed,
+    ///2.05
     Content,
+    ///2.31
+    Continue,
 
     //4.xx
     BadRequest,
@@ -21,9 +27,13 @@ enum ResponseCode {
     NotFound,
     MethodNotAllowed,
     NotAcceptable,
+    RequestEntityIncomplete,
+    Conflict,
     PreconditionFailed,
     RequestEntityTooLarge,
     UnsupportedContentFormat,
+    UnprocessableEntity,
+    TooManyRequests,
 
     //5.xx
     InternalServerError,
@@ -31,74 +41,154 @@ enum ResponseCode {
     BadGateway,
     ServiceUnavailable,
     GatewayTimeout,
-    ProxyingNotSupported
+    ProxyingNotSupported,
+    HopLimitReached,
+
+    //7.xx signaling codes, only used over reliable transports (RFC 8323)
+    Csm,
+    Ping,
+    Pong,
+    Release,
+    Abort,
+
+    /// any code this crate does not know about, kept as the raw byte
+    Unknown(u8),
+}
+
+impl ResponseCode {
+
+    /// the `c` in `c.dd`
+    pub fn class(&self) -> u8 {
+        u8::from(self) >> 5
+    }
+
+    /// the `dd` in `c.dd`
+    pub fn detail(&self) -> u8 {
+        u8::from(self) & 0x1F
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.class() == 2
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        self.class() == 4
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        self.class() == 5
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.is_client_error() || self.is_server_error()
+    }
+
+    pub fn is_signaling(&self) -> bool {
+        self.class() == 7
+    }
 }
 
 impl From<&ResponseCode> for u8 {
     fn from(value: &ResponseCode) -> Self {
         match value {
-            ResponseCode::Created => code_from(2, 01),
-            ResponseCode::Deleted => code_from(2, 02),
-            ResponseCode::Valid => code_from(2, 03),
-            ResponseCode::Changed => code_from(2, 04),
-            ResponseCode::Content => code_from(2, 05),
-
-            ResponseCode::BadRequest => code_from(4, 00),
-            ResponseCode::Unauthorized => code_from(4, 01),
-            ResponseCode::BadOption => code_from(4, 02),
-            ResponseCode::Forbidden => code_from(4, 03),
-            ResponseCode::NotFound => code_from(4, 04),
-            ResponseCode::MethodNotAllowed => code_from(4, 05),
-            ResponseCode::NotAcceptable => code_from(4, 06),
+            ResponseCode::Created => code_from(2, 1),
+            ResponseCode::Deleted => code_from(2, 2),
+            ResponseCode::Valid => code_from(2, 3),
+            ResponseCode::Changed => code_from(2, 4),
+            ResponseCode::Content => code_from(2, 5),
+            ResponseCode::Continue => code_from(2, 31),
+
+            ResponseCode::BadRequest => code_from(4, 0),
+            ResponseCode::Unauthorized => code_from(4, 1),
+            ResponseCode::BadOption => code_from(4, 2),
+            ResponseCode::Forbidden => code_from(4, 3),
+            ResponseCode::NotFound => code_from(4, 4),
+            ResponseCode::MethodNotAllowed => code_from(4, 5),
+            ResponseCode::NotAcceptable => code_from(4, 6),
+            ResponseCode::RequestEntityIncomplete => code_from(4, 8),
+            ResponseCode::Conflict => code_from(4, 9),
             ResponseCode::PreconditionFailed => code_from(4, 12),
             ResponseCode::RequestEntityTooLarge => code_from(4, 13),
             ResponseCode::UnsupportedContentFormat => code_from(4, 15),
+            ResponseCode::UnprocessableEntity => code_from(4, 22),
+            ResponseCode::TooManyRequests => code_from(4, 29),
+
+            ResponseCode::InternalServerError => code_from(5, 0),
+            ResponseCode::NotImplemented => code_from(5, 1),
+            ResponseCode::BadGateway => code_from(5, 2),
+            ResponseCode::ServiceUnavailable => code_from(5, 3),
+            ResponseCode::GatewayTimeout => code_from(5, 4),
+            ResponseCode::ProxyingNotSupported => code_from(5, 5),
+            ResponseCode::HopLimitReached => code_from(5, 8),
 
-            ResponseCode::InternalServerError => code_from(5, 00),
-            ResponseCode::NotImplemented => code_from(5, 01),
-            ResponseCode::BadGateway => code_from(5, 02),
-            ResponseCode::ServiceUnavailable => code_from(5, 03),
-            ResponseCode::GatewayTimeout => code_from(5, 04),
-            ResponseCode::ProxyingNotSupported => code_from(5, 05)
+            ResponseCode::Csm => code_from(7, 1),
+            ResponseCode::Ping => code_from(7, 2),
+            ResponseCode::Pong => code_from(7, 3),
+            ResponseCode::Release => code_from(7, 4),
+            ResponseCode::Abort => code_from(7, 5),
+
+            ResponseCode::Unknown(code) => *code,
         }
     }
 }
 
-impl TryFrom<u8> for ResponseCode {
-    type Error = InvalidResponseCode;
+impl From<ResponseCode> for u8 {
+    fn from(value: ResponseCode) -> Self {
+        u8::from(&value)
+    }
+}
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
+impl From<u8> for ResponseCode {
+    fn from(value: u8) -> Self {
         match value {
-            0x41 => Ok(ResponseCode::Created),
-            0x42 => Ok(ResponseCode::Deleted),
-            0x43 => Ok(ResponseCode::Valid),
-            0x44 => Ok(ResponseCode::Changed),
-            0x45 => Ok(ResponseCode::Content),
-
-            0x80 => Ok(ResponseCode::BadRequest),
-            0x81 => Ok(ResponseCode::Unauthorized),
-            0x82 => Ok(ResponseCode::BadOption),
-            0x83 => Ok(ResponseCode::Forbidden),
-            0x84 => Ok(ResponseCode::NotFound),
-            0x85 => Ok(ResponseCode::MethodNotAllowed),
-            0x86 => Ok(ResponseCode::NotAcceptable),
-            0x8C => Ok(ResponseCode::PreconditionFailed),
-            0x8D => Ok(ResponseCode::UnsupportedContentFormat),
-            0x8F => Ok(ResponseCode::RequestEntityTooLarge),
-
-            0xA0 => Ok(ResponseCode::InternalServerError),
-            0xA1 => Ok(ResponseCode::NotImplemented),
-            0xA2 => Ok(ResponseCode::BadGateway),
-            0xA3 => Ok(ResponseCode::ServiceUnavailable),
-            0xA4 => Ok(ResponseCode::GatewayTimeout),
-            0xA5 => Ok(ResponseCode::ProxyingNotSupported),
-
-            _ => Err(InvalidResponseCode)
+            0x41 => ResponseCode::Created,
+            0x42 => ResponseCode::Deleted,
+            0x43 => ResponseCode::Valid,
+            0x44 => ResponseCode::Changed,
+            0x45 => ResponseCode::Content,
+            0x5F => ResponseCode::Continue,
+
+            0x80 => ResponseCode::BadRequest,
+            0x81 => ResponseCode::Unauthorized,
+            0x82 => ResponseCode::BadOption,
+            0x83 => ResponseCode::Forbidden,
+            0x84 => ResponseCode::NotFound,
+            0x85 => ResponseCode::MethodNotAllowed,
+            0x86 => ResponseCode::NotAcceptable,
+            0x88 => ResponseCode::RequestEntityIncomplete,
+            0x89 => ResponseCode::Conflict,
+            0x8C => ResponseCode::PreconditionFailed,
+            0x8D => ResponseCode::RequestEntityTooLarge,
+            0x8F => ResponseCode::UnsupportedContentFormat,
+            0x96 => ResponseCode::UnprocessableEntity,
+            0x9D => ResponseCode::TooManyRequests,
+
+            0xA0 => ResponseCode::InternalServerError,
+            0xA1 => ResponseCode::NotImplemented,
+            0xA2 => ResponseCode::BadGateway,
+            0xA3 => ResponseCode::ServiceUnavailable,
+            0xA4 => ResponseCode::GatewayTimeout,
+            0xA5 => ResponseCode::ProxyingNotSupported,
+            0xA8 => ResponseCode::HopLimitReached,
+
+            0xE1 => ResponseCode::Csm,
+            0xE2 => ResponseCode::Ping,
+            0xE3 => ResponseCode::Pong,
+            0xE4 => ResponseCode::Release,
+            0xE5 => ResponseCode::Abort,
+
+            _ => ResponseCode::Unknown(value)
         }
     }
 }
 
-#[derive(Debug)]
+impl Display for ResponseCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", common::to_code_str(u8::from(self)))
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Response {
     message_type: MessageType,
     code: ResponseCode,
@@ -107,7 +197,44 @@ pub struct Response {
 }
 
 impl Response {
-    
+
+    pub fn new(code: ResponseCode) -> Response {
+        Response {
+            message_type: MessageType::Ack,
+            code,
+            options: BTreeMap::new(),
+            body: vec![],
+        }
+    }
+
+    pub fn set_type(&mut self, message_type: MessageType) {
+        self.message_type = message_type;
+    }
+
+    pub fn set_code(&mut self, code: ResponseCode) {
+        self.code = code;
+    }
+
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+    }
+
+    pub fn set_option(&mut self, number: OptionEnum, value: Vec<u8>) {
+        self.options.insert(u16::from(number), vec![value]);
+    }
+
+    pub fn add_option(&mut self, number: OptionEnum, value: Vec<u8>) {
+        self.options.entry(u16::from(number)).or_default().push(value);
+    }
+
+    pub fn get_option(&self, number: OptionEnum) -> Option<&Vec<Vec<u8>>> {
+        self.options.get(&u16::from(number))
+    }
+
+    pub fn remove_option(&mut self, number: OptionEnum) {
+        self.options.remove(&u16::from(number));
+    }
+
     pub fn get_type(&self) -> u8 {
         self.message_type.into()
     }
@@ -116,6 +243,10 @@ impl Response {
         u8::from(&self.code)
     }
 
+    pub fn get_response_code(&self) -> ResponseCode {
+        self.code
+    }
+
     pub fn get_code_str(&self) -> String {
         common::to_code_str(u8::from(&self.code))
     }
@@ -127,19 +258,61 @@ impl Response {
     pub fn get_options(&self) -> BTreeMap<OptionEnum, &Vec<Vec<u8>>> {
         let mut options = BTreeMap::new();
         for ele in &self.options {
-            let number = OptionEnum::try_from(*ele.0).expect("parse option number error");
+            let number = OptionEnum::from(*ele.0);
             options.insert(number, ele.1);
         }
         options
     }
 
-    pub fn from(buf: Vec<u8>) -> Response {
-        let frame = CoAPFrame::from_bytes(buf);
+    pub fn from(buf: Vec<u8>) -> Result<Response, InvalidFrame> {
+        Ok(Response::from_frame(&CoAPFrame::from_bytes(buf)?))
+    }
+
+    pub fn from_frame(frame: &CoAPFrame) -> Response {
         Response {
             message_type: frame.header.get_type().try_into().unwrap(),
-            code: frame.header.get_code().try_into().unwrap(),
+            code: frame.header.get_code().into(),
             options: frame.get_options(),
             body: frame.get_body()
         }
     }
-}
\ No newline at end of file
+
+    /// encode as a reply carrying the given message id and the request's token
+    pub fn to_frame(&self, msg_id: u16, token: Vec<u8>) -> Result<CoAPFrame, InvalidFrame> {
+        let mut header = Header::new(self.message_type.into(), u8::from(&self.code));
+        header.set_msg_id(msg_id);
+        let mut frame = CoAPFrame::new(header, self.options.clone(), self.body.clone());
+        frame.set_token(token)?;
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{common::code_from, response::ResponseCode};
+
+    #[test]
+    fn response_code_round_trip() {
+        for code in 0..=u8::MAX {
+            assert_eq!(u8::from(ResponseCode::from(code)), code);
+        }
+        assert_eq!(ResponseCode::from(code_from(4, 13)), ResponseCode::RequestEntityTooLarge);
+        assert_eq!(ResponseCode::from(code_from(4, 15)), ResponseCode::UnsupportedContentFormat);
+        assert_eq!(ResponseCode::from(code_from(2, 31)), ResponseCode::Continue);
+        assert_eq!(ResponseCode::from(code_from(4, 29)), ResponseCode::TooManyRequests);
+        assert_eq!(ResponseCode::from(code_from(4, 30)), ResponseCode::Unknown(0x9E));
+    }
+
+    #[test]
+    fn response_code_class() {
+        let code = ResponseCode::ProxyingNotSupported;
+        assert_eq!(code.class(), 5);
+        assert_eq!(code.detail(), 5);
+        assert!(code.is_server_error());
+        assert!(!code.is_client_error());
+        assert!(ResponseCode::Continue.is_success());
+        assert!(ResponseCode::Pong.is_signaling());
+        assert!(ResponseCode::Unknown(0x9E).is_client_error());
+        assert_eq!(ResponseCode::RequestEntityIncomplete.to_string(), "4.08");
+    }
+}
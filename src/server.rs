@@ -0,0 +1,1200 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{mpsc::{self, Receiver, Sender}, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::{
+    block::{block_size_for, BlockValue, MAX_BODY_SIZE, MAX_PEER_TRANSFERS},
+    clock::{Clock, SystemClock},
+    common::uint_to_bytes,
+    conditional,
+    echo::EchoChallenges,
+    endpoint::{Endpoint, Event, Transmit},
+    frame::{MessageType, OptionEnum},
+    middleware::{Middleware, Next},
+    proxy::ForwardProxy,
+    q_block::{self, QBlockBody, QBlockParameters},
+    request::{Request, RequestMethod},
+    response::{Response, ResponseCode},
+    transmission::{RetransmissionPolicy, TransmissionParameters, EXCHANGE_LIFETIME},
+    transport::{Transport, UdpTransport},
+};
+
+/// ETag of the current representation of the resource a request targets
+type EtagProvider = Box<dyn Fn(&Request) -> Option<Vec<u8>> + Send>;
+
+/// a block-wise body is matched on peer, path, query and Request-Tag (RFC 9175 section 3)
+type BodyKey = (SocketAddr, String, Vec<Vec<u8>>, Option<Vec<u8>>);
+
+struct Block1Transfer {
+    body: Vec<u8>,
+    expires: Instant,
+}
+
+struct QBlock1Transfer {
+    body: QBlockBody,
+    expires: Instant,
+}
+
+/// proxied requests forwarded at the same time, more are answered 5.03
+pub const MAX_FORWARDS: usize = 16;
+
+/// how often the run loop looks for forwarded responses while any are out
+const FORWARD_POLL: Duration = Duration::from_millis(10);
+
+/// response of the origin to a proxied request, with the Block1 or
+/// Q-Block1 option the request was assembled from
+struct Forwarded {
+    request: Request,
+    response: Response,
+    block1: Option<BlockValue>,
+    q_block1: Option<BlockValue>,
+}
+
+/// a response cut into blocks, kept so later blocks come from the same body
+struct Block2Transfer {
+    response: Response,
+    expires: Instant,
+}
+
+/// CoAP server: the message layer is an `Endpoint` accepting requests, on
+/// top of it sit Echo freshness, block-wise transfers, the forward proxy,
+/// the middleware and the handler
+pub struct CoapServer<T: Transport = UdpTransport> {
+    transport: T,
+    endpoint: Endpoint,
+    clock: Box<dyn Clock>,
+    rng: Box<dyn RngCore + Send>,
+    max_body_size: usize,
+    echo: Option<EchoChallenges>,
+    block1: HashMap<BodyKey, Block1Transfer>,
+    q_block: Option<QBlockParameters>,
+    q_block1: HashMap<BodyKey, QBlock1Transfer>,
+    block2: HashMap<BodyKey, Block2Transfer>,
+    proxy: Option<Arc<ForwardProxy>>,
+    /// proxied requests whose response is not back yet
+    forwarding: usize,
+    forwarded: (Sender<Forwarded>, Receiver<Forwarded>),
+    current_etag: Option<EtagProvider>,
+    auto_etag: bool,
+    middleware: Vec<Box<dyn Middleware>>,
+    /// datagrams `run` could not hand to the transport
+    unsent: u64,
+}
+
+impl CoapServer {
+
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<CoapServer> {
+        Ok(CoapServer::with_transport(UdpTransport::bind(addr)?))
+    }
+}
+
+impl<T: Transport> CoapServer<T> {
+
+    /// server answering requests that arrive over `transport`
+    pub fn with_transport(transport: T) -> CoapServer<T> {
+        let mut endpoint = Endpoint::new();
+        endpoint.set_accept_requests(true);
+        CoapServer {
+            transport,
+            endpoint,
+            clock: Box::new(SystemClock),
+            rng: Box::new(StdRng::from_entropy()),
+            max_body_size: MAX_BODY_SIZE,
+            echo: None,
+            block1: HashMap::new(),
+            q_block: None,
+            q_block1: HashMap::new(),
+            block2: HashMap::new(),
+            proxy: None,
+            forwarding: 0,
+            forwarded: mpsc::channel(),
+            current_etag: None,
+            auto_etag: false,
+            middleware: Vec::new(),
+            unsent: 0,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    /// how many datagrams `run` failed to send, they are dropped and a CON
+    /// is left to its retransmissions
+    pub fn get_unsent(&self) -> u64 {
+        self.unsent
+    }
+
+    /// time source for deduplication, Echo freshness and block-wise transfer lifetimes
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// ACK_TIMEOUT, ACK_RANDOM_FACTOR and MAX_RETRANSMIT for what the server
+    /// sends as CON
+    pub fn set_transmission_parameters(&mut self, params: TransmissionParameters) {
+        self.endpoint.set_transmission_parameters(params);
+    }
+
+    /// fixed retransmission timeouts (RFC 7252) or CoCoA (RFC 9006)
+    /// estimating them per client from measured round trips
+    pub fn set_retransmission_policy(&mut self, policy: RetransmissionPolicy) {
+        self.endpoint.set_retransmission_policy(policy);
+    }
+
+    /// source of message ids and Echo values, a seeded one makes them reproducible
+    pub fn set_rng<R: RngCore + Send + 'static>(&mut self, mut rng: R) {
+        self.endpoint.set_rng(StdRng::seed_from_u64(rng.next_u64()));
+        self.rng = Box::new(rng);
+    }
+
+    /// longest token accepted in requests, 8 unless extended tokens (RFC 8974) are enabled
+    pub fn set_max_token_length(&mut self, len: usize) {
+        self.endpoint.set_max_token_length(len);
+    }
+
+    pub fn get_max_token_length(&self) -> usize {
+        self.endpoint.get_max_token_length()
+    }
+
+    /// largest message received or sent, 1152 bytes by default; larger
+    /// requests are answered 4.13 and larger responses sent block-wise
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.endpoint.set_max_message_size(size);
+    }
+
+    pub fn get_max_message_size(&self) -> usize {
+        self.endpoint.get_max_message_size()
+    }
+
+    /// most bytes of request bodies reassembled from blocks for one peer at
+    /// a time, 1 MiB by default; a block going beyond is answered 4.13 with
+    /// Size1; besides at most MAX_PEER_TRANSFERS block-wise transfers are
+    /// kept per peer, a new one replaces the one idle the longest
+    pub fn set_max_body_size(&mut self, size: usize) {
+        self.max_body_size = size;
+    }
+
+    pub fn get_max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+
+    /// require a fresh Echo (RFC 9175) on every request with an unsafe
+    /// method, that is all but GET and FETCH; requests without one are
+    /// answered 4.01 Unauthorized carrying a challenge
+    pub fn set_echo_freshness(&mut self, freshness: Option<Duration>) {
+        self.echo = freshness.map(EchoChallenges::new);
+    }
+
+    /// accept Q-Block1/Q-Block2 (RFC 9177), without it requests carrying
+    /// them are answered 4.02 Bad Option so clients fall back to RFC 7959
+    pub fn set_q_block(&mut self, params: Option<QBlockParameters>) {
+        self.q_block = params;
+    }
+
+    /// forward requests carrying Proxy-Uri/Proxy-Scheme instead of passing
+    /// them to the handler, without a proxy they get 5.05 Proxying Not Supported
+    ///
+    /// a CON is acknowledged at once and forwarded on a thread of its own,
+    /// so the server keeps answering meanwhile; the origin's response goes
+    /// back as a separate CON (RFC 7252 section 5.2.2), past the middleware
+    /// like any other response
+    pub fn set_forward_proxy(&mut self, proxy: Option<ForwardProxy>) {
+        self.proxy = proxy.map(Arc::new);
+    }
+
+    /// look up the current ETag of the target resource, None when it does not
+    /// exist, so If-Match/If-None-Match are answered 4.12 Precondition Failed
+    /// before the handler runs
+    pub fn set_etag_provider<P>(&mut self, provider: P)
+    where
+        P: Fn(&Request) -> Option<Vec<u8>> + Send + 'static,
+    {
+        self.current_etag = Some(Box::new(provider));
+    }
+
+    /// give 2.05 responses without an ETag one computed from the payload
+    pub fn set_auto_etag(&mut self, enabled: bool) {
+        self.auto_etag = enabled;
+    }
+
+    /// wrap the handler in `middleware`, the first one added runs outermost;
+    /// its `pre_dispatch` sees every request message before Echo and
+    /// block-wise reassembly, its `handle` each complete request once and the
+    /// whole response before it is cut into blocks
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// receive and answer requests until the transport fails, a reply
+    /// that cannot be sent is dropped and counted in `get_unsent`
+    pub fn run<F>(&mut self, handler: F) -> io::Result<()>
+    where
+        F: Fn(&Request) -> Response,
+    {
+        // one byte more than fits, to tell a truncated datagram from a full one
+        let mut buf = vec![0u8; self.get_max_message_size() + 1];
+        loop {
+            while let Some(transmit) = self.poll_transmit() {
+                if self.transport.send_to(&transmit.data, transmit.peer).is_err() {
+                    self.unsent += 1;
+                }
+            }
+            let now = self.clock.now();
+            let timeout = self.poll_timeout()
+                .map(|next| next.saturating_duration_since(now).max(Duration::from_millis(1)));
+            match self.transport.recv_from(&mut buf, timeout) {
+                Ok((recv_len, peer)) => self.handle_datagram(peer, &buf[..recv_len], &handler),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => self.handle_timeout(),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// feed a datagram from `peer` to the server, requests in it are
+    /// answered with `handler`; `run` does this for every datagram the
+    /// transport receives, a simulation or another event loop can call it
+    /// directly and send what `poll_transmit` returns
+    pub fn handle_datagram<F>(&mut self, peer: SocketAddr, data: &[u8], handler: &F)
+    where
+        F: Fn(&Request) -> Response,
+    {
+        let now = self.clock.now();
+        self.endpoint.handle_datagram(peer, data, now);
+        while let Some(event) = self.endpoint.poll_event() {
+            if let Event::Request { message_id, request } = event {
+                self.answer(request, message_id, handler, now);
+            }
+        }
+    }
+
+    /// retransmit what is due and send the responses of forwarded
+    /// requests that came back, see `poll_timeout`
+    pub fn handle_timeout(&mut self) {
+        self.finish_forwards();
+        self.endpoint.handle_timeout(self.clock.now());
+        // nothing the server sent waits for an answer it cares about
+        while self.endpoint.poll_event().is_some() {}
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.endpoint.poll_transmit()
+    }
+
+    /// when `handle_timeout` has to be called next, None while idle
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let forwards = (self.forwarding > 0).then(|| self.clock.now() + FORWARD_POLL);
+        self.endpoint.poll_timeout().into_iter().chain(forwards).min()
+    }
+
+    /// run the request layer and hand its responses to the endpoint, the
+    /// first on the ACK of a CON and any further ones as NON
+    fn answer<F>(&mut self, request: Request, message_id: u16, handler: &F, now: Instant)
+    where
+        F: Fn(&Request) -> Response,
+    {
+        let Some(peer) = request.get_peer() else {
+            return;
+        };
+        let no_response = request.get_no_response();
+        let mut responses = self.respond(request.clone(), peer, handler).into_iter();
+
+        // nothing to say yet, or the client is not interested in this class
+        // of response (RFC 7967), a CON still has to be acknowledged
+        let response = responses.next()
+            .filter(|response| !no_response.is_some_and(|nr| nr.suppresses(response.get_response_code())));
+        let Some(response) = response else {
+            self.endpoint.acknowledge(&request, message_id);
+            return;
+        };
+        if self.endpoint.respond(&request, message_id, &response, now).is_err() {
+            return;
+        }
+        for response in responses {
+            if self.endpoint.respond_separately(&request, &response, now).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// request layer: freshness check, Block1/Q-Block1 reassembly, the
+    /// middleware and handler, then Block2/Q-Block2 slicing of its response; an empty list
+    /// means nothing is sent back yet
+    fn respond<F>(&mut self, mut request: Request, peer: SocketAddr, handler: &F) -> Vec<Response>
+    where
+        F: Fn(&Request) -> Response,
+    {
+        let now = self.clock.now();
+        if let Some(response) = self.middleware.iter().find_map(|middleware| middleware.pre_dispatch(&request)) {
+            return vec![response];
+        }
+        if let Some(echo) = &mut self.echo {
+            let fresh = request.get_option(OptionEnum::Echo)
+                .and_then(|v| v.first())
+                .is_some_and(|v| echo.verify(peer, v, now));
+            if !request.get_method().is_safe() && !fresh {
+                let mut response = Response::new(ResponseCode::Unauthorized);
+                response.set_option(OptionEnum::Echo, echo.issue_with(peer, now, &mut *self.rng));
+                return vec![response];
+            }
+        }
+
+        // unrecognized critical options, RFC 7252 section 5.4.1
+        let q_block_options = request.get_option(OptionEnum::QBlock1).is_some() || request.get_option(OptionEnum::QBlock2).is_some();
+        let unknown_critical = request.get_options().keys().any(|number| {
+            matches!(number, OptionEnum::Unknown(n) if n & 1 == 1)
+        });
+        if unknown_critical || (q_block_options && self.q_block.is_none()) {
+            return vec![Response::new(ResponseCode::BadOption)];
+        }
+
+        let block1 = first_block(request.get_option(OptionEnum::Block1));
+        let q_block1 = first_block(request.get_option(OptionEnum::QBlock1));
+        let assembled = match (q_block1, block1) {
+            (Some(block), _) => self.assemble_q_block1(&mut request, peer, block, now),
+            (None, Some(block)) => self.assemble_block1(&mut request, peer, block, now),
+            (None, None) => Ok(()),
+        };
+        if let Err(response) = assembled {
+            return response.into_iter().collect();
+        }
+
+        self.block2.retain(|_, transfer| transfer.expires > now);
+        let mut key = body_key(&request, peer);
+        key.3 = None;
+        let response = match self.block2.get(&key) {
+            Some(transfer) if is_block2_continuation(&request) => transfer.response.clone(),
+            _ if self.proxy.is_some() && ForwardProxy::is_proxy_request(&request) => {
+                return self.forward(request, block1, q_block1);
+            }
+            _ => {
+                let inner = |request: &Request| match &self.proxy {
+                    None if ForwardProxy::is_proxy_request(request) => Response::new(ResponseCode::ProxyingNotSupported),
+                    _ if !self.preconditions_hold(request) => Response::new(ResponseCode::PreconditionFailed),
+                    _ => self.handle_validated(request, handler),
+                };
+                self.dispatch(&mut request, &inner, block1, q_block1)
+            }
+        };
+        self.blocks_of(&request, peer, response, now)
+    }
+
+    /// run the middleware around `inner`, echoing the Block1 or Q-Block1
+    /// option of the request on its response
+    fn dispatch(&self, request: &mut Request, inner: &dyn Fn(&Request) -> Response, block1: Option<BlockValue>, q_block1: Option<BlockValue>) -> Response {
+        let mut response = Next::new(&self.middleware, inner).run(request);
+        if let Some(block) = q_block1 {
+            response.set_option(OptionEnum::QBlock1, block.to_value());
+        } else if let Some(block) = block1 {
+            response.set_option(OptionEnum::Block1, block.to_value());
+        }
+        response
+    }
+
+    /// hand a proxied request to a thread of its own, nothing is sent back
+    /// but the ACK until its response is in
+    fn forward(&mut self, request: Request, block1: Option<BlockValue>, q_block1: Option<BlockValue>) -> Vec<Response> {
+        let Some(proxy) = self.proxy.clone() else {
+            return vec![Response::new(ResponseCode::ProxyingNotSupported)];
+        };
+        if self.forwarding >= MAX_FORWARDS {
+            let mut busy = Response::new(ResponseCode::ServiceUnavailable);
+            // worth trying again in a second (RFC 7252 section 5.9.3.4)
+            busy.set_option(OptionEnum::MaxAge, uint_to_bytes(1));
+            return vec![busy];
+        }
+        self.forwarding += 1;
+        let sender = self.forwarded.0.clone();
+        thread::spawn(move || {
+            let response = proxy.forward(&request);
+            let _ = sender.send(Forwarded { request, response, block1, q_block1 });
+        });
+        vec![]
+    }
+
+    /// send the responses of forwarded requests that are back, separately
+    /// as their requests were acknowledged already
+    fn finish_forwards(&mut self) {
+        let now = self.clock.now();
+        while let Ok(Forwarded { mut request, response, block1, q_block1 }) = self.forwarded.1.try_recv() {
+            self.forwarding -= 1;
+            let Some(peer) = request.get_peer() else {
+                continue;
+            };
+            let inner = |_: &Request| response.clone();
+            let response = self.dispatch(&mut request, &inner, block1, q_block1);
+            let no_response = request.get_no_response();
+            let mut responses = self.blocks_of(&request, peer, response, now).into_iter();
+            let Some(first) = responses.next()
+                .filter(|response| !no_response.is_some_and(|nr| nr.suppresses(response.get_response_code()))) else {
+                continue;
+            };
+            let sent = match request.get_type() {
+                MessageType::Con => self.endpoint.respond_confirmable(&request, &first, now).map(|_| ()),
+                _ => self.endpoint.respond_separately(&request, &first, now),
+            };
+            if sent.is_err() {
+                continue;
+            }
+            for response in responses {
+                if self.endpoint.respond_separately(&request, &response, now).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// cut `response` into the blocks the request asks for, keeping the
+    /// whole of it for the later ones
+    fn blocks_of(&mut self, request: &Request, peer: SocketAddr, response: Response, now: Instant) -> Vec<Response> {
+        let mut key = body_key(request, peer);
+        key.3 = None;
+        let responses = self.slice_response(request, &response);
+        if responses.first().is_some_and(|first| first.get_body().len() < response.get_body().len()) {
+            if !self.block2.contains_key(&key) {
+                self.make_room(peer);
+            }
+            self.block2.insert(key, Block2Transfer { response, expires: now + EXCHANGE_LIFETIME });
+        }
+        responses
+    }
+
+    /// run the handler, answering a GET whose ETag options name the current
+    /// representation with 2.03 Valid and no payload (RFC 7252 section 5.10.6.2)
+    fn handle_validated<F>(&self, request: &Request, handler: &F) -> Response
+    where
+        F: Fn(&Request) -> Response,
+    {
+        let requested = match request.get_method() {
+            RequestMethod::Get => request.get_option(OptionEnum::ETag).cloned().unwrap_or_default(),
+            _ => vec![],
+        };
+        // the provider can tell without running the handler at all
+        if !requested.is_empty() {
+            if let Some(current) = self.current_etag.as_ref().and_then(|current_etag| current_etag(request)) {
+                if requested.contains(&current) {
+                    let mut valid = Response::new(ResponseCode::Valid);
+                    valid.set_option(OptionEnum::ETag, current);
+                    return valid;
+                }
+            }
+        }
+
+        let mut response = handler(request);
+        if response.get_response_code() != ResponseCode::Content {
+            return response;
+        }
+        if self.auto_etag && response.get_option(OptionEnum::ETag).is_none() {
+            response.set_option(OptionEnum::ETag, conditional::payload_etag(response.get_body()));
+        }
+        let etag = response.get_option(OptionEnum::ETag).and_then(|v| v.first());
+        match etag {
+            Some(etag) if requested.contains(etag) => {
+                let mut valid = Response::new(ResponseCode::Valid);
+                valid.set_option(OptionEnum::ETag, etag.clone());
+                if let Some(max_age) = response.get_option(OptionEnum::MaxAge).and_then(|v| v.first()) {
+                    valid.set_option(OptionEnum::MaxAge, max_age.clone());
+                }
+                valid
+            }
+            _ => response,
+        }
+    }
+
+    /// without an ETag provider preconditions are left to the handler
+    fn preconditions_hold(&self, request: &Request) -> bool {
+        match &self.current_etag {
+            Some(current_etag) if conditional::has_preconditions(request) => {
+                conditional::preconditions_hold(request, current_etag(request).as_deref())
+            }
+            _ => true,
+        }
+    }
+
+    /// request body bytes held for `peer` in unfinished transfers
+    fn peer_body_size(&self, peer: SocketAddr) -> usize {
+        let block1 = self.block1.iter().filter(|(key, _)| key.0 == peer).map(|(_, transfer)| transfer.body.len());
+        let q_block1 = self.q_block1.iter().filter(|(key, _)| key.0 == peer).map(|(_, transfer)| transfer.body.len());
+        block1.chain(q_block1).sum()
+    }
+
+    /// drop the transfers of `peer` idle the longest until a new one fits
+    /// within MAX_PEER_TRANSFERS
+    fn make_room(&mut self, peer: SocketAddr) {
+        let mut transfers: Vec<(Instant, BodyKey)> = self.block1.iter().map(|(key, transfer)| (transfer.expires, key))
+            .chain(self.q_block1.iter().map(|(key, transfer)| (transfer.expires, key)))
+            .chain(self.block2.iter().map(|(key, transfer)| (transfer.expires, key)))
+            .filter(|(_, key)| key.0 == peer)
+            .map(|(expires, key)| (expires, key.clone()))
+            .collect();
+        if transfers.len() < MAX_PEER_TRANSFERS {
+            return;
+        }
+        let excess = transfers.len() + 1 - MAX_PEER_TRANSFERS;
+        transfers.sort_by_key(|(expires, _)| *expires);
+        for (_, key) in transfers.into_iter().take(excess) {
+            self.block1.remove(&key);
+            self.q_block1.remove(&key);
+            self.block2.remove(&key);
+        }
+    }
+
+    /// Block1 stop-and-wait reassembly, RFC 7959 section 2.5
+    fn assemble_block1(&mut self, request: &mut Request, peer: SocketAddr, block: BlockValue, now: Instant) -> Result<(), Option<Response>> {
+        let key = body_key(request, peer);
+        self.block1.retain(|_, transfer| transfer.expires > now);
+        if block.num == 0 {
+            if self.block1.remove(&key).is_none() {
+                self.make_room(peer);
+            }
+            self.block1.insert(key.clone(), Block1Transfer { body: vec![], expires: now + EXCHANGE_LIFETIME });
+        }
+        if self.peer_body_size(peer) + request.get_body().len() > self.max_body_size {
+            self.block1.remove(&key);
+            return Err(Some(self.body_too_large()));
+        }
+        let transfer = match self.block1.get_mut(&key) {
+            Some(transfer) if transfer.body.len() == block.offset() => transfer,
+            // a block is missing or belongs to a body we never saw the start of
+            _ => {
+                self.block1.remove(&key);
+                return Err(Some(Response::new(ResponseCode::RequestEntityIncomplete)));
+            }
+        };
+        transfer.body.extend_from_slice(request.get_body());
+        transfer.expires = now + EXCHANGE_LIFETIME;
+
+        if block.more {
+            let mut response = Response::new(ResponseCode::Continue);
+            response.set_option(OptionEnum::Block1, block.to_value());
+            return Err(Some(response));
+        }
+        let transfer = self.block1.remove(&key).unwrap();
+        request.set_body(transfer.body);
+        Ok(())
+    }
+
+    /// Q-Block1 reassembly, RFC 9177 section 4.3: blocks may arrive out of
+    /// order, the end of each set of max_payloads blocks is answered 2.31, or
+    /// 4.08 listing the blocks still missing
+    fn assemble_q_block1(&mut self, request: &mut Request, peer: SocketAddr, block: BlockValue, now: Instant) -> Result<(), Option<Response>> {
+        let max_payloads = self.q_block.map_or(q_block::MAX_PAYLOADS, |params| params.max_payloads) as u32;
+        let key = body_key(request, peer);
+        self.q_block1.retain(|_, transfer| transfer.expires > now);
+        let held = self.peer_body_size(peer);
+        if block.offset() + request.get_body().len() > self.max_body_size || held + request.get_body().len() > self.max_body_size {
+            self.q_block1.remove(&key);
+            return Err(Some(self.body_too_large()));
+        }
+        let highest = self.q_block1.get(&key).and_then(|transfer| transfer.body.highest());
+        // at most one burst past what arrived so far, anything further is
+        // not from a peer following RFC 9177 and would only inflate the
+        // list of missing blocks
+        if block.num >= highest.map_or(0, |highest| highest + 1).saturating_add(max_payloads) {
+            return Err(None);
+        }
+        if !self.q_block1.contains_key(&key) {
+            self.make_room(peer);
+        }
+        let transfer = self.q_block1.entry(key.clone()).or_insert_with(|| QBlock1Transfer {
+            body: QBlockBody::default(),
+            expires: now,
+        });
+        // a block below the highest seen so far fills a gap
+        let repair = highest.is_some_and(|highest| block.num < highest);
+        if !transfer.body.insert(block.num, block.more, request.get_body().clone()) {
+            if transfer.body.is_empty() {
+                self.q_block1.remove(&key);
+            }
+            return Err(None);
+        }
+        transfer.expires = now + EXCHANGE_LIFETIME;
+
+        if transfer.body.is_complete() {
+            let transfer = self.q_block1.remove(&key).unwrap();
+            request.set_body(transfer.body.assemble());
+            return Ok(());
+        }
+        let set_end = !block.more || (block.num + 1).is_multiple_of(max_payloads);
+        if !set_end && !repair {
+            return Err(None);
+        }
+        if transfer.body.missing_count() == 0 {
+            let mut response = Response::new(ResponseCode::Continue);
+            response.set_option(OptionEnum::QBlock1, block.to_value());
+            return Err(Some(response));
+        }
+        if repair {
+            return Err(None);
+        }
+        let mut response = Response::new(ResponseCode::RequestEntityIncomplete);
+        response.set_option(OptionEnum::ContentFormat, uint_to_bytes(q_block::MISSING_BLOCKS_CBOR_SEQ as u32));
+        // as much of the list as fits one message
+        let header_len = response.to_frame(0, request.get_token().cloned().unwrap_or_default())
+            .map_or(self.endpoint.get_max_message_size(), |frame| frame.to_bytes().len());
+        let room = self.endpoint.get_max_message_size().saturating_sub(header_len + 1);
+        response.set_body(q_block::encode_missing_within(transfer.body.missing(), room));
+        Err(Some(response))
+    }
+
+    /// 4.13 telling the peer the largest body accepted (RFC 7959 section 2.9.3)
+    fn body_too_large(&self) -> Response {
+        let mut response = Response::new(ResponseCode::RequestEntityTooLarge);
+        response.set_option(OptionEnum::Size1, uint_to_bytes(self.max_body_size.min(u32::MAX as usize) as u32));
+        response
+    }
+
+    /// cut a response body that does not fit one block into the blocks the
+    /// request asked for, with Q-Block2 a whole set of them
+    fn slice_response(&self, request: &Request, response: &Response) -> Vec<Response> {
+        let q_block2: Vec<BlockValue> = request.get_option(OptionEnum::QBlock2)
+            .map(|values| values.iter().filter_map(|v| BlockValue::from_value(v)).collect())
+            .unwrap_or_default();
+        let block2 = first_block(request.get_option(OptionEnum::Block2));
+        // blocks are made smaller than asked for when they would not fit a message
+        let header_len = {
+            let mut bare = response.clone();
+            bare.set_body(vec![]);
+            bare.to_frame(0, request.get_token().cloned().unwrap_or_default())
+                .map_or(self.get_max_message_size(), |frame| frame.to_bytes().len())
+        };
+        let fitting = block_size_for(self.get_max_message_size(), header_len);
+        let size = q_block2.first().or(block2.as_ref()).map_or(fitting, |block| block.size().min(fitting));
+        let body = response.get_body();
+        if body.len() <= size && block2.is_none_or(|block| block.num == 0) {
+            return vec![response.clone()];
+        }
+        let last = (body.len().max(1) - 1) / size;
+        let block_response = |num: usize, option: OptionEnum| {
+            let offset = (num * size).min(body.len());
+            let mut block = response.clone();
+            block.set_body(body[offset..(offset + size).min(body.len())].to_vec());
+            block.set_option(option, BlockValue::new(num as u32, num < last, size).to_value());
+            block
+        };
+
+        if q_block2.is_empty() {
+            let num = block2.map_or(0, |block| block.num as usize);
+            return vec![block_response(num.min(last), OptionEnum::Block2)];
+        }
+        // M set asks for the set starting at that block, a single block 0 is
+        // the initial request, anything else lists missing blocks
+        let max_payloads = self.q_block.map_or(q_block::MAX_PAYLOADS, |params| params.max_payloads);
+        let nums: Vec<usize> = match q_block2.as_slice() {
+            [block] if block.more || block.num == 0 => {
+                let first = block.num as usize;
+                (first..(first + max_payloads).min(last + 1)).collect()
+            }
+            blocks => blocks.iter().map(|block| block.num as usize).filter(|num| *num <= last).collect(),
+        };
+        nums.into_iter().map(|num| block_response(num, OptionEnum::QBlock2)).collect()
+    }
+}
+
+/// asks for a later part of a response cut into blocks
+fn is_block2_continuation(request: &Request) -> bool {
+    let initial = BlockValue { num: 0, more: false, szx: 0 };
+    let q_block2 = request.get_option(OptionEnum::QBlock2).map_or(&[][..], |values| values.as_slice());
+    first_block(request.get_option(OptionEnum::Block2)).is_some_and(|block| block.num > 0)
+        || match q_block2 {
+            [] => false,
+            [value] => BlockValue::from_value(value).is_some_and(|block| BlockValue { szx: 0, ..block } != initial),
+            _ => true,
+        }
+}
+
+fn first_block(values: Option<&Vec<Vec<u8>>>) -> Option<BlockValue> {
+    values.and_then(|v| v.first()).and_then(|v| BlockValue::from_value(v))
+}
+
+fn body_key(request: &Request, peer: SocketAddr) -> BodyKey {
+    // proxied requests for different targets all have an empty path
+    let target = request.get_option(OptionEnum::ProxyUri)
+        .and_then(|v| v.first())
+        .map(|v| String::from_utf8_lossy(v).into_owned());
+    (
+        peer,
+        target.unwrap_or_else(|| request.get_path()),
+        request.get_option(OptionEnum::UriQuery).cloned().unwrap_or_default(),
+        request.get_option(OptionEnum::RequestTag).and_then(|v| v.first()).cloned(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, collections::BTreeMap, io, net::SocketAddr, sync::{atomic::{AtomicUsize, Ordering}, mpsc, Arc, Mutex}, thread, time::{Duration, Instant}};
+
+    use url::Url;
+
+    use crate::{
+        block::BlockValue,
+        clock::ManualClock,
+        common::uint_to_bytes,
+        frame::{CoAPFrame, Header, MessageType, OptionEnum},
+        http::{HttpConnector, HttpRequest, HttpResponse},
+        middleware::{Middleware, Next, RateLimit},
+        no_response::NoResponse,
+        proxy::ForwardProxy,
+        q_block::{self, QBlockParameters},
+        request::{Request, RequestMethod},
+        response::{Response, ResponseCode},
+        server::CoapServer,
+        transport::{MemoryNetwork, Transport},
+    };
+
+    fn hello(req: &Request) -> Response {
+        let mut response = Response::new(ResponseCode::Content);
+        response.set_body(format!("hello {}", req.get_path()).into_bytes());
+        response
+    }
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:5683".parse().unwrap()
+    }
+
+    /// feed `data` from `peer()` and take the first datagram sent back
+    fn exchange<T: Transport, F: Fn(&Request) -> Response>(server: &mut CoapServer<T>, data: &[u8], handler: &F) -> Option<Vec<u8>> {
+        server.handle_datagram(peer(), data, handler);
+        let reply = server.poll_transmit().map(|transmit| transmit.data);
+        while server.poll_transmit().is_some() {}
+        reply
+    }
+
+    #[test]
+    fn empty_con_gets_rst() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        let ping = CoAPFrame::empty(MessageType::Con, 0x1234);
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &ping.to_bytes(), &hello).unwrap()).unwrap();
+        assert_eq!(reply.header.get_type(), u8::from(MessageType::Rst));
+        assert_eq!(reply.header.get_msg_id(), 0x1234);
+        assert!(reply.is_empty_message());
+
+        let non = CoAPFrame::empty(MessageType::Non, 0x1235);
+        assert!(exchange(&mut server, &non.to_bytes(), &hello).is_none());
+    }
+
+    /// hands the server one datagram, fails every send and then fails itself
+    struct OneDatagram(Mutex<Option<Vec<u8>>>);
+
+    impl Transport for OneDatagram {
+        fn send_to(&self, _: &[u8], _: SocketAddr) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::NotConnected))
+        }
+
+        fn recv_from(&self, buf: &mut [u8], _: Option<Duration>) -> io::Result<(usize, SocketAddr)> {
+            let datagram = self.0.lock().unwrap().take().ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            buf[..datagram.len()].copy_from_slice(&datagram);
+            Ok((datagram.len(), peer()))
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok(peer())
+        }
+    }
+
+    #[test]
+    fn unsent_replies_are_counted() {
+        let ping = CoAPFrame::empty(MessageType::Con, 1).to_bytes();
+        let mut server = CoapServer::with_transport(OneDatagram(Mutex::new(Some(ping))));
+        assert_eq!(server.run(hello).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(server.get_unsent(), 1);
+    }
+
+    #[test]
+    fn malformed_datagrams() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        // token length 8 but no token
+        assert!(exchange(&mut server, &[0x48, 1, 0, 0], &hello).is_none());
+        assert!(exchange(&mut server, &[0x50, 1, 0, 0, 0xB3, b'a'], &hello).is_none());
+        // a CON whose option runs past the end is rejected
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &[0x40, 1, 0, 9, 0xB3, b'a'], &hello).unwrap()).unwrap();
+        assert_eq!(reply.header.get_type(), u8::from(MessageType::Rst));
+        assert_eq!(reply.header.get_msg_id(), 9);
+    }
+
+    #[test]
+    fn con_request_gets_piggybacked_response() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        let mut options = BTreeMap::new();
+        options.insert(u16::from(OptionEnum::UriPath), vec![Vec::from("world")]);
+        let mut header = Header::new(MessageType::Con.into(), RequestMethod::Get as u8);
+        header.set_msg_id(7);
+        let request = CoAPFrame::new(header, options, vec![]);
+
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &request.to_bytes(), &hello).unwrap()).unwrap();
+        assert_eq!(reply.header.get_type(), u8::from(MessageType::Ack));
+        assert_eq!(reply.header.get_msg_id(), 7);
+        assert_eq!(reply.get_token(), request.get_token());
+        assert_eq!(reply.get_body(), b"hello world");
+    }
+
+    #[test]
+    fn duplicate_requests_run_handler_once() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        let calls = Cell::new(0);
+        let count = |_: &Request| {
+            calls.set(calls.get() + 1);
+            Response::new(ResponseCode::Changed)
+        };
+
+        let mut header = Header::new(MessageType::Con.into(), RequestMethod::Post as u8);
+        header.set_msg_id(8);
+        let con = CoAPFrame::new(header, BTreeMap::new(), vec![1]).to_bytes();
+        let first = exchange(&mut server, &con, &count).unwrap();
+        let second = exchange(&mut server, &con, &count).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(calls.get(), 1);
+
+        let mut header = Header::new(MessageType::Non.into(), RequestMethod::Post as u8);
+        header.set_msg_id(9);
+        let non = CoAPFrame::new(header, BTreeMap::new(), vec![1]).to_bytes();
+        assert!(exchange(&mut server, &non, &count).is_some());
+        assert!(exchange(&mut server, &non, &count).is_none());
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn extended_token_length() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        let mut header = Header::new(MessageType::Con.into(), RequestMethod::Get as u8);
+        header.set_msg_id(10);
+        let mut request = CoAPFrame::new(header, BTreeMap::new(), vec![]);
+        request.set_token(vec![7u8; 300]).unwrap();
+
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &request.to_bytes(), &hello).unwrap()).unwrap();
+        assert_eq!(reply.header.get_type(), u8::from(MessageType::Rst));
+
+        server.set_max_token_length(1024);
+        request.header.set_msg_id(11);
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &request.to_bytes(), &hello).unwrap()).unwrap();
+        assert_eq!(reply.header.get_type(), u8::from(MessageType::Ack));
+        assert_eq!(reply.get_token(), vec![7u8; 300]);
+    }
+
+    #[test]
+    fn no_response_suppresses_by_class() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        let changed = |_: &Request| Response::new(ResponseCode::Changed);
+        let mut options = BTreeMap::new();
+        options.insert(u16::from(OptionEnum::NoResponse), vec![NoResponse::SUCCESS.to_value()]);
+
+        let mut header = Header::new(MessageType::Non.into(), RequestMethod::Post as u8);
+        header.set_msg_id(12);
+        let non = CoAPFrame::new(header, options.clone(), vec![1]).to_bytes();
+        assert!(exchange(&mut server, &non, &changed).is_none());
+
+        let mut header = Header::new(MessageType::Con.into(), RequestMethod::Post as u8);
+        header.set_msg_id(13);
+        let con = CoAPFrame::new(header, options.clone(), vec![1]).to_bytes();
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &con, &changed).unwrap()).unwrap();
+        assert_eq!(reply.header.get_type(), u8::from(MessageType::Ack));
+        assert!(reply.is_empty_message());
+
+        let mut header = Header::new(MessageType::Non.into(), RequestMethod::Post as u8);
+        header.set_msg_id(14);
+        let non = CoAPFrame::new(header, options, vec![1]).to_bytes();
+        let not_found = |_: &Request| Response::new(ResponseCode::NotFound);
+        assert!(exchange(&mut server, &non, &not_found).is_some());
+    }
+
+    #[test]
+    fn unsafe_requests_are_challenged() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        server.set_echo_freshness(Some(Duration::from_secs(10)));
+        let mut header = Header::new(MessageType::Con.into(), RequestMethod::Put as u8);
+        header.set_msg_id(20);
+        let put = CoAPFrame::new(header, BTreeMap::new(), vec![1]).to_bytes();
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &put, &hello).unwrap()).unwrap();
+        assert_eq!(reply.header.get_code(), u8::from(ResponseCode::Unauthorized));
+        assert!(reply.get_options().contains_key(&u16::from(OptionEnum::Echo)));
+    }
+
+    #[test]
+    fn fetch_is_not_challenged() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        server.set_echo_freshness(Some(Duration::from_secs(10)));
+        let mut header = Header::new(MessageType::Con.into(), RequestMethod::Fetch as u8);
+        header.set_msg_id(21);
+        let fetch = CoAPFrame::new(header, BTreeMap::new(), vec![1]).to_bytes();
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &fetch, &hello).unwrap()).unwrap();
+        assert_eq!(reply.header.get_code(), u8::from(ResponseCode::Content));
+    }
+
+    #[test]
+    fn block1_bodies_are_kept_apart_by_request_tag() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        let echo_body = |req: &Request| {
+            let mut response = Response::new(ResponseCode::Changed);
+            response.set_body(req.get_body().clone());
+            response
+        };
+        let block = |msg_id: u16, tag: u8, num: u32, more: bool, body: &[u8]| {
+            let mut options = BTreeMap::new();
+            options.insert(u16::from(OptionEnum::Block1), vec![BlockValue::new(num, more, 16).to_value()]);
+            options.insert(u16::from(OptionEnum::RequestTag), vec![vec![tag]]);
+            let mut header = Header::new(MessageType::Con.into(), RequestMethod::Post as u8);
+            header.set_msg_id(msg_id);
+            CoAPFrame::new(header, options, body.to_vec()).to_bytes()
+        };
+        let code = |reply: Vec<u8>| CoAPFrame::from_bytes(reply).unwrap().header.get_code();
+
+        let a = [b'a'; 16];
+        let b = [b'b'; 16];
+        let cont = u8::from(ResponseCode::Continue);
+        assert_eq!(code(exchange(&mut server, &block(30, 1, 0, true, &a), &echo_body).unwrap()), cont);
+        assert_eq!(code(exchange(&mut server, &block(31, 2, 0, true, &b), &echo_body).unwrap()), cont);
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &block(32, 1, 1, false, b"A"), &echo_body).unwrap()).unwrap();
+        assert_eq!(reply.get_body(), [&a[..], b"A"].concat());
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &block(33, 2, 1, false, b"B"), &echo_body).unwrap()).unwrap();
+        assert_eq!(reply.get_body(), [&b[..], b"B"].concat());
+
+        // block 1 without block 0
+        let reply = exchange(&mut server, &block(34, 3, 1, false, b"C"), &echo_body).unwrap();
+        assert_eq!(code(reply), u8::from(ResponseCode::RequestEntityIncomplete));
+    }
+
+    #[test]
+    fn block_transfers_are_bounded_per_peer() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        let clock = ManualClock::new(Instant::now());
+        server.set_clock(clock.clone());
+        server.set_max_body_size(100);
+        let block = |msg_id: u16, tag: u8, num: u32| {
+            let mut options = BTreeMap::new();
+            options.insert(u16::from(OptionEnum::Block1), vec![BlockValue::new(num, true, 16).to_value()]);
+            options.insert(u16::from(OptionEnum::RequestTag), vec![vec![tag]]);
+            let mut header = Header::new(MessageType::Con.into(), RequestMethod::Post as u8);
+            header.set_msg_id(msg_id);
+            CoAPFrame::new(header, options, vec![tag; 16]).to_bytes()
+        };
+        let code = |reply: Vec<u8>| ResponseCode::from(CoAPFrame::from_bytes(reply).unwrap().header.get_code());
+
+        for tag in 1..=5 {
+            assert_eq!(code(exchange(&mut server, &block(tag as u16, tag, 0), &hello).unwrap()), ResponseCode::Continue);
+            clock.advance(Duration::from_secs(1));
+        }
+        // the fifth transfer replaced the first
+        assert_eq!(code(exchange(&mut server, &block(6, 1, 1), &hello).unwrap()), ResponseCode::RequestEntityIncomplete);
+        // 64 bytes are held, the peer may add 36 more across its transfers
+        assert_eq!(code(exchange(&mut server, &block(7, 2, 1), &hello).unwrap()), ResponseCode::Continue);
+        assert_eq!(code(exchange(&mut server, &block(8, 3, 1), &hello).unwrap()), ResponseCode::Continue);
+        assert_eq!(code(exchange(&mut server, &block(9, 4, 1), &hello).unwrap()), ResponseCode::RequestEntityTooLarge);
+    }
+
+    #[test]
+    fn q_block1_reports_missing_blocks() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        server.set_q_block(Some(QBlockParameters { max_payloads: 3, ..Default::default() }));
+        let size = |req: &Request| {
+            let mut response = Response::new(ResponseCode::Changed);
+            response.set_body(req.get_body().clone());
+            response
+        };
+        let block = |msg_id: u16, num: u32, more: bool| {
+            let mut options = BTreeMap::new();
+            options.insert(u16::from(OptionEnum::QBlock1), vec![BlockValue::new(num, more, 16).to_value()]);
+            let mut header = Header::new(MessageType::Non.into(), RequestMethod::Post as u8);
+            header.set_msg_id(msg_id);
+            CoAPFrame::new(header, options, vec![num as u8; 16]).to_bytes()
+        };
+
+        assert!(exchange(&mut server, &block(40, 0, true), &size).is_none());
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &block(41, 2, true), &size).unwrap()).unwrap();
+        assert_eq!(reply.header.get_code(), u8::from(ResponseCode::RequestEntityIncomplete));
+        assert_eq!(q_block::decode_missing(&reply.get_body()), vec![1]);
+
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &block(42, 1, true), &size).unwrap()).unwrap();
+        assert_eq!(reply.header.get_code(), u8::from(ResponseCode::Continue));
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &block(43, 3, false), &size).unwrap()).unwrap();
+        assert_eq!(reply.header.get_code(), u8::from(ResponseCode::Changed));
+        assert_eq!(reply.get_body().len(), 64);
+
+        // without Q-Block support the option is rejected
+        server.set_q_block(None);
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &block(44, 0, true), &size).unwrap()).unwrap();
+        assert_eq!(reply.header.get_code(), u8::from(ResponseCode::BadOption));
+    }
+
+    #[test]
+    fn q_block1_transfers_are_bounded() {
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        server.set_q_block(Some(QBlockParameters { max_payloads: 5000, ..Default::default() }));
+        server.set_max_message_size(64);
+        let block = |msg_id: u16, num: u32, more: bool| {
+            let mut options = BTreeMap::new();
+            options.insert(u16::from(OptionEnum::QBlock1), vec![BlockValue::new(num, more, 16).to_value()]);
+            let mut header = Header::new(MessageType::Non.into(), RequestMethod::Post as u8);
+            header.set_msg_id(msg_id);
+            CoAPFrame::new(header, options, vec![1; 16]).to_bytes()
+        };
+
+        // a block far beyond anything received keeps no state
+        assert!(exchange(&mut server, &block(1, 6000, true), &hello).is_none());
+        assert!(server.q_block1.is_empty());
+
+        // the list of missing blocks is cut to fit one message
+        assert!(exchange(&mut server, &block(2, 0, true), &hello).is_none());
+        let reply = exchange(&mut server, &block(3, 4999, true), &hello).unwrap();
+        assert!(reply.len() <= 64);
+        let missing = q_block::decode_missing(&CoAPFrame::from_bytes(reply).unwrap().get_body());
+        assert_eq!(missing[..3], [1, 2, 3]);
+
+        // bodies above the limit are refused with the size that is accepted
+        server.set_max_body_size(4096);
+        let reply = CoAPFrame::from_bytes(exchange(&mut server, &block(4, 256, true), &hello).unwrap()).unwrap();
+        assert_eq!(reply.header.get_code(), u8::from(ResponseCode::RequestEntityTooLarge));
+        assert_eq!(reply.get_options()[&u16::from(OptionEnum::Size1)], vec![uint_to_bytes(4096)]);
+        assert!(server.q_block1.is_empty());
+    }
+
+    /// origin answering once the test lets it
+    struct GatedOrigin(Mutex<mpsc::Receiver<()>>);
+
+    impl HttpConnector for GatedOrigin {
+        fn send(&self, _: &Url, _: &HttpRequest, _: Duration) -> io::Result<HttpResponse> {
+            let _ = self.0.lock().unwrap().recv();
+            Ok(HttpResponse { status: 200, headers: vec![], body: Vec::from("slow") })
+        }
+    }
+
+    #[test]
+    fn slow_origins_do_not_block_the_server() {
+        let (open, gate) = mpsc::channel();
+        let mut proxy = ForwardProxy::new();
+        proxy.set_http_connector(GatedOrigin(Mutex::new(gate)));
+        let network = MemoryNetwork::new();
+        let mut server = CoapServer::with_transport(network.bind(peer()).unwrap());
+        server.set_forward_proxy(Some(proxy));
+
+        let mut options = BTreeMap::new();
+        options.insert(u16::from(OptionEnum::ProxyUri), vec![Vec::from("http://origin.invalid/a")]);
+        let mut header = Header::new(MessageType::Con.into(), RequestMethod::Get as u8);
+        header.set_msg_id(20);
+        let mut request = CoAPFrame::new(header, options, vec![]);
+        request.set_token(vec![5]).unwrap();
+        // acknowledged at once while the origin is still busy
+        let ack = CoAPFrame::from_bytes(exchange(&mut server, &request.to_bytes(), &hello).unwrap()).unwrap();
+        assert!(ack.is_empty_message());
+        assert_eq!(ack.header.get_msg_id(), 20);
+
+        // and other requests are answered meanwhile
+        let mut header = Header::new(MessageType::Con.into(), RequestMethod::Get as u8);
+        header.set_msg_id(21);
+        let other = CoAPFrame::from_bytes(exchange(&mut server, &CoAPFrame::new(header, BTreeMap::new(), vec![]).to_bytes(), &hello).unwrap()).unwrap();
+        assert_eq!(other.get_body(), b"hello ");
+        assert!(server.poll_timeout().is_some());
+
+        open.send(()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let separate = loop {
+            server.handle_timeout();
+            if let Some(transmit) = server.poll_transmit() {
+                break CoAPFrame::from_bytes(transmit.data).unwrap();
+            }
+            assert!(Instant::now() < deadline, "no separate response");
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(separate.header.get_type(), u8::from(MessageType::Con));
+        assert_eq!(separate.get_token(), vec![5]);
+        assert_eq!(separate.get_body(), b"slow");
+        // acknowledged, nothing is left to do
+        let ack = CoAPFrame::empty(MessageType::Ack, separate.header.get_msg_id());
+        assert!(exchange(&mut server, &ack.to_bytes(), &hello).is_none());
+        assert_eq!(server.poll_timeout(), None);
+    }
+
+    #[test]
+    fn middleware_wraps_the_handler() {
+        struct User(String);
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        // outermost, sees every response
+        server.add_middleware(|request: &mut Request, next: Next<'_>| {
+            let mut response = next.run(request);
+            response.set_option(OptionEnum::ContentFormat, vec![]);
+            response
+        });
+        // requests that do not name a user are refused before the handler runs
+        server.add_middleware(|request: &mut Request, next: Next<'_>| {
+            match request.get_option(OptionEnum::UriQuery).and_then(|v| v.first()).cloned() {
+                Some(user) => {
+                    request.get_extensions_mut().insert(User(String::from_utf8_lossy(&user).into_owned()));
+                    next.run(request)
+                }
+                None => Response::new(ResponseCode::Unauthorized),
+            }
+        });
+        let calls = Cell::new(0);
+        let greet = |req: &Request| {
+            calls.set(calls.get() + 1);
+            let mut response = Response::new(ResponseCode::Content);
+            response.set_body(format!("hello {}", req.get_extensions().get::<User>().unwrap().0).into_bytes());
+            response
+        };
+        let request = |msg_id, query: Option<&str>| {
+            let mut options = BTreeMap::new();
+            if let Some(query) = query {
+                options.insert(u16::from(OptionEnum::UriQuery), vec![Vec::from(query)]);
+            }
+            let mut header = Header::new(MessageType::Con.into(), RequestMethod::Get as u8);
+            header.set_msg_id(msg_id);
+            CoAPFrame::new(header, options, vec![]).to_bytes()
+        };
+
+        let reply = Response::from_frame(&CoAPFrame::from_bytes(exchange(&mut server, &request(1, None), &greet).unwrap()).unwrap());
+        assert_eq!(reply.get_response_code(), ResponseCode::Unauthorized);
+        assert!(reply.get_option(OptionEnum::ContentFormat).is_some());
+        assert_eq!(calls.get(), 0);
+
+        let reply = Response::from_frame(&CoAPFrame::from_bytes(exchange(&mut server, &request(2, Some("alice")), &greet).unwrap()).unwrap());
+        assert_eq!(reply.get_response_code(), ResponseCode::Content);
+        assert_eq!(reply.get_body(), b"hello alice");
+        assert!(reply.get_option(OptionEnum::ContentFormat).is_some());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn middleware_sees_every_block() {
+        /// counts request messages and complete requests
+        struct Count(Arc<AtomicUsize>, Arc<AtomicUsize>);
+
+        impl Middleware for Count {
+            fn pre_dispatch(&self, _: &Request) -> Option<Response> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                None
+            }
+
+            fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                next.run(request)
+            }
+        }
+
+        let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+        let (messages, requests) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        server.add_middleware(Count(messages.clone(), requests.clone()));
+        server.add_middleware(RateLimit::new(3, Duration::from_secs(60)));
+        let block = |msg_id: u16, num: u32, more: bool| {
+            let mut options = BTreeMap::new();
+            options.insert(u16::from(OptionEnum::Block1), vec![BlockValue::new(num, more, 16).to_value()]);
+            let mut header = Header::new(MessageType::Con.into(), RequestMethod::Post as u8);
+            header.set_msg_id(msg_id);
+            CoAPFrame::new(header, options, vec![0; 16]).to_bytes()
+        };
+        let code = |reply: Vec<u8>| ResponseCode::from(CoAPFrame::from_bytes(reply).unwrap().header.get_code());
+
+        assert_eq!(code(exchange(&mut server, &block(1, 0, true), &hello).unwrap()), ResponseCode::Continue);
+        assert_eq!(code(exchange(&mut server, &block(2, 1, true), &hello).unwrap()), ResponseCode::Continue);
+        assert_eq!(code(exchange(&mut server, &block(3, 2, false), &hello).unwrap()), ResponseCode::Content);
+        assert_eq!((messages.load(Ordering::SeqCst), requests.load(Ordering::SeqCst)), (3, 1));
+        // the blocks used up the budget of the peer
+        assert_eq!(code(exchange(&mut server, &block(4, 0, true), &hello).unwrap()), ResponseCode::TooManyRequests);
+        assert_eq!((messages.load(Ordering::SeqCst), requests.load(Ordering::SeqCst)), (4, 1));
+    }
+}
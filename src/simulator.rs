@@ -0,0 +1,420 @@
+use std::{collections::HashMap, net::SocketAddr, time::{Duration, Instant}};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    endpoint::{Endpoint, Event, Transmit},
+    request::Request,
+    response::Response,
+};
+
+/// a participant in a simulation, driven sans-IO like `Endpoint`
+pub trait Node {
+    fn handle_datagram(&mut self, from: SocketAddr, data: &[u8], now: Instant);
+
+    fn handle_timeout(&mut self, now: Instant);
+
+    fn poll_transmit(&mut self) -> Option<Transmit>;
+
+    fn poll_timeout(&self) -> Option<Instant>;
+}
+
+impl Node for Endpoint {
+    fn handle_datagram(&mut self, from: SocketAddr, data: &[u8], now: Instant) {
+        Endpoint::handle_datagram(self, from, data, now)
+    }
+
+    fn handle_timeout(&mut self, now: Instant) {
+        Endpoint::handle_timeout(self, now)
+    }
+
+    fn poll_transmit(&mut self) -> Option<Transmit> {
+        Endpoint::poll_transmit(self)
+    }
+
+    fn poll_timeout(&self) -> Option<Instant> {
+        Endpoint::poll_timeout(self)
+    }
+}
+
+/// server side of a simulation, an endpoint answering every request with `handler`
+pub struct Responder<F> {
+    endpoint: Endpoint,
+    handler: F,
+}
+
+impl<F: FnMut(&Request) -> Response> Responder<F> {
+
+    pub fn new(handler: F) -> Responder<F> {
+        let mut endpoint = Endpoint::new();
+        endpoint.set_accept_requests(true);
+        Responder { endpoint, handler }
+    }
+
+    pub fn get_endpoint_mut(&mut self) -> &mut Endpoint {
+        &mut self.endpoint
+    }
+}
+
+impl<F: FnMut(&Request) -> Response> Node for Responder<F> {
+    fn handle_datagram(&mut self, from: SocketAddr, data: &[u8], now: Instant) {
+        self.endpoint.handle_datagram(from, data, now);
+        while let Some(event) = self.endpoint.poll_event() {
+            if let Event::Request { message_id, request } = event {
+                let response = (self.handler)(&request);
+                let _ = self.endpoint.respond(&request, message_id, &response, now);
+            }
+        }
+    }
+
+    fn handle_timeout(&mut self, now: Instant) {
+        self.endpoint.handle_timeout(now);
+    }
+
+    fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.endpoint.poll_transmit()
+    }
+
+    fn poll_timeout(&self) -> Option<Instant> {
+        self.endpoint.poll_timeout()
+    }
+}
+
+/// how a link treats the datagrams sent over it
+#[derive(Debug, Clone, Copy)]
+pub struct LinkConditions {
+    /// chance a datagram is lost
+    pub loss: f64,
+    /// chance a datagram is delivered twice
+    pub duplication: f64,
+    /// chance a datagram is held back by another `delay`, so later ones overtake it
+    pub reordering: f64,
+    pub delay: Duration,
+    /// random extra delay between zero and this
+    pub jitter: Duration,
+    /// larger datagrams are dropped, as a link that does not fragment would
+    pub mtu: usize,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        LinkConditions {
+            loss: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+            delay: Duration::from_millis(10),
+            jitter: Duration::ZERO,
+            mtu: 1280,
+        }
+    }
+}
+
+impl LinkConditions {
+
+    /// delays after which the copies of a datagram of `len` bytes arrive,
+    /// none when it is lost or too large
+    pub(crate) fn schedule<R: Rng + ?Sized>(&self, len: usize, rng: &mut R, stats: &mut SimulatorStats) -> Vec<Duration> {
+        stats.sent += 1;
+        if len > self.mtu {
+            stats.oversized += 1;
+            return vec![];
+        }
+        if rng.gen_bool(self.loss.clamp(0.0, 1.0)) {
+            stats.lost += 1;
+            return vec![];
+        }
+        let copies = if rng.gen_bool(self.duplication.clamp(0.0, 1.0)) {
+            stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        (0..copies).map(|_| {
+            let mut delay = self.delay + self.jitter.mul_f64(rng.gen_range(0.0..=1.0));
+            if rng.gen_bool(self.reordering.clamp(0.0, 1.0)) {
+                delay += self.delay;
+            }
+            delay
+        }).collect()
+    }
+}
+
+/// what happened to the datagrams of a simulation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimulatorStats {
+    pub sent: usize,
+    pub delivered: usize,
+    pub lost: usize,
+    pub duplicated: usize,
+    pub oversized: usize,
+}
+
+struct InFlight {
+    deliver_at: Instant,
+    seq: u64,
+    from: SocketAddr,
+    to: SocketAddr,
+    data: Vec<u8>,
+}
+
+/// deterministic network for tests
+///
+/// nodes are driven on a virtual clock, jumping straight to the next
+/// delivery or timeout, and every random decision comes from a seeded RNG,
+/// so a run with the same seed always plays out the same
+pub struct Simulator {
+    now: Instant,
+    rng: StdRng,
+    conditions: LinkConditions,
+    links: HashMap<(SocketAddr, SocketAddr), LinkConditions>,
+    in_flight: Vec<InFlight>,
+    seq: u64,
+    stats: SimulatorStats,
+}
+
+impl Simulator {
+
+    pub fn new(seed: u64) -> Simulator {
+        Simulator {
+            now: Instant::now(),
+            rng: StdRng::seed_from_u64(seed),
+            conditions: LinkConditions::default(),
+            links: HashMap::new(),
+            in_flight: Vec::new(),
+            seq: 0,
+            stats: SimulatorStats::default(),
+        }
+    }
+
+    /// conditions of every link without its own
+    pub fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.conditions = conditions;
+    }
+
+    /// conditions for datagrams from `from` to `to`
+    pub fn set_link(&mut self, from: SocketAddr, to: SocketAddr, conditions: LinkConditions) {
+        self.links.insert((from, to), conditions);
+    }
+
+    /// the virtual time
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    pub fn get_stats(&self) -> SimulatorStats {
+        self.stats
+    }
+
+    pub fn run_for(&mut self, nodes: &mut [(SocketAddr, &mut dyn Node)], duration: Duration) {
+        let until = self.now + duration;
+        self.run_until(nodes, until);
+    }
+
+    /// deliver datagrams and fire timeouts in time order until `until`
+    pub fn run_until(&mut self, nodes: &mut [(SocketAddr, &mut dyn Node)], until: Instant) {
+        loop {
+            for (addr, node) in nodes.iter_mut() {
+                while let Some(transmit) = node.poll_transmit() {
+                    self.send(*addr, transmit);
+                }
+            }
+            let next_delivery = self.in_flight.iter().map(|d| d.deliver_at).min();
+            let next_timeout = nodes.iter().filter_map(|(_, node)| node.poll_timeout()).min();
+            let next = match (next_delivery, next_timeout) {
+                (Some(a), Some(b)) => a.min(b),
+                (a, b) => match a.or(b) {
+                    Some(next) => next,
+                    None => break,
+                },
+            };
+            if next > until {
+                break;
+            }
+            self.now = self.now.max(next);
+
+            let mut due: Vec<InFlight> = Vec::new();
+            let mut i = 0;
+            while i < self.in_flight.len() {
+                if self.in_flight[i].deliver_at <= self.now {
+                    due.push(self.in_flight.swap_remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+            due.sort_by_key(|d| (d.deliver_at, d.seq));
+            for datagram in due {
+                // nobody bound on the address, the datagram is lost like on UDP
+                if let Some((_, node)) = nodes.iter_mut().find(|(addr, _)| *addr == datagram.to) {
+                    node.handle_datagram(datagram.from, &datagram.data, self.now);
+                    self.stats.delivered += 1;
+                }
+            }
+            for (_, node) in nodes.iter_mut() {
+                if node.poll_timeout().is_some_and(|t| t <= self.now) {
+                    node.handle_timeout(self.now);
+                }
+            }
+        }
+        self.now = self.now.max(until);
+    }
+
+    fn send(&mut self, from: SocketAddr, transmit: Transmit) {
+        let conditions = self.links.get(&(from, transmit.peer)).copied().unwrap_or(self.conditions);
+        for delay in conditions.schedule(transmit.data.len(), &mut self.rng, &mut self.stats) {
+            self.seq += 1;
+            self.in_flight.push(InFlight {
+                deliver_at: self.now + delay,
+                seq: self.seq,
+                from,
+                to: transmit.peer,
+                data: transmit.data.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::Cell, net::SocketAddr, time::Duration};
+
+    use crate::{
+        endpoint::{Endpoint, Event},
+        frame::MessageType,
+        request::{test_request, RequestMethod},
+        response::{Response, ResponseCode},
+        simulator::{LinkConditions, Node, Responder, Simulator},
+        transmission::{RetransmissionPolicy, TransmissionParameters},
+    };
+
+    fn client_addr() -> SocketAddr {
+        "10.0.0.1:40000".parse().unwrap()
+    }
+
+    fn server_addr() -> SocketAddr {
+        "10.0.0.2:5683".parse().unwrap()
+    }
+
+    fn fixed_timeouts() -> TransmissionParameters {
+        TransmissionParameters {
+            ack_timeout: Duration::from_secs(2),
+            ack_random_factor: 1.0,
+            max_retransmit: 4,
+        }
+    }
+
+    /// send one CON GET over `sim`, returns the response and how many times the handler ran
+    fn exchange(sim: &mut Simulator, client: &mut Endpoint, body: Vec<u8>) -> (Option<Response>, usize) {
+        let runs = Cell::new(0);
+        let mut server = Responder::new(|_| {
+            runs.set(runs.get() + 1);
+            Response::new(ResponseCode::Content)
+        });
+        let mut request = test_request(RequestMethod::Get, client_addr());
+        request.set_body(body);
+        let deadline = sim.now() + Duration::from_secs(120);
+        client.request(server_addr(), &request, vec![1], deadline, sim.now()).unwrap();
+        sim.run_for(&mut [(client_addr(), client as &mut dyn Node), (server_addr(), &mut server)], Duration::from_secs(120));
+        let mut response = None;
+        while let Some(event) = client.poll_event() {
+            if let Event::Response { response: r, .. } = event {
+                response = Some(r);
+            }
+        }
+        client.close(&[1], sim.now());
+        (response, runs.get())
+    }
+
+    #[test]
+    fn lossy_link_is_deterministic() {
+        let run = |seed| {
+            let mut sim = Simulator::new(seed);
+            sim.set_conditions(LinkConditions { loss: 0.4, ..LinkConditions::default() });
+            let mut client = Endpoint::new();
+            client.set_transmission_parameters(fixed_timeouts());
+            let (response, _) = exchange(&mut sim, &mut client, vec![]);
+            (response.is_some(), sim.get_stats())
+        };
+        assert_eq!(run(7), run(7));
+        // some seed loses a datagram and still gets through by retransmission
+        assert!((0..20).map(run).any(|(ok, stats)| ok && stats.lost > 0));
+    }
+
+    #[test]
+    fn duplicates_reach_the_handler_once() {
+        let mut sim = Simulator::new(1);
+        sim.set_conditions(LinkConditions { duplication: 1.0, reordering: 0.5, ..LinkConditions::default() });
+        let mut client = Endpoint::new();
+        client.set_transmission_parameters(fixed_timeouts());
+        let (response, runs) = exchange(&mut sim, &mut client, vec![]);
+        assert!(response.is_some());
+        assert_eq!(runs, 1);
+        assert!(sim.get_stats().duplicated > 0);
+    }
+
+    #[test]
+    fn oversized_datagrams_are_dropped() {
+        let mut sim = Simulator::new(1);
+        sim.set_conditions(LinkConditions { mtu: 100, ..LinkConditions::default() });
+        let mut client = Endpoint::new();
+        client.set_transmission_parameters(fixed_timeouts());
+        let start = sim.now();
+        let (response, runs) = exchange(&mut sim, &mut client, vec![0; 200]);
+        assert!(response.is_none());
+        assert_eq!(runs, 0);
+        assert_eq!(sim.get_stats().oversized, 5);
+        // the virtual clock ran through every retransmission without waiting
+        assert_eq!(sim.now() - start, Duration::from_secs(120));
+    }
+
+    #[test]
+    fn cocoa_learns_a_fast_link() {
+        let rto_after = |policy| {
+            let mut sim = Simulator::new(1);
+            let mut client = Endpoint::new();
+            client.set_retransmission_policy(policy);
+            let mut server = Responder::new(|_| Response::new(ResponseCode::Content));
+            let request = test_request(RequestMethod::Get, client_addr());
+            // back to back, so the RTO has no time to age
+            for _ in 0..10 {
+                client.request(server_addr(), &request, vec![1], sim.now() + Duration::from_secs(60), sim.now()).unwrap();
+                sim.run_for(&mut [(client_addr(), &mut client as &mut dyn Node), (server_addr(), &mut server)], Duration::from_millis(100));
+                assert!(matches!(client.poll_event(), Some(Event::Response { .. })));
+                client.close(&[1], sim.now());
+            }
+            // the next request is lost, how long until it is retransmitted
+            sim.set_conditions(LinkConditions { loss: 1.0, ..LinkConditions::default() });
+            client.request(server_addr(), &request, vec![2], sim.now() + Duration::from_secs(60), sim.now()).unwrap();
+            (client.poll_timeout().unwrap() - sim.now(), client.get_rto(server_addr()))
+        };
+        let (fixed, rto) = rto_after(RetransmissionPolicy::Fixed);
+        assert!(fixed >= Duration::from_secs(2));
+        assert_eq!(rto, None);
+        // round trips of 20 ms bring the RTO far below ACK_TIMEOUT
+        let (cocoa, rto) = rto_after(RetransmissionPolicy::Cocoa);
+        assert!(cocoa < Duration::from_millis(500));
+        assert!(rto.unwrap() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn paced_peer_waits_for_nstart() {
+        let mut sim = Simulator::new(1);
+        // the server never answers
+        sim.set_conditions(LinkConditions { loss: 1.0, ..LinkConditions::default() });
+        let mut client = Endpoint::new();
+        client.set_transmission_parameters(fixed_timeouts());
+        let mut request = test_request(RequestMethod::Get, client_addr());
+        let start = sim.now();
+        request.set_type(MessageType::Non);
+        client.request(server_addr(), &request, vec![1], start + Duration::from_secs(60), start).unwrap();
+        request.set_type(MessageType::Con);
+        client.request(server_addr(), &request, vec![2], start + Duration::from_secs(200), start).unwrap();
+
+        // PROBING_RATE runs out long before the NON stops counting against
+        // NSTART, the CON then goes out and is retransmitted
+        sim.run_for(&mut [(client_addr(), &mut client as &mut dyn Node)], Duration::from_secs(300));
+        assert_eq!(sim.get_stats().sent, 6);
+        assert!(matches!(client.poll_event(), Some(Event::TimedOut { token }) if token == [1]));
+        assert!(matches!(client.poll_event(), Some(Event::NotAcknowledged { token }) if token == [2]));
+        assert_eq!(client.poll_timeout(), None);
+    }
+}
@@ -0,0 +1,110 @@
+use rand::{Rng, RngCore};
+
+/// longest token allowed by RFC 7252
+pub const MAX_TOKEN_LEN: usize = 8;
+/// longest token expressible with extended token length, RFC 8974
+pub const MAX_EXTENDED_TOKEN_LEN: usize = 65804;
+
+/// source of request tokens for a client endpoint
+pub trait TokenGenerator {
+    fn generate(&mut self) -> Vec<u8>;
+}
+
+/// any closure returning a token can be used as generator
+impl<F> TokenGenerator for F
+where
+    F: FnMut() -> Vec<u8>,
+{
+    fn generate(&mut self) -> Vec<u8> {
+        self()
+    }
+}
+
+/// random tokens of a fixed length, the default uses 8 bytes
+pub struct RandomToken {
+    len: usize,
+    /// None draws from the thread local generator
+    rng: Option<Box<dyn RngCore + Send>>,
+}
+
+impl RandomToken {
+    pub fn new(len: usize) -> RandomToken {
+        assert!(len <= MAX_EXTENDED_TOKEN_LEN, "token length must be 0 to {}", MAX_EXTENDED_TOKEN_LEN);
+        RandomToken { len, rng: None }
+    }
+
+    /// tokens drawn from `rng`, reproducible with a seeded generator
+    pub fn with_rng<R: RngCore + Send + 'static>(len: usize, rng: R) -> RandomToken {
+        let mut token = RandomToken::new(len);
+        token.rng = Some(Box::new(rng));
+        token
+    }
+}
+
+impl Default for RandomToken {
+    fn default() -> Self {
+        RandomToken::new(MAX_TOKEN_LEN)
+    }
+}
+
+impl TokenGenerator for RandomToken {
+    fn generate(&mut self) -> Vec<u8> {
+        match &mut self.rng {
+            Some(rng) => (0..self.len).map(|_| rng.gen()).collect(),
+            None => {
+                let mut rng = rand::thread_rng();
+                (0..self.len).map(|_| rng.gen()).collect()
+            }
+        }
+    }
+}
+
+/// big endian counter truncated (or zero padded) to `len` bytes, predictable
+/// tokens for tests and peers that only keep a few bytes of token state
+pub struct CounterToken {
+    len: usize,
+    next: u64,
+}
+
+impl CounterToken {
+    pub fn new(len: usize, start: u64) -> CounterToken {
+        assert!(len <= MAX_EXTENDED_TOKEN_LEN, "token length must be 0 to {}", MAX_EXTENDED_TOKEN_LEN);
+        CounterToken { len, next: start }
+    }
+}
+
+impl TokenGenerator for CounterToken {
+    fn generate(&mut self) -> Vec<u8> {
+        let counter = self.next.to_be_bytes();
+        let mut token = vec![0u8; self.len.saturating_sub(MAX_TOKEN_LEN)];
+        token.extend_from_slice(&counter[MAX_TOKEN_LEN - self.len.min(MAX_TOKEN_LEN)..]);
+        self.next = self.next.wrapping_add(1);
+        token
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::token::{CounterToken, RandomToken, TokenGenerator};
+
+    #[test]
+    fn token_lengths() {
+        assert_eq!(RandomToken::default().generate().len(), 8);
+        assert!(RandomToken::new(0).generate().is_empty());
+
+        let mut counter = CounterToken::new(2, 0x01FF);
+        assert_eq!(counter.generate(), vec![0x01, 0xFF]);
+        assert_eq!(counter.generate(), vec![0x02, 0x00]);
+        let mut long = CounterToken::new(10, 1);
+        assert_eq!(long.generate(), vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+        let mut fixed = || vec![0xAB];
+        assert_eq!(fixed.generate(), vec![0xAB]);
+
+        let mut a = RandomToken::with_rng(8, StdRng::seed_from_u64(1));
+        let mut b = RandomToken::with_rng(8, StdRng::seed_from_u64(1));
+        assert_eq!(a.generate(), b.generate());
+    }
+}
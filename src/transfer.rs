@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{atomic::{AtomicU32, Ordering}, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    block::{block_size_for, BlockValue, MAX_BLOCK_SIZE},
+    common::uint_to_bytes,
+    endpoint::{Endpoint, Event},
+    frame::{MessageType, OptionEnum},
+    no_response::NoResponse,
+    request::{block_option, too_large, Request},
+    response::{Response, ResponseCode},
+};
+
+/// what a client keeps across its transfers
+#[derive(Default)]
+pub(crate) struct Transfers {
+    request_tags: AtomicU32,
+    /// latest Echo value (RFC 9175) received from each host:port
+    echo_values: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl Transfers {
+
+    /// every Block1 operation carries a Request-Tag of its own, RFC 9175 section 3
+    pub(crate) fn next_tag(&self) -> Vec<u8> {
+        uint_to_bytes(self.request_tags.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// send the Echo value last received from `key` along with `req`
+    pub(crate) fn add_echo(&self, key: &str, req: &mut Request) {
+        if let Some(echo) = self.echo_values.lock().unwrap().get(key) {
+            req.set_option(OptionEnum::Echo, echo.clone());
+        }
+    }
+
+    /// remember the Echo value `res` carries; when `res` is a 4.01 asking
+    /// for proof of freshness (RFC 9175 section 2) returns `sent` to repeat
+    /// with it
+    pub(crate) fn echo_retry(&self, key: &str, sent: &Request, res: &Response) -> Option<Request> {
+        let echo = res.get_option(OptionEnum::Echo).and_then(|v| v.first())?.clone();
+        self.echo_values.lock().unwrap().insert(key.to_owned(), echo.clone());
+        let sent_echo = sent.get_option(OptionEnum::Echo).and_then(|v| v.first());
+        if res.get_response_code() != ResponseCode::Unauthorized || sent_echo == Some(&echo) {
+            return None;
+        }
+        let mut again = sent.clone();
+        again.set_option(OptionEnum::Echo, echo);
+        Some(again)
+    }
+}
+
+/// what the driver of a `Transfer` does next
+pub(crate) enum Step {
+    /// send the message and hand what comes back to `Transfer::response`
+    Send(Request),
+    /// the response to the whole request, None when No-Response suppressed it
+    Done(Option<Response>),
+}
+
+/// the messages of one request, without IO: the request whole or its body
+/// Block1-wise (RFC 7959 section 2.5), each message repeated once with Echo
+/// when the server asks for it, and a Block2 response (section 2.4)
+/// collected into one body; the blocking and the tokio client both step
+/// through it
+pub(crate) struct Transfer<'a> {
+    transfers: &'a Transfers,
+    /// host:port the Echo values are kept for
+    key: String,
+    /// the request, without its body when that goes block-wise
+    req: Request,
+    block_size: usize,
+    /// the body while it is being uploaded
+    upload: Option<Upload>,
+    /// the message last handed out
+    sent: Option<Request>,
+    echo_retried: bool,
+    /// the response body collected so far, once Block2 blocks arrive
+    download: Option<Vec<u8>>,
+}
+
+struct Upload {
+    body: Vec<u8>,
+    tag: Vec<u8>,
+    size: usize,
+    offset: usize,
+    /// end of the block last handed out
+    end: usize,
+}
+
+impl Upload {
+    fn message(&mut self, req: &Request) -> Request {
+        self.end = (self.offset + self.size).min(self.body.len());
+        let block = BlockValue::new((self.offset / self.size) as u32, self.end < self.body.len(), self.size);
+        let mut message = req.clone();
+        message.set_body(self.body[self.offset..self.end].to_vec());
+        message.set_option(OptionEnum::Block1, block.to_value());
+        message.set_option(OptionEnum::RequestTag, self.tag.clone());
+        message
+    }
+}
+
+impl<'a> Transfer<'a> {
+
+    /// a body above `block1_size` or the message size goes block-wise; with
+    /// `ask_block2` the request asks for response blocks that fit right away
+    pub(crate) fn new(transfers: &'a Transfers, mut req: Request, max_message_size: usize, block1_size: usize, ask_block2: bool) -> io::Result<Transfer<'a>> {
+        let header_len = {
+            let mut bare = req.clone();
+            bare.set_body(vec![]);
+            bare.to_frame().to_bytes().len()
+        };
+        // not even a message without payload fits, blocks cannot help
+        if header_len > max_message_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "request options exceed the maximum message size"));
+        }
+        let fitting = block_size_for(max_message_size, header_len);
+        if ask_block2 && fitting < MAX_BLOCK_SIZE && req.get_option(OptionEnum::Block2).is_none() {
+            req.set_option(OptionEnum::Block2, BlockValue::new(0, false, fitting).to_value());
+        }
+        let block_size = block1_size.min(fitting);
+        let body_len = req.get_body().len();
+        // an empty body needs no payload marker
+        let whole = body_len == 0 || (body_len <= block1_size && header_len + 1 + body_len <= max_message_size);
+        let upload = (!whole).then(|| {
+            let body = req.get_body().clone();
+            req.set_body(vec![]);
+            Upload { body, tag: transfers.next_tag(), size: block_size, offset: 0, end: 0 }
+        });
+        Ok(Transfer {
+            transfers,
+            key: req.authority(),
+            req,
+            block_size,
+            upload,
+            sent: None,
+            echo_retried: false,
+            download: None,
+        })
+    }
+
+    /// the request, without its body when that goes block-wise
+    pub(crate) fn request(&self) -> &Request {
+        &self.req
+    }
+
+    pub(crate) fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// the body and Request-Tag of a block-wise upload
+    pub(crate) fn upload(&self) -> Option<(&[u8], &[u8])> {
+        self.upload.as_ref().map(|upload| (&upload.body[..], &upload.tag[..]))
+    }
+
+    /// the first message to send
+    pub(crate) fn first(&mut self) -> Request {
+        let message = match &mut self.upload {
+            Some(upload) => upload.message(&self.req),
+            None => self.req.clone(),
+        };
+        self.hand_out(message)
+    }
+
+    /// the response to the message last handed out, None when No-Response
+    /// suppressed it
+    pub(crate) fn response(&mut self, res: Option<Response>) -> io::Result<Step> {
+        if let (Some(res), Some(sent)) = (&res, &self.sent) {
+            let again = self.transfers.echo_retry(&self.key, sent, res);
+            if let Some(again) = again.filter(|_| !self.echo_retried) {
+                self.sent = Some(again.clone());
+                self.echo_retried = true;
+                return Ok(Step::Send(again));
+            }
+        }
+        if self.download.is_some() {
+            let res = res.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "block suppressed by No-Response"))?;
+            return Ok(self.download(res));
+        }
+        let next = match &mut self.upload {
+            Some(upload) if upload.end < upload.body.len() => {
+                match &res {
+                    Some(res) if res.get_response_code() == ResponseCode::Continue => {
+                        // the server may ask for smaller blocks
+                        if let Some(server_block) = block_option(res.get_option(OptionEnum::Block1)) {
+                            upload.size = upload.size.min(server_block.size());
+                        }
+                    }
+                    Some(_) => self.upload = None,
+                    None => {}
+                }
+                self.upload.as_mut().map(|upload| {
+                    upload.offset = upload.end;
+                    upload.message(&self.req)
+                })
+            }
+            _ => None,
+        };
+        if let Some(next) = next {
+            return Ok(Step::Send(self.hand_out(next)));
+        }
+        self.upload = None;
+        Ok(match res {
+            Some(res) => self.download(res),
+            None => Step::Done(None),
+        })
+    }
+
+    /// go on from `res`, a response to the whole upload: ask for the blocks
+    /// after it until the last one arrives
+    pub(crate) fn download(&mut self, mut res: Response) -> Step {
+        let Some(block) = block_option(res.get_option(OptionEnum::Block2)) else {
+            if let Some(mut body) = self.download.take() {
+                body.extend_from_slice(res.get_body());
+                res.set_body(body);
+            }
+            return Step::Done(Some(res));
+        };
+        let mut body = self.download.take().unwrap_or_default();
+        body.extend_from_slice(res.get_body());
+        if !block.more {
+            res.set_body(body);
+            res.remove_option(OptionEnum::Block2);
+            return Step::Done(Some(res));
+        }
+        self.download = Some(body);
+        let mut next = self.req.clone();
+        next.set_body(vec![]);
+        next.set_option(OptionEnum::Block2, BlockValue { num: block.num + 1, more: false, szx: block.szx }.to_value());
+        Step::Send(self.hand_out(next))
+    }
+
+    fn hand_out(&mut self, mut message: Request) -> Request {
+        self.transfers.add_echo(&self.key, &mut message);
+        self.sent = Some(message.clone());
+        self.echo_retried = false;
+        message
+    }
+}
+
+/// one message of a request and the endpoint events that end its exchange,
+/// without IO: a CON may be answered separately after its ACK, and with
+/// No-Response (RFC 7967) a response may never come
+pub(crate) struct Exchange {
+    peer: SocketAddr,
+    token: Vec<u8>,
+    no_response: Option<NoResponse>,
+    confirmable: bool,
+    acked: bool,
+    deadline: Instant,
+    no_response_wait: Duration,
+}
+
+impl Exchange {
+
+    pub(crate) fn new(req: &Request, peer: SocketAddr, token: Vec<u8>, now: Instant, timeout: Duration, no_response_wait: Duration) -> Exchange {
+        let confirmable = matches!(req.get_type(), MessageType::Con);
+        Exchange {
+            peer,
+            token,
+            no_response: req.get_no_response(),
+            confirmable,
+            acked: !confirmable,
+            deadline: now + timeout,
+            no_response_wait,
+        }
+    }
+
+    /// a NON suppressing every response class gets nothing back
+    pub(crate) fn expects_nothing(&self) -> bool {
+        !self.confirmable && self.no_response.is_some_and(|nr| nr.suppresses_all())
+    }
+
+    /// hand the request to the endpoint; a NON that may go unanswered is
+    /// given up on sooner
+    pub(crate) fn start(&self, endpoint: &mut Endpoint, req: &Request, now: Instant) -> io::Result<()> {
+        let deadline = match self.no_response.is_some() && !self.confirmable {
+            true => self.no_response_deadline(now),
+            false => self.deadline,
+        };
+        endpoint.request(self.peer, req, self.token.clone(), deadline, now)?;
+        Ok(())
+    }
+
+    /// what an event for the token means, Some once the exchange is over
+    pub(crate) fn event(&mut self, event: Event, endpoint: &mut Endpoint, now: Instant) -> Option<io::Result<Option<Response>>> {
+        let suppress_all = self.no_response.is_some_and(|nr| nr.suppresses_all());
+        Some(match event {
+            Event::Response { response, .. } => Ok(Some(response)),
+            Event::Acknowledged { .. } if suppress_all => Ok(None),
+            // a separate response follows, or was suppressed
+            Event::Acknowledged { .. } => {
+                self.acked = true;
+                if self.no_response.is_some() {
+                    endpoint.listen(self.peer, self.token.clone(), self.no_response_deadline(now));
+                }
+                return None;
+            }
+            Event::Reset { .. } => Err(io::Error::new(io::ErrorKind::ConnectionReset, "request rejected with RST")),
+            Event::NotAcknowledged { .. } => Err(io::Error::new(io::ErrorKind::TimedOut, "request was not acknowledged")),
+            // a response may legitimately never come when No-Response is set
+            Event::TimedOut { .. } if self.no_response.is_some() && self.acked => Ok(None),
+            Event::TooLarge { .. } => Err(too_large()),
+            _ => Err(io::Error::new(io::ErrorKind::TimedOut, "no response before timeout")),
+        })
+    }
+
+    fn no_response_deadline(&self, sent: Instant) -> Instant {
+        self.deadline.min(sent + self.no_response_wait)
+    }
+}
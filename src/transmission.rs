@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// CoAP transmission parameters, RFC 7252 section 4.8
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+pub const ACK_RANDOM_FACTOR: f64 = 1.5;
+pub const MAX_RETRANSMIT: u32 = 4;
+pub const NSTART: usize = 1;
+pub const DEFAULT_LEISURE: Duration = Duration::from_secs(5);
+/// bytes per second
+pub const PROBING_RATE: u32 = 1;
+
+/// derived time values, RFC 7252 section 4.8.2
+pub const MAX_TRANSMIT_SPAN: Duration = Duration::from_secs(45);
+pub const MAX_TRANSMIT_WAIT: Duration = Duration::from_secs(93);
+pub const MAX_LATENCY: Duration = Duration::from_secs(100);
+pub const PROCESSING_DELAY: Duration = ACK_TIMEOUT;
+pub const MAX_RTT: Duration = Duration::from_secs(202);
+pub const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+pub const NON_LIFETIME: Duration = Duration::from_secs(145);
+
+/// retransmission settings for confirmable messages
+#[derive(Debug, Clone, Copy)]
+pub struct TransmissionParameters {
+    pub ack_timeout: Duration,
+    pub ack_random_factor: f64,
+    pub max_retransmit: u32,
+}
+
+impl Default for TransmissionParameters {
+    fn default() -> Self {
+        TransmissionParameters {
+            ack_timeout: ACK_TIMEOUT,
+            ack_random_factor: ACK_RANDOM_FACTOR,
+            max_retransmit: MAX_RETRANSMIT,
+        }
+    }
+}
+
+/// how the timeouts of CON retransmissions are chosen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RetransmissionPolicy {
+    /// ACK_TIMEOUT dithered by ACK_RANDOM_FACTOR and doubled on every
+    /// retransmission, RFC 7252 section 4.2
+    #[default]
+    Fixed,
+    /// an RTO estimated per peer from measured round trips with a variable
+    /// backoff factor (RFC 9006), see `cocoa::RtoEstimator`
+    Cocoa,
+}
+
+/// congestion control towards one peer, RFC 7252 section 4.7
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CongestionParameters {
+    /// outstanding interactions at a time, further requests wait their turn
+    pub nstart: usize,
+    /// bytes per second sent to a peer that does not respond
+    pub probing_rate: u32,
+}
+
+impl Default for CongestionParameters {
+    fn default() -> Self {
+        CongestionParameters {
+            nstart: NSTART,
+            probing_rate: PROBING_RATE,
+        }
+    }
+}
+
+impl TransmissionParameters {
+
+    /// random timeout between ACK_TIMEOUT and ACK_TIMEOUT * ACK_RANDOM_FACTOR
+    pub fn initial_timeout(&self) -> Duration {
+        self.initial_timeout_with(&mut rand::thread_rng())
+    }
+
+    /// `initial_timeout` drawing from `rng`, for reproducible jitter
+    pub fn initial_timeout_with<R: Rng + ?Sized>(&self, rng: &mut R) -> Duration {
+        let factor = rng.gen_range(1.0..=self.ack_random_factor.max(1.0));
+        self.ack_timeout.mul_f64(factor)
+    }
+}
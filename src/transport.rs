@@ -0,0 +1,867 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{mpsc::{self, Receiver, Sender}, Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    common::{bytes_to_uint, uint_to_bytes},
+    frame::{tcp_head_len, tcp_message_len, CoAPFrame, CsmOption, Header, MessageType, MAX_MESSAGE_SIZE},
+    simulator::{LinkConditions, SimulatorStats},
+    token::{MAX_EXTENDED_TOKEN_LEN, MAX_TOKEN_LEN},
+};
+
+/// datagram transport the client and server exchange CoAP messages over
+///
+/// implement it to run CoAP over another link, e.g. a radio; this crate
+/// ships UDP, in-memory, TCP and, with the `dtls` feature, DTLS
+pub trait Transport {
+    /// send one datagram to `peer`
+    fn send_to(&self, data: &[u8], peer: SocketAddr) -> io::Result<usize>;
+
+    /// wait for the next datagram, at most `timeout` or forever when None;
+    /// runs out with `ErrorKind::WouldBlock` or `ErrorKind::TimedOut`
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<(usize, SocketAddr)>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send_to(&self, data: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        (**self).send_to(data, peer)
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<(usize, SocketAddr)> {
+        (**self).recv_from(buf, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        (**self).local_addr()
+    }
+}
+
+/// plain CoAP over UDP, RFC 7252
+///
+/// a single IPv6 socket is treated as dual-stack: IPv4 peers are sent to
+/// as v4-mapped addresses and reported back as plain IPv4; `dual` does not
+/// rely on that and keeps a socket per family
+pub struct UdpTransport {
+    socket: UdpSocket,
+    ipv6: bool,
+    /// separate IPv4 socket of a dual transport
+    ipv4: Option<UdpSocket>,
+}
+
+impl UdpTransport {
+
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<UdpTransport> {
+        Ok(UdpTransport::from_socket(UdpSocket::bind(addr)?))
+    }
+
+    pub fn from_socket(socket: UdpSocket) -> UdpTransport {
+        let ipv6 = socket.local_addr().is_ok_and(|addr| addr.is_ipv6());
+        UdpTransport { socket, ipv6, ipv4: None }
+    }
+
+    /// ephemeral ports for both families: an IPv6 socket and a separate
+    /// IPv4 one, so IPv4 peers are reached whether or not the IPv6 socket
+    /// takes v4-mapped addresses, which BSD and Windows refuse by default;
+    /// a host without IPv6 gets an IPv4 socket alone
+    pub fn dual() -> io::Result<UdpTransport> {
+        let Ok(socket) = UdpSocket::bind("[::]:0") else {
+            return UdpTransport::bind("0.0.0.0:0");
+        };
+        let ipv4 = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        ipv4.set_nonblocking(true)?;
+        Ok(UdpTransport { socket, ipv6: true, ipv4: Some(ipv4) })
+    }
+
+    /// ephemeral port on the unspecified address of the family of `peer`,
+    /// so IPv6 peers get an IPv6 socket
+    pub fn bind_for(peer: SocketAddr) -> io::Result<UdpTransport> {
+        let ip = match peer {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        UdpTransport::bind(SocketAddr::new(ip, 0))
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_to(&self, data: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        match (peer, &self.ipv4) {
+            (SocketAddr::V4(_), Some(ipv4)) => ipv4.send_to(data, peer),
+            (SocketAddr::V4(v4), None) if self.ipv6 => {
+                self.socket.send_to(data, SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()))
+            }
+            _ => self.socket.send_to(data, peer),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<(usize, SocketAddr)> {
+        let Some(ipv4) = &self.ipv4 else {
+            // a zero timeout means blocking forever to the socket
+            self.socket.set_read_timeout(timeout.map(|t| t.max(Duration::from_millis(1))))?;
+            let (len, peer) = self.socket.recv_from(buf)?;
+            return Ok((len, unmapped(peer)));
+        };
+        // std cannot wait on two sockets at once, so both are polled
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            for socket in [&self.socket, ipv4] {
+                match socket.recv_from(buf) {
+                    Ok((len, peer)) => return Ok((len, unmapped(peer))),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            let pause = Duration::from_millis(1);
+            match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "no datagram before timeout"));
+                    }
+                    thread::sleep(pause.min(left));
+                }
+                None => thread::sleep(pause),
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+/// v4-mapped IPv6 addresses as the IPv4 address they stand for
+fn unmapped(peer: SocketAddr) -> SocketAddr {
+    match peer {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => peer,
+        },
+        SocketAddr::V4(_) => peer,
+    }
+}
+
+/// index of the network interface `name`, the scope id of IPv6 link-local
+/// addresses on it; a number is taken as the index itself
+///
+/// names are looked up in /sys/class/net, std has no portable
+/// if_nametoindex, so elsewhere only numeric indexes work
+pub fn interface_index(name: &str) -> io::Result<u32> {
+    if let Ok(index) = name.parse() {
+        return Ok(index);
+    }
+    if name.is_empty() || name.contains(['/', '.']) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"));
+    }
+    std::fs::read_to_string(format!("/sys/class/net/{}/ifindex", name))
+        .ok()
+        .and_then(|index| index.trim().parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no interface {}", name)))
+}
+
+type Inbox = Sender<(Vec<u8>, SocketAddr)>;
+
+/// a datagram on its way to a `MemoryTransport`
+struct InFlight {
+    deliver_at: Instant,
+    seq: u64,
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct MemoryInbox {
+    queue: Mutex<Vec<InFlight>>,
+    arrived: Condvar,
+}
+
+/// how datagrams cross a `MemoryNetwork`, shared by its clones
+struct MemoryLinks {
+    /// None delivers everything at once
+    conditions: Option<LinkConditions>,
+    rng: Box<dyn RngCore + Send>,
+    clock: Box<dyn Clock>,
+    seq: u64,
+    stats: SimulatorStats,
+}
+
+impl Default for MemoryLinks {
+    fn default() -> Self {
+        MemoryLinks {
+            conditions: None,
+            rng: Box::new(StdRng::from_entropy()),
+            clock: Box::new(SystemClock),
+            seq: 0,
+            stats: SimulatorStats::default(),
+        }
+    }
+}
+
+/// in-process network for tests, delivering datagrams between the
+/// `MemoryTransport`s bound on it without touching any socket
+///
+/// by default every datagram arrives at once; with `set_conditions` they
+/// are lost, duplicated, reordered and delayed like in the `Simulator`,
+/// so the real client and server can be tested over a bad link
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    endpoints: Arc<Mutex<HashMap<SocketAddr, Arc<MemoryInbox>>>>,
+    links: Arc<Mutex<MemoryLinks>>,
+}
+
+impl MemoryNetwork {
+
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    /// conditions of every link, None for a perfect network
+    pub fn set_conditions(&self, conditions: Option<LinkConditions>) {
+        self.links.lock().unwrap().conditions = conditions;
+    }
+
+    /// source of loss, duplication and delay decisions
+    pub fn set_rng<R: RngCore + Send + 'static>(&self, rng: R) {
+        self.links.lock().unwrap().rng = Box::new(rng);
+    }
+
+    /// time datagrams are delayed on, and receive timeouts measured on;
+    /// the transports wait in real time until it reaches them
+    pub fn set_clock<C: Clock + 'static>(&self, clock: C) {
+        self.links.lock().unwrap().clock = Box::new(clock);
+    }
+
+    pub fn get_stats(&self) -> SimulatorStats {
+        self.links.lock().unwrap().stats
+    }
+
+    fn now(&self) -> Instant {
+        self.links.lock().unwrap().clock.now()
+    }
+
+    /// port 0 picks a free port on 127.0.0.1
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<MemoryTransport> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let addr = match addr.port() {
+            0 => (1..=u16::MAX)
+                .map(|port| SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))
+                .find(|addr| !endpoints.contains_key(addr))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "no free port"))?,
+            _ if endpoints.contains_key(&addr) => return Err(io::Error::new(io::ErrorKind::AddrInUse, "address in use")),
+            _ => addr,
+        };
+        let inbox = Arc::new(MemoryInbox::default());
+        endpoints.insert(addr, inbox.clone());
+        Ok(MemoryTransport { network: self.clone(), addr, inbox })
+    }
+}
+
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    addr: SocketAddr,
+    inbox: Arc<MemoryInbox>,
+}
+
+impl Transport for MemoryTransport {
+    /// like UDP, a datagram to an address nobody is bound on is lost
+    fn send_to(&self, data: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        let Some(inbox) = self.network.endpoints.lock().unwrap().get(&peer).cloned() else {
+            return Ok(data.len());
+        };
+        let copies: Vec<InFlight> = {
+            let mut links = self.network.links.lock().unwrap();
+            let links = &mut *links;
+            let now = links.clock.now();
+            let delays = match links.conditions {
+                Some(conditions) => conditions.schedule(data.len(), &mut *links.rng, &mut links.stats),
+                None => {
+                    links.stats.sent += 1;
+                    vec![Duration::ZERO]
+                }
+            };
+            delays.into_iter()
+                .map(|delay| {
+                    links.seq += 1;
+                    InFlight { deliver_at: now + delay, seq: links.seq, from: self.addr, data: data.to_vec() }
+                })
+                .collect()
+        };
+        // the links are not locked here, as recv_from reads the clock holding the queue
+        inbox.queue.lock().unwrap().extend(copies);
+        inbox.arrived.notify_all();
+        Ok(data.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<(usize, SocketAddr)> {
+        let deadline = timeout.map(|timeout| self.network.now() + timeout);
+        let mut queue = self.inbox.queue.lock().unwrap();
+        loop {
+            let now = self.network.now();
+            let due = queue.iter().enumerate()
+                .filter(|(_, datagram)| datagram.deliver_at <= now)
+                .min_by_key(|(_, datagram)| (datagram.deliver_at, datagram.seq))
+                .map(|(i, _)| i);
+            if let Some(i) = due {
+                let datagram = queue.swap_remove(i);
+                self.network.links.lock().unwrap().stats.delivered += 1;
+                // datagram semantics: what does not fit the buffer is cut off
+                let len = datagram.data.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram.data[..len]);
+                return Ok((len, datagram.from));
+            }
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            // woken by a new datagram, or in a while to look at the clock again
+            queue = self.inbox.arrived.wait_timeout(queue, Duration::from_millis(1)).unwrap().0;
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network.endpoints.lock().unwrap().remove(&self.addr);
+    }
+}
+
+/// signaling codes of RFC 8323 section 5
+const CSM: u8 = 0xE1;
+const PING: u8 = 0xE2;
+const PONG: u8 = 0xE3;
+const RELEASE: u8 = 0xE4;
+const ABORT: u8 = 0xE5;
+/// option of an Abort naming the CSM option that caused it, RFC 8323 section 5.6.1
+const BAD_CSM_OPTION: u16 = 2;
+
+/// what a side of a TCP connection accepts, announced in its 7.01 CSM
+/// (RFC 8323 section 5.3); the defaults hold until the peer's CSM arrives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csm {
+    pub max_message_size: usize,
+    pub block_wise_transfer: bool,
+    /// longest token, RFC 8974 section 2.2.1
+    pub max_token_length: usize,
+}
+
+impl Default for Csm {
+    fn default() -> Self {
+        Csm {
+            max_message_size: MAX_MESSAGE_SIZE,
+            block_wise_transfer: false,
+            max_token_length: MAX_TOKEN_LEN,
+        }
+    }
+}
+
+impl Csm {
+
+    fn to_frame(self) -> CoAPFrame {
+        let mut options = BTreeMap::new();
+        options.insert(u16::from(CsmOption::MaxMessageSize), vec![uint_to_bytes(self.max_message_size as u32)]);
+        if self.block_wise_transfer {
+            options.insert(u16::from(CsmOption::BlockWiseTransfer), vec![vec![]]);
+        }
+        options.insert(u16::from(CsmOption::ExtendedTokenLength), vec![uint_to_bytes(self.max_token_length as u32)]);
+        CoAPFrame::new(Header::new(MessageType::Con.into(), CSM), options, vec![])
+    }
+
+    /// a later CSM only changes the settings it carries; a Max-Message-Size
+    /// below the 1152 bytes every peer takes is ignored, an unknown critical
+    /// (odd) option is returned for the Abort it calls for, RFC 8323 section 5.3
+    fn update(&mut self, frame: &CoAPFrame) -> Result<(), u16> {
+        for (number, values) in frame.get_options() {
+            let value = values.last().map(|value| bytes_to_uint(value) as usize).unwrap_or_default();
+            match CsmOption::from(number) {
+                CsmOption::MaxMessageSize if value >= MAX_MESSAGE_SIZE => self.max_message_size = value,
+                CsmOption::MaxMessageSize => {}
+                CsmOption::BlockWiseTransfer => self.block_wise_transfer = true,
+                CsmOption::ExtendedTokenLength => self.max_token_length = value.clamp(MAX_TOKEN_LEN, MAX_EXTENDED_TOKEN_LEN),
+                CsmOption::Unknown(number) if number % 2 == 1 => return Err(number),
+                // elective options we do not know are ignored
+                CsmOption::Unknown(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// how long a write to a TCP connection may block before the connection
+/// is given up on
+pub const TCP_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// one connection of a `TcpTransport`
+struct Connection {
+    /// locked per connection, so a slow peer holds up nobody else
+    writer: Writer,
+    /// settings of the peer, from its latest CSM
+    peer: Csm,
+    /// message ids of CoAP pings sent as 7.02 Ping, answered in order
+    pings: VecDeque<u16>,
+}
+
+type Connections = Arc<Mutex<HashMap<SocketAddr, Connection>>>;
+
+type Writer = Arc<Mutex<TcpStream>>;
+
+/// write one whole message; a failed or timed out write may have left part
+/// of it on the stream, so the connection is shut down
+fn write_message(writer: &Writer, message: &[u8]) -> io::Result<()> {
+    let stream = writer.lock().unwrap();
+    (&*stream).write_all(message).inspect_err(|_| {
+        let _ = stream.shutdown(Shutdown::Both);
+    })
+}
+
+/// CoAP over TCP, RFC 8323
+///
+/// the message layer above keeps speaking the UDP format and this
+/// transport translates at the connection: messages go out in the RFC 8323
+/// framing without type and message id, and a CON counts as acknowledged
+/// once written; empty ACKs and RSTs are not sent, and a CoAP ping becomes
+/// a 7.02 Ping whose 7.03 Pong is handed up as the RST. received messages
+/// are handed up as NON with a message id of their own. each connection
+/// opens with a 7.01 CSM and ends on a 7.04 Release or 7.05 Abort
+///
+/// messages above the Max-Message-Size or with tokens above the
+/// Extended-Token-Length the peer announced are refused with
+/// `ErrorKind::InvalidInput`; received ones above our own end the
+/// connection with an Abort
+pub struct TcpTransport {
+    local_addr: SocketAddr,
+    connections: Connections,
+    /// settings announced to every peer
+    csm: Arc<Mutex<Csm>>,
+    receiver: Mutex<Receiver<(Vec<u8>, SocketAddr)>>,
+    inbox: Inbox,
+}
+
+impl TcpTransport {
+
+    /// accept connections from any number of peers
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<TcpTransport> {
+        let listener = TcpListener::bind(addr)?;
+        let transport = TcpTransport::new(listener.local_addr()?);
+        let connections = transport.connections.clone();
+        let csm = transport.csm.clone();
+        let inbox = transport.inbox.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = TcpTransport::add_stream(&connections, &csm, &inbox, stream);
+            }
+        });
+        Ok(transport)
+    }
+
+    /// connect to a single server, messages can only be sent to its address
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpTransport> {
+        let stream = TcpStream::connect(addr)?;
+        let transport = TcpTransport::new(stream.local_addr()?);
+        TcpTransport::add_stream(&transport.connections, &transport.csm, &transport.inbox, stream)?;
+        Ok(transport)
+    }
+
+    /// settings announced to peers, by default the sizes of RFC 7252 and
+    /// block-wise transfer; sent again on open connections when changed
+    pub fn set_csm(&self, csm: Csm) -> io::Result<()> {
+        *self.csm.lock().unwrap() = csm;
+        let writers: Vec<Writer> = self.connections.lock().unwrap().values()
+            .map(|connection| connection.writer.clone())
+            .collect();
+        let message = csm.to_frame().to_tcp_bytes();
+        writers.iter().try_for_each(|writer| write_message(writer, &message))
+    }
+
+    pub fn get_csm(&self) -> Csm {
+        *self.csm.lock().unwrap()
+    }
+
+    /// settings `peer` announced, None without a connection to it
+    pub fn get_peer_csm(&self, peer: SocketAddr) -> Option<Csm> {
+        self.connections.lock().unwrap().get(&peer).map(|connection| connection.peer)
+    }
+
+    fn new(local_addr: SocketAddr) -> TcpTransport {
+        let (inbox, receiver) = mpsc::channel();
+        TcpTransport {
+            local_addr,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            csm: Arc::new(Mutex::new(Csm { block_wise_transfer: true, ..Csm::default() })),
+            receiver: Mutex::new(receiver),
+            inbox,
+        }
+    }
+
+    /// send our CSM and read messages from `stream` on a thread of its own
+    /// until it closes
+    fn add_stream(connections: &Connections, csm: &Arc<Mutex<Csm>>, inbox: &Inbox, stream: TcpStream) -> io::Result<()> {
+        let peer = stream.peer_addr()?;
+        stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT))?;
+        let mut reader = stream.try_clone()?;
+        let writer = Arc::new(Mutex::new(stream));
+        let own_csm = csm.lock().unwrap().to_frame().to_tcp_bytes();
+        write_message(&writer, &own_csm)?;
+        connections.lock().unwrap().insert(peer, Connection { writer: writer.clone(), peer: Csm::default(), pings: VecDeque::new() });
+        let connections = connections.clone();
+        let csm = csm.clone();
+        let inbox = inbox.clone();
+        thread::spawn(move || {
+            let mut msg_id: u16 = 0;
+            loop {
+                let max_message_size = csm.lock().unwrap().max_message_size;
+                let frame = match read_message(&mut reader, max_message_size) {
+                    Ok(message) => CoAPFrame::from_tcp_bytes(&message, MessageType::Non, msg_id)
+                        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData)),
+                    Err(err) => Err(err),
+                };
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        // a peer breaking the framing is told so before the connection ends
+                        if err.kind() == io::ErrorKind::InvalidData {
+                            let _ = write_message(&writer, &signal(ABORT, vec![]).to_tcp_bytes());
+                        }
+                        break;
+                    }
+                };
+                msg_id = msg_id.wrapping_add(1);
+                match frame.header.get_code() {
+                    PING => {
+                        if write_message(&writer, &signal(PONG, frame.get_token()).to_tcp_bytes()).is_err() {
+                            break;
+                        }
+                    }
+                    PONG => {
+                        let ping = connections.lock().unwrap().get_mut(&peer).and_then(|connection| connection.pings.pop_front());
+                        if let Some(ping) = ping {
+                            let _ = inbox.send((CoAPFrame::empty(MessageType::Rst, ping).to_bytes(), peer));
+                        }
+                    }
+                    CSM => {
+                        let update = connections.lock().unwrap().get_mut(&peer).map(|connection| connection.peer.update(&frame));
+                        if let Some(Err(number)) = update {
+                            let options = BTreeMap::from([(BAD_CSM_OPTION, vec![uint_to_bytes(number as u32)])]);
+                            let abort = CoAPFrame::new(Header::new(MessageType::Con.into(), ABORT), options, vec![]);
+                            let _ = write_message(&writer, &abort.to_tcp_bytes());
+                            break;
+                        }
+                    }
+                    RELEASE | ABORT => break,
+                    // unknown signals are ignored
+                    code if code >> 5 == 7 => {}
+                    _ => {
+                        if inbox.send((frame.to_bytes(), peer)).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            connections.lock().unwrap().remove(&peer);
+            let _ = reader.shutdown(Shutdown::Both);
+        });
+        Ok(())
+    }
+}
+
+/// signaling message with `code`, RFC 8323 section 5
+fn signal(code: u8, token: Vec<u8>) -> CoAPFrame {
+    let mut frame = CoAPFrame::new(Header::new(MessageType::Con.into(), code), BTreeMap::new(), vec![]);
+    // the token of a received message, so one that can be encoded
+    let _ = frame.set_token(token);
+    frame
+}
+
+/// read one RFC 8323 message, anything above `max` bytes is invalid
+fn read_message<R: Read>(reader: &mut R, max: usize) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+    let mut message = vec![0u8; 1];
+    reader.read_exact(&mut message)?;
+    let head_len = tcp_head_len(message[0]).ok_or_else(invalid)?;
+    message.resize(head_len, 0);
+    reader.read_exact(&mut message[1..])?;
+    let len = tcp_message_len(&message).map_err(|_| invalid())?;
+    if len > max {
+        return Err(invalid());
+    }
+    message.resize(len, 0);
+    reader.read_exact(&mut message[head_len..])?;
+    Ok(message)
+}
+
+impl Transport for TcpTransport {
+    fn send_to(&self, data: &[u8], peer: SocketAddr) -> io::Result<usize> {
+        let frame = CoAPFrame::from_bytes(data.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not a CoAP message"))?;
+        let message_type = MessageType::try_from(frame.header.get_type());
+        let is_ping = matches!(message_type, Ok(MessageType::Con)) && frame.is_empty_message();
+        // the connections are not locked while writing
+        let (writer, peer_csm) = {
+            let mut connections = self.connections.lock().unwrap();
+            let connection = connections.get_mut(&peer)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no connection to peer"))?;
+            if is_ping {
+                connection.pings.push_back(frame.header.get_msg_id());
+            }
+            (connection.writer.clone(), connection.peer)
+        };
+        if frame.get_token().len() > peer_csm.max_token_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "token longer than the peer accepts"));
+        }
+        match message_type {
+            // the connection already acknowledges, and a reset has nothing to say
+            Ok(MessageType::Ack | MessageType::Rst) if frame.is_empty_message() => {}
+            _ if is_ping => write_message(&writer, &signal(PING, vec![]).to_tcp_bytes())?,
+            message_type => {
+                let message = frame.to_tcp_bytes();
+                if message.len() > peer_csm.max_message_size {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "message larger than the peer accepts"));
+                }
+                write_message(&writer, &message)?;
+                if let Ok(MessageType::Con) = message_type {
+                    let ack = CoAPFrame::empty(MessageType::Ack, frame.header.get_msg_id());
+                    let _ = self.inbox.send((ack.to_bytes(), peer));
+                }
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Option<Duration>) -> io::Result<(usize, SocketAddr)> {
+        let receiver = self.receiver.lock().unwrap();
+        let (data, from) = match timeout {
+            Some(timeout) => receiver.recv_timeout(timeout).map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?,
+            None => receiver.recv().map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?,
+        };
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, io::{self, Read, Write}, net::{TcpListener, TcpStream}, sync::Arc, thread, time::{Duration, Instant}};
+
+    use crate::{
+        frame::{CoAPFrame, Header, MessageType},
+        request::RequestMethod,
+        transport::{interface_index, Csm, MemoryNetwork, TcpTransport, Transport, UdpTransport},
+    };
+
+    #[test]
+    fn dual_udp_reaches_both_families() {
+        let dual = UdpTransport::dual().unwrap();
+        let mut peers = vec![UdpTransport::bind("127.0.0.1:0").unwrap()];
+        // hosts without IPv6 have only the IPv4 side
+        peers.extend(UdpTransport::bind("[::1]:0"));
+        let mut buf = [0u8; 16];
+        for peer in peers {
+            let addr = peer.local_addr().unwrap();
+            dual.send_to(b"ping", addr).unwrap();
+            let (len, from) = peer.recv_from(&mut buf, Some(Duration::from_secs(1))).unwrap();
+            assert_eq!(&buf[..len], b"ping");
+            assert_eq!(from.is_ipv4(), addr.is_ipv4());
+            peer.send_to(b"pong", from).unwrap();
+            let (len, from) = dual.recv_from(&mut buf, Some(Duration::from_secs(1))).unwrap();
+            assert_eq!(&buf[..len], b"pong");
+            assert_eq!(from, addr);
+        }
+        assert!(dual.recv_from(&mut buf, Some(Duration::from_millis(10))).is_err());
+    }
+
+    #[test]
+    fn memory_datagrams() {
+        let network = MemoryNetwork::new();
+        let a = network.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let b = network.bind("127.0.0.1:5683".parse().unwrap()).unwrap();
+        assert!(network.bind("127.0.0.1:5683".parse().unwrap()).is_err());
+
+        a.send_to(b"ping", b.local_addr().unwrap()).unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = b.recv_from(&mut buf, Some(Duration::from_millis(100))).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, a.local_addr().unwrap());
+        assert!(b.recv_from(&mut buf, Some(Duration::from_millis(10))).is_err());
+    }
+
+    #[test]
+    fn interface_indexes() {
+        assert_eq!(interface_index("3").unwrap(), 3);
+        assert!(interface_index("../lo").is_err());
+        // the loopback interface is there on any Linux box
+        if cfg!(target_os = "linux") {
+            assert!(interface_index("lo").unwrap() > 0);
+        }
+    }
+
+    #[test]
+    fn tcp_frames() {
+        let server = TcpTransport::listen("127.0.0.1:0").unwrap();
+        let client = TcpTransport::connect(server.local_addr().unwrap()).unwrap();
+        let mut buf = [0u8; 64];
+        let mut recv = |transport: &TcpTransport| {
+            let (len, peer) = transport.recv_from(&mut buf, Some(Duration::from_secs(1))).unwrap();
+            (CoAPFrame::from_bytes(buf[..len].to_vec()).unwrap(), peer)
+        };
+
+        // a CON is acknowledged as soon as the connection took it
+        let mut header = Header::new(MessageType::Con.into(), RequestMethod::Get as u8);
+        header.set_msg_id(40);
+        let mut request = CoAPFrame::new(header, BTreeMap::new(), vec![]);
+        request.set_token(vec![1, 2]).unwrap();
+        client.send_to(&request.to_bytes(), server.local_addr().unwrap()).unwrap();
+        let (ack, _) = recv(&client);
+        assert_eq!(ack.header.get_type(), u8::from(MessageType::Ack));
+        assert_eq!(ack.header.get_msg_id(), 40);
+
+        // and arrives without type and message id, handed up as NON
+        let (received, peer) = recv(&server);
+        assert_eq!(received.header.get_type(), u8::from(MessageType::Non));
+        assert_eq!(received.get_token(), vec![1, 2]);
+        let mut header = Header::new(MessageType::Ack.into(), 0x45);
+        header.set_msg_id(received.header.get_msg_id());
+        let mut response = CoAPFrame::new(header, BTreeMap::new(), b"back".to_vec());
+        response.set_token(vec![1, 2]).unwrap();
+        server.send_to(&response.to_bytes(), peer).unwrap();
+        let (received, _) = recv(&client);
+        assert_eq!(received.get_body(), b"back");
+
+        // a CoAP ping goes as 7.02 Ping and its Pong comes back as the RST
+        client.send_to(&CoAPFrame::empty(MessageType::Con, 41).to_bytes(), server.local_addr().unwrap()).unwrap();
+        let (pong, _) = recv(&client);
+        assert_eq!(pong.header.get_type(), u8::from(MessageType::Rst));
+        assert_eq!(pong.header.get_msg_id(), 41);
+    }
+
+    #[test]
+    fn tcp_capabilities() {
+        let server = TcpTransport::listen("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let client = TcpTransport::connect(addr).unwrap();
+        let csm = Csm { max_message_size: 1200, max_token_length: 300, ..server.get_csm() };
+        server.set_csm(csm).unwrap();
+        // the CSM is sent again on the open connection
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while client.get_peer_csm(addr) != Some(csm) {
+            assert!(Instant::now() < deadline, "{:?}", client.get_peer_csm(addr));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut request = CoAPFrame::new(Header::new(MessageType::Non.into(), RequestMethod::Post as u8), BTreeMap::new(), vec![b'x'; 1000]);
+        request.set_token(vec![1; 300]).unwrap();
+        let err = client.send_to(&request.to_bytes(), addr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        request = CoAPFrame::new(Header::new(MessageType::Non.into(), RequestMethod::Post as u8), BTreeMap::new(), vec![b'x'; 10]);
+        request.set_token(vec![1; 301]).unwrap();
+        assert!(client.send_to(&request.to_bytes(), addr).is_err());
+        request.set_token(vec![1; 30]).unwrap();
+        client.send_to(&request.to_bytes(), addr).unwrap();
+        let mut buf = [0u8; 64];
+        let (len, _) = server.recv_from(&mut buf, Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(CoAPFrame::from_bytes(buf[..len].to_vec()).unwrap().get_body(), vec![b'x'; 10]);
+    }
+
+    #[test]
+    fn tcp_messages_in_rfc_8323_framing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpTransport::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        // the connection opens with a CSM: Max-Message-Size 1152,
+        // Block-Wise-Transfer and Extended-Token-Length 8
+        let mut csm = [0u8; 8];
+        stream.read_exact(&mut csm).unwrap();
+        assert_eq!(csm, [0x60, 0xE1, 0x22, 0x04, 0x80, 0x20, 0x21, 0x08]);
+
+        // NON 0.01 with token 0x07 and Uri-Path "a"
+        stream.write_all(&[0x21, 0x01, 0x07, 0xB1, b'a']).unwrap();
+        let mut buf = [0u8; 64];
+        let (len, _) = client.recv_from(&mut buf, Some(Duration::from_secs(1))).unwrap();
+        let request = CoAPFrame::from_bytes(buf[..len].to_vec()).unwrap();
+        assert_eq!(request.get_options()[&11], vec![b"a".to_vec()]);
+
+        // a length the peer did not agree to ends the connection with 7.05 Abort
+        stream.write_all(&[0xF0, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]).unwrap();
+        let mut abort = [0u8; 2];
+        stream.read_exact(&mut abort).unwrap();
+        assert_eq!(abort, [0x00, 0xE5]);
+    }
+
+    #[test]
+    fn csm_options() {
+        let csm_with = |options: Vec<(u16, Vec<u8>)>| {
+            let options = options.into_iter().map(|(number, value)| (number, vec![value])).collect();
+            CoAPFrame::new(Header::new(MessageType::Con.into(), 0xE1), options, vec![])
+        };
+        let mut csm = Csm::default();
+        // an empty or too small Max-Message-Size is ignored
+        assert_eq!(csm.update(&csm_with(vec![(2, vec![])])), Ok(()));
+        assert_eq!(csm.update(&csm_with(vec![(2, vec![64])])), Ok(()));
+        assert_eq!(csm.max_message_size, 1152);
+        assert_eq!(csm.update(&csm_with(vec![(2, vec![0x08, 0x00]), (8, vec![1])])), Ok(()));
+        assert_eq!(csm.max_message_size, 2048);
+        assert_eq!(csm.update(&csm_with(vec![(9, vec![])])), Err(9));
+
+        // and the connection ends with an Abort naming it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpTransport::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        stream.read_exact(&mut [0u8; 8]).unwrap();
+        stream.write_all(&[0x10, 0xE1, 0x90]).unwrap();
+        let mut abort = [0u8; 4];
+        stream.read_exact(&mut abort).unwrap();
+        assert_eq!(abort, [0x20, 0xE5, 0x21, 0x09]);
+    }
+
+    #[test]
+    fn stalled_tcp_peers_hold_up_nobody_else() {
+        let server = TcpTransport::listen("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        // never reads, so writes to it block once the buffers are full
+        let stalled = TcpStream::connect(addr).unwrap();
+        let stalled_addr = stalled.local_addr().unwrap();
+        let client = TcpTransport::connect(addr).unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while server.get_peer_csm(stalled_addr).is_none() || server.get_peer_csm(client_addr).is_none() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let server = Arc::new(server);
+        let flooding = server.clone();
+        thread::spawn(move || {
+            let flood = CoAPFrame::new(Header::new(MessageType::Non.into(), RequestMethod::Post as u8), BTreeMap::new(), vec![b'x'; 1000]);
+            while flooding.send_to(&flood.to_bytes(), stalled_addr).is_ok() {}
+        });
+        thread::sleep(Duration::from_millis(300));
+
+        let start = Instant::now();
+        let request = CoAPFrame::new(Header::new(MessageType::Non.into(), RequestMethod::Get as u8), BTreeMap::new(), vec![b'y']);
+        server.send_to(&request.to_bytes(), client_addr).unwrap();
+        let mut buf = [0u8; 64];
+        let (len, _) = client.recv_from(&mut buf, Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(CoAPFrame::from_bytes(buf[..len].to_vec()).unwrap().get_body(), b"y");
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drop(stalled);
+    }
+}
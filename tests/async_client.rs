@@ -0,0 +1,70 @@
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use std::{io, thread, time::{Duration, Instant}};
+
+use coap::{
+    async_client::AsyncCoapClient,
+    no_response::NoResponse,
+    request::{Request, RequestMethod},
+    response::{Response, ResponseCode},
+};
+
+use common::{body_len, serve};
+
+#[tokio::test]
+async fn requests_on_tokio() {
+    let base = serve(|req: &Request| {
+        let mut response = Response::new(ResponseCode::Content);
+        match req.get_path().as_str() {
+            "large" => response.set_body(vec![b'l'; 3000]),
+            "slow" => {
+                thread::sleep(Duration::from_millis(200));
+                response.set_body(b"slow".to_vec());
+            }
+            _ => response.set_body(format!("{} {}", req.get_path(), req.get_body().len()).into_bytes()),
+        }
+        response
+    });
+    let client = AsyncCoapClient::bind("127.0.0.1:0").await.unwrap();
+    assert_eq!(client.get(&format!("{}/hello", base)).await.unwrap().get_body(), b"hello 0");
+    // block-wise both ways
+    assert_eq!(client.post(&format!("{}/upload", base), vec![b'x'; 3000]).await.unwrap().get_body(), b"upload 3000");
+    assert_eq!(client.get(&format!("{}/large", base)).await.unwrap().get_body(), &vec![b'l'; 3000]);
+    assert!(client.ping(&base).await.is_ok());
+
+    // requests in flight together share the driver
+    let slow = format!("{}/slow", base);
+    let fast = format!("{}/fast", base);
+    let (slow, fast) = tokio::join!(client.get(&slow), client.get(&fast));
+    assert_eq!(slow.unwrap().get_body(), b"slow");
+    assert_eq!(fast.unwrap().get_body(), b"fast 0");
+}
+
+#[tokio::test]
+async fn unanswered_requests_time_out() {
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut client = AsyncCoapClient::bind("127.0.0.1:0").await.unwrap();
+    client.set_timeout(100);
+    let err = client.get(&format!("coap://{}/", silent.local_addr().unwrap())).await.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+async fn no_response_and_small_blocks_on_tokio() {
+    let base = serve(body_len);
+    let mut client = AsyncCoapClient::bind("127.0.0.1:0").await.unwrap();
+    client.set_block1_size(16);
+    client.set_no_response_wait(Duration::from_millis(300));
+    let uri = format!("{}/upload", base);
+    let res = client.post(&uri, vec![b'x'; 100]).await.unwrap();
+    assert_eq!(res.get_body(), b"100");
+
+    let start = Instant::now();
+    let mut req = client.new_request(RequestMethod::Post, &uri).unwrap();
+    req.set_body(vec![b'x'; 100]);
+    req.set_no_response(NoResponse::SUCCESS);
+    assert!(client.send_request(req).await.unwrap().is_none());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
@@ -0,0 +1,227 @@
+mod common;
+
+use std::{
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use coap::{
+    frame::{MessageType, OptionEnum},
+    no_response::NoResponse,
+    q_block::QBlockParameters,
+    request::{CoapClient, Request, RequestMethod},
+    response::{Response, ResponseCode},
+    server::CoapServer,
+    transmission::{CongestionParameters, TransmissionParameters},
+    transport::Transport,
+};
+
+use common::{body_len, echo_body, hello, serve, spawn};
+
+#[test]
+fn client_request_round_trip() {
+    let base = serve(hello);
+    let res = CoapClient::new().get(&format!("{}/a/b", base)).unwrap();
+    assert_eq!(res.get_response_code(), ResponseCode::Content);
+    assert_eq!(res.get_body(), b"hello a/b");
+    // a bad URI is an error, not a panic
+    assert!(CoapClient::new().get("coap://[::1/a").is_err());
+}
+
+#[test]
+fn one_client_for_many_servers() {
+    let uris: Vec<String> = (0..2).map(|i| format!("{}/s{}", serve(hello), i)).collect();
+    let client = Arc::new(CoapClient::new());
+    let local = client.get_transport().local_addr().unwrap();
+    // threads sharing the client each get their own response
+    let workers: Vec<_> = uris.into_iter().enumerate().map(|(i, uri)| {
+        let client = client.clone();
+        thread::spawn(move || for _ in 0..5 {
+            assert_eq!(client.get(&uri).unwrap().get_body(), format!("hello s{}", i).as_bytes());
+        })
+    }).collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert_eq!(client.get_transport().local_addr().unwrap(), local);
+}
+
+#[test]
+fn client_over_ipv6() {
+    let addr = spawn(CoapServer::bind("[::1]:0").unwrap(), hello);
+    let uri = format!("coap://{}/v6", addr);
+    let client = CoapClient::new();
+    assert!(client.get_transport().local_addr().unwrap().is_ipv6());
+    assert_eq!(client.get(&uri).unwrap().get_body(), b"hello v6");
+
+    let bound = CoapClient::bind("[::1]:0").unwrap();
+    assert_eq!(bound.get(&uri).unwrap().get_body(), b"hello v6");
+}
+
+#[test]
+fn client_fire_and_forget() {
+    let uri = format!("{}/telemetry", serve(|_: &Request| Response::new(ResponseCode::Changed)));
+    let mut client = CoapClient::new();
+    // after an empty ACK a suppressed response is indistinguishable from a
+    // late one, how long to wait for it is bounded apart from the timeout
+    client.set_no_response_wait(Duration::from_millis(300));
+    let start = Instant::now();
+    // a peer that does not answer NON requests gets them at PROBING_RATE
+    client.set_congestion_parameters(CongestionParameters { probing_rate: 100_000, ..Default::default() });
+    let res = client.request_no_response(RequestMethod::Post, &uri, vec![1], NoResponse::SUCCESS).unwrap();
+    assert!(res.is_none());
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    client.set_type(MessageType::Non);
+    let res = client.request_no_response(RequestMethod::Post, &uri, vec![2], NoResponse::ALL).unwrap();
+    assert!(res.is_none());
+    let res = client.request_no_response(RequestMethod::Post, &uri, vec![3], NoResponse::CLIENT_ERROR).unwrap();
+    assert_eq!(res.unwrap().get_response_code(), ResponseCode::Changed);
+}
+
+#[test]
+fn client_answers_echo_challenge() {
+    let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+    server.set_echo_freshness(Some(Duration::from_secs(10)));
+    let uri = format!("coap://{}/valve", spawn(server, echo_body));
+    let client = CoapClient::new();
+    let res = client.put(&uri, vec![1, 2, 3]).unwrap();
+    assert_eq!(res.get_response_code(), ResponseCode::Changed);
+    assert_eq!(res.get_body(), &vec![1, 2, 3]);
+    // safe methods are not challenged
+    assert_eq!(client.get(&uri).unwrap().get_response_code(), ResponseCode::Changed);
+}
+
+#[test]
+fn client_sends_large_body_block_wise() {
+    let mut client = CoapClient::new();
+    client.set_block1_size(64);
+    let res = client.post(&format!("{}/firmware", serve(body_len)), vec![0x55; 1000]).unwrap();
+    assert_eq!(res.get_response_code(), ResponseCode::Changed);
+    assert_eq!(res.get_body(), b"1000");
+}
+
+#[test]
+fn bodies_above_max_message_size_go_block_wise() {
+    let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+    server.set_max_message_size(300);
+    let addr = spawn(server, |req: &Request| {
+        let mut response = Response::new(ResponseCode::Content);
+        match req.get_body().is_empty() {
+            true => response.set_body(vec![b'r'; 1000]),
+            false => response.set_body(req.get_body().len().to_string().into_bytes()),
+        }
+        response
+    });
+    let uri = format!("coap://{}/large", addr);
+
+    // within the block size but not within the server's message size
+    let client = CoapClient::new();
+    let res = client.post(&uri, vec![0x55; 600]).unwrap();
+    assert_eq!(res.get_response_code(), ResponseCode::RequestEntityTooLarge);
+    assert_eq!(res.get_option(OptionEnum::Size1), Some(&vec![vec![1, 44]]));
+
+    let mut client = CoapClient::new();
+    client.set_max_message_size(300);
+    assert_eq!(client.post(&uri, vec![0x55; 600]).unwrap().get_body(), b"600");
+    assert_eq!(client.get(&uri).unwrap().get_body(), &vec![b'r'; 1000]);
+}
+
+#[test]
+fn ping_server() {
+    let base = serve(hello);
+    let mut client = CoapClient::new();
+    client.set_transmission_parameters(TransmissionParameters {
+        ack_timeout: Duration::from_millis(200),
+        ..Default::default()
+    });
+    assert!(client.ping(&base).is_ok());
+
+    // nobody is listening on the port of a dropped socket
+    let closed = CoapServer::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut client = CoapClient::new();
+    client.set_transmission_parameters(TransmissionParameters {
+        ack_timeout: Duration::from_millis(10),
+        max_retransmit: 1,
+        ..Default::default()
+    });
+    assert!(client.ping(&format!("coap://{}/", closed)).is_err());
+}
+
+#[test]
+fn client_q_block_transfers() {
+    let body: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+    // uploads are echoed, a GET downloads a large body
+    let echo_or_download = |req: &Request| {
+        let mut response = Response::new(ResponseCode::Content);
+        match req.get_body().is_empty() {
+            true => response.set_body((0..3000u32).map(|i| (i * 7) as u8).collect()),
+            false => response.set_body(req.get_body().clone()),
+        }
+        response
+    };
+    let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+    server.set_q_block(Some(QBlockParameters::default()));
+    let q_block = format!("coap://{}", spawn(server, echo_or_download));
+    // the same client against a server that only knows RFC 7959
+    let plain = serve(echo_or_download);
+
+    for base in [q_block, plain] {
+        let uri = format!("{}/upload", base);
+        let mut client = CoapClient::new();
+        client.set_block1_size(256);
+        client.set_q_block(Some(QBlockParameters::default()));
+        let res = client.post(&uri, body.clone()).unwrap();
+        assert_eq!(res.get_response_code(), ResponseCode::Content);
+        assert_eq!(res.get_body(), &body);
+
+        let res = client.get(&uri).unwrap();
+        assert_eq!(res.get_body().len(), 3000);
+        assert_eq!(res.get_body()[2999], (2999 * 7) as u8);
+    }
+}
+
+#[test]
+fn client_cache_freshness_and_validation() {
+    let fetched = Arc::new(AtomicUsize::new(0));
+    let counter = fetched.clone();
+    let base = serve(move |req: &Request| {
+        if req.get_method() == RequestMethod::Put {
+            return Response::new(ResponseCode::Changed);
+        }
+        if req.get_option(OptionEnum::ETag).is_some_and(|tags| tags.contains(&vec![9])) {
+            let mut valid = Response::new(ResponseCode::Valid);
+            valid.set_option(OptionEnum::ETag, vec![9]);
+            valid.set_option(OptionEnum::MaxAge, vec![0]);
+            return valid;
+        }
+        counter.fetch_add(1, Ordering::SeqCst);
+        let mut response = Response::new(ResponseCode::Content);
+        response.set_option(OptionEnum::ETag, vec![9]);
+        let max_age = if req.get_path() == "stale" { 0 } else { 60 };
+        response.set_option(OptionEnum::MaxAge, vec![max_age]);
+        response.set_body(Vec::from(req.get_path()));
+        response
+    });
+
+    let fresh = format!("{}/fresh", base);
+    let stale = format!("{}/stale", base);
+    let mut client = CoapClient::new();
+    client.set_cache(true);
+    assert_eq!(client.get(&fresh).unwrap().get_body(), b"fresh");
+    assert_eq!(client.get(&fresh).unwrap().get_body(), b"fresh");
+    assert_eq!(fetched.load(Ordering::SeqCst), 1);
+
+    for _ in 0..2 {
+        let res = client.get(&stale).unwrap();
+        assert_eq!(res.get_response_code(), ResponseCode::Content);
+        assert_eq!(res.get_body(), b"stale");
+    }
+    assert_eq!(fetched.load(Ordering::SeqCst), 2);
+
+    // a 2.04 to a PUT invalidates what was cached for the resource
+    client.put(&fresh, vec![1]).unwrap();
+    assert_eq!(client.get(&fresh).unwrap().get_body(), b"fresh");
+    assert_eq!(fetched.load(Ordering::SeqCst), 3);
+}
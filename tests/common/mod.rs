@@ -0,0 +1,51 @@
+// each test crate uses its own part of the helpers
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, thread};
+
+use coap::{
+    request::Request,
+    response::{Response, ResponseCode},
+    server::CoapServer,
+    transport::Transport,
+};
+
+/// run `server` on a thread of its own, returns the address it listens on
+pub fn spawn<T, F>(mut server: CoapServer<T>, handler: F) -> SocketAddr
+where
+    T: Transport + Send + 'static,
+    F: Fn(&Request) -> Response + Send + 'static,
+{
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run(handler));
+    addr
+}
+
+/// UDP server on loopback running `handler`, returns the URI of its root
+pub fn serve<F>(handler: F) -> String
+where
+    F: Fn(&Request) -> Response + Send + 'static,
+{
+    format!("coap://{}", spawn(CoapServer::bind("127.0.0.1:0").unwrap(), handler))
+}
+
+/// greets with the path of the request
+pub fn hello(req: &Request) -> Response {
+    let mut response = Response::new(ResponseCode::Content);
+    response.set_body(format!("hello {}", req.get_path()).into_bytes());
+    response
+}
+
+/// answers with the body of the request
+pub fn echo_body(req: &Request) -> Response {
+    let mut response = Response::new(ResponseCode::Changed);
+    response.set_body(req.get_body().clone());
+    response
+}
+
+/// answers with the length of the body of the request
+pub fn body_len(req: &Request) -> Response {
+    let mut response = Response::new(ResponseCode::Changed);
+    response.set_body(req.get_body().len().to_string().into_bytes());
+    response
+}
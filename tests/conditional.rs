@@ -0,0 +1,83 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use coap::{
+    frame::OptionEnum,
+    request::{CoapClient, Request, RequestMethod},
+    response::{Response, ResponseCode},
+    server::CoapServer,
+};
+
+use common::spawn;
+
+#[test]
+fn conditional_requests() {
+    // version of the single resource, None until it is created
+    let version: Arc<Mutex<Option<u8>>> = Arc::new(Mutex::new(None));
+    let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+    let current = version.clone();
+    server.set_etag_provider(move |_: &Request| current.lock().unwrap().map(|v| vec![v]));
+    let state = version.clone();
+    let addr = spawn(server, move |req: &Request| {
+        let mut version = state.lock().unwrap();
+        match req.get_method() {
+            RequestMethod::Deleted => {
+                *version = None;
+                Response::new(ResponseCode::Deleted)
+            }
+            _ => {
+                let next = version.map_or(1, |v| v + 1);
+                *version = Some(next);
+                let mut response = Response::new(ResponseCode::Changed);
+                response.set_option(OptionEnum::ETag, vec![next]);
+                response
+            }
+        }
+    });
+
+    let uri = format!("coap://{}/config", addr);
+    let client = CoapClient::new();
+    assert_eq!(client.put_if_none_match(&uri, vec![1]).unwrap().get_response_code(), ResponseCode::Changed);
+    assert_eq!(client.put_if_none_match(&uri, vec![1]).unwrap().get_response_code(), ResponseCode::PreconditionFailed);
+    assert_eq!(client.put_if_match(&uri, vec![2], vec![1]).unwrap().get_response_code(), ResponseCode::Changed);
+    // somebody else's update got in between
+    assert_eq!(client.put_if_match(&uri, vec![3], vec![1]).unwrap().get_response_code(), ResponseCode::PreconditionFailed);
+    assert_eq!(client.delete_if_match(&uri, vec![1]).unwrap().get_response_code(), ResponseCode::PreconditionFailed);
+    assert_eq!(client.delete_if_match(&uri, vec![2]).unwrap().get_response_code(), ResponseCode::Deleted);
+    assert_eq!(*version.lock().unwrap(), None);
+}
+
+#[test]
+fn server_answers_valid_for_current_etag() {
+    let reading = Arc::new(Mutex::new(String::from("21.5")));
+    let mut server = CoapServer::bind("127.0.0.1:0").unwrap();
+    server.set_auto_etag(true);
+    let current = reading.clone();
+    let addr = spawn(server, move |_: &Request| {
+        let mut response = Response::new(ResponseCode::Content);
+        response.set_body(current.lock().unwrap().clone().into_bytes());
+        response
+    });
+
+    let client = CoapClient::new();
+    let uri = format!("coap://{}/temp", addr);
+    let get = |etag: Option<&Vec<u8>>| {
+        let mut req = client.new_request(RequestMethod::Get, &uri).unwrap();
+        if let Some(etag) = etag {
+            req.add_option(OptionEnum::ETag, etag.clone());
+        }
+        client.send_request(req).unwrap().unwrap()
+    };
+    let first = get(None);
+    let etag = first.get_option(OptionEnum::ETag).unwrap()[0].clone();
+    let res = get(Some(&etag));
+    assert_eq!(res.get_response_code(), ResponseCode::Valid);
+    assert!(res.get_body().is_empty());
+
+    *reading.lock().unwrap() = String::from("22.0");
+    let res = get(Some(&etag));
+    assert_eq!(res.get_response_code(), ResponseCode::Content);
+    assert_eq!(res.get_body(), b"22.0");
+    assert_ne!(res.get_option(OptionEnum::ETag).unwrap()[0], etag);
+}
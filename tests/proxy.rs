@@ -0,0 +1,95 @@
+mod common;
+
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+use coap::{
+    frame::OptionEnum,
+    hop_limit,
+    proxy::ForwardProxy,
+    request::{CoapClient, Request, RequestMethod},
+    response::{Response, ResponseCode},
+    server::CoapServer,
+};
+
+use common::{hello, serve, spawn};
+
+#[test]
+fn proxy_decrements_hop_limit() {
+    let origin = serve(|req: &Request| {
+        let mut response = Response::new(ResponseCode::Content);
+        response.set_body(req.get_hop_limit().unwrap().to_string().into_bytes());
+        response
+    });
+    let upstream = CoapClient::new();
+    let proxy = serve(move |req: &Request| {
+        let hop_limit = match hop_limit::next_hop_limit(req, "proxy") {
+            Ok(hop_limit) => hop_limit,
+            Err(response) => return response,
+        };
+        let mut forward = upstream.new_request(req.get_method(), &origin).unwrap();
+        forward.set_hop_limit(hop_limit);
+        upstream.send_request(forward).unwrap().unwrap()
+    });
+
+    let client = CoapClient::new();
+    let mut req = client.new_request(RequestMethod::Get, &proxy).unwrap();
+    req.set_hop_limit(3);
+    let res = client.send_request(req).unwrap().unwrap();
+    assert_eq!(res.get_body(), b"2");
+
+    let mut req = client.new_request(RequestMethod::Get, &proxy).unwrap();
+    req.set_hop_limit(1);
+    let res = client.send_request(req).unwrap().unwrap();
+    assert_eq!(res.get_response_code(), ResponseCode::HopLimitReached);
+}
+
+#[test]
+fn forward_proxy_caches_and_revalidates() {
+    let fetched = Arc::new(AtomicUsize::new(0));
+    let counter = fetched.clone();
+    let origin = serve(move |req: &Request| {
+        if req.get_option(OptionEnum::ETag).is_some_and(|tags| tags.contains(&vec![1])) {
+            let mut valid = Response::new(ResponseCode::Valid);
+            valid.set_option(OptionEnum::ETag, vec![1]);
+            valid.set_option(OptionEnum::MaxAge, vec![0]);
+            return valid;
+        }
+        counter.fetch_add(1, Ordering::SeqCst);
+        let mut response = Response::new(ResponseCode::Content);
+        response.set_option(OptionEnum::ETag, vec![1]);
+        // "stale" is outdated at once and has to be revalidated every time
+        let max_age = if req.get_path() == "stale" { 0 } else { 60 };
+        response.set_option(OptionEnum::MaxAge, vec![max_age]);
+        response.set_body(Vec::from(req.get_path()));
+        response
+    });
+
+    let mut proxy = CoapServer::bind("127.0.0.1:0").unwrap();
+    proxy.set_forward_proxy(Some(ForwardProxy::new()));
+    let proxy_uri = format!("coap://{}/", spawn(proxy, hello));
+
+    let client = CoapClient::new();
+    let get = |uri: String| {
+        let mut req = client.new_request(RequestMethod::Get, &proxy_uri).unwrap();
+        req.set_option(OptionEnum::ProxyUri, uri.into_bytes());
+        client.send_request(req).unwrap().unwrap()
+    };
+    for _ in 0..2 {
+        assert_eq!(get(format!("{}/fresh", origin)).get_body(), b"fresh");
+    }
+    assert_eq!(fetched.load(Ordering::SeqCst), 1);
+    for _ in 0..2 {
+        let res = get(format!("{}/stale", origin));
+        assert_eq!(res.get_response_code(), ResponseCode::Content);
+        assert_eq!(res.get_body(), b"stale");
+    }
+    assert_eq!(fetched.load(Ordering::SeqCst), 2);
+
+    let res = get(String::from("ftp://127.0.0.1/"));
+    assert_eq!(res.get_response_code(), ResponseCode::ProxyingNotSupported);
+    // the origin is no proxy
+    let mut req = client.new_request(RequestMethod::Get, &format!("{}/", origin)).unwrap();
+    req.set_option(OptionEnum::ProxyUri, Vec::from("coap://127.0.0.1/"));
+    let res = client.send_request(req).unwrap().unwrap();
+    assert_eq!(res.get_response_code(), ResponseCode::ProxyingNotSupported);
+}
@@ -0,0 +1,200 @@
+mod common;
+
+use std::{
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, SeedableRng};
+
+use coap::{
+    request::{CoapClient, Request, RequestMethod},
+    response::{Response, ResponseCode},
+    server::CoapServer,
+    simulator::LinkConditions,
+    transmission::TransmissionParameters,
+    transport::{MemoryNetwork, TcpTransport},
+};
+
+use common::{hello, spawn};
+
+#[test]
+fn client_server_over_other_transports() {
+    let network = MemoryNetwork::new();
+    spawn(CoapServer::with_transport(network.bind("10.0.0.1:5683".parse().unwrap()).unwrap()), hello);
+    let client = CoapClient::with_transport(network.bind("10.0.0.2:0".parse().unwrap()).unwrap());
+    assert_eq!(client.get("coap://10.0.0.1/mem").unwrap().get_body(), b"hello mem");
+    // the large body goes block-wise over the in-memory network too
+    assert_eq!(client.post("coap://10.0.0.1/mem", vec![b'x'; 3000]).unwrap().get_response_code(), ResponseCode::Content);
+
+    let addr = spawn(CoapServer::with_transport(TcpTransport::listen("127.0.0.1:0").unwrap()), hello);
+    let client = CoapClient::with_transport(TcpTransport::connect(addr).unwrap());
+    assert_eq!(client.get(&format!("coap://{}/tcp", addr)).unwrap().get_body(), b"hello tcp");
+}
+
+#[test]
+fn client_server_over_impaired_network() {
+    let network = MemoryNetwork::new();
+    network.set_rng(StdRng::seed_from_u64(43));
+    network.set_conditions(Some(LinkConditions {
+        loss: 0.1,
+        duplication: 0.1,
+        reordering: 0.2,
+        delay: Duration::from_millis(5),
+        jitter: Duration::from_millis(5),
+        ..LinkConditions::default()
+    }));
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counted = runs.clone();
+    spawn(CoapServer::with_transport(network.bind("10.0.0.1:5683".parse().unwrap()).unwrap()), move |req: &Request| {
+        counted.fetch_add(1, Ordering::SeqCst);
+        let mut response = Response::new(ResponseCode::Content);
+        match req.get_method() {
+            RequestMethod::Post => response.set_body(req.get_body().len().to_string().into_bytes()),
+            _ => response.set_body(vec![b'r'; 3000]),
+        }
+        response
+    });
+
+    let mut client = CoapClient::with_transport(network.bind("10.0.0.2:0".parse().unwrap()).unwrap());
+    client.set_transmission_parameters(TransmissionParameters {
+        ack_timeout: Duration::from_millis(50),
+        max_retransmit: 8,
+        ..TransmissionParameters::default()
+    });
+    assert_eq!(client.post("coap://10.0.0.1/up", vec![b'x'; 3000]).unwrap().get_body(), b"3000");
+    assert_eq!(client.get("coap://10.0.0.1/down").unwrap().get_body(), &vec![b'r'; 3000]);
+    // retransmissions and duplicates reach the handler once per request
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    let stats = network.get_stats();
+    assert!(stats.lost > 0 && stats.duplicated > 0);
+}
+
+#[cfg(feature = "dtls")]
+mod dtls {
+    use std::{
+        io,
+        net::{SocketAddr, UdpSocket},
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use openssl::ssl::{SslContext, SslContextBuilder, SslMethod};
+
+    use coap::{
+        dtls::DtlsTransport,
+        request::CoapClient,
+        response::ResponseCode,
+        server::CoapServer,
+        transport::Transport,
+    };
+
+    use crate::common::{hello, spawn};
+
+    /// the cipher suite RFC 7252 section 9.1.3.1 mandates for PreSharedKey mode
+    fn psk_context(key: &'static [u8; 16]) -> SslContextBuilder {
+        let mut builder = SslContext::builder(SslMethod::dtls()).unwrap();
+        builder.set_cipher_list("PSK-AES128-CCM8").unwrap();
+        builder.set_psk_server_callback(move |_, _, psk| {
+            psk[..16].copy_from_slice(key);
+            Ok(16)
+        });
+        builder.set_psk_client_callback(move |_, _, identity, psk| {
+            identity[..7].copy_from_slice(b"client\0");
+            psk[..16].copy_from_slice(key);
+            Ok(16)
+        });
+        builder
+    }
+
+    /// forwards datagrams between one client and `server`, recording those
+    /// of the server and dropping the one at index `lost`
+    fn relay(server: SocketAddr, lost: usize) -> (SocketAddr, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let record = recorded.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            let mut client = None;
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                if from != server {
+                    client = Some(from);
+                    let _ = socket.send_to(&buf[..len], server);
+                    continue;
+                }
+                let mut recorded = record.lock().unwrap();
+                recorded.push(buf[..len].to_vec());
+                if let Some(client) = client.filter(|_| recorded.len() != lost + 1) {
+                    let _ = socket.send_to(&buf[..len], client);
+                }
+            }
+        });
+        (addr, recorded)
+    }
+
+    #[test]
+    fn client_server_over_dtls() {
+        let addr = spawn(CoapServer::with_transport(DtlsTransport::listen("127.0.0.1:0", psk_context(b"0123456789abcdef")).unwrap()), hello);
+
+        let client = CoapClient::with_transport(DtlsTransport::connect(addr, psk_context(b"0123456789abcdef").build()).unwrap());
+        assert_eq!(client.get(&format!("coaps://{}/secure", addr)).unwrap().get_body(), b"hello secure");
+        // a large body goes block-wise inside the session
+        assert_eq!(client.post(&format!("coaps://{}/secure", addr), vec![b'x'; 3000]).unwrap().get_response_code(), ResponseCode::Content);
+
+        // records the server cannot authenticate are dropped, so a wrong key
+        // shows as a handshake running out of time
+        let err = DtlsTransport::connect_with_timeout(addr, psk_context(b"fedcba9876543210").build(), Duration::from_secs(2)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let transport = DtlsTransport::connect(addr, psk_context(b"0123456789abcdef").build()).unwrap();
+        assert!(transport.send_to(b"x", "127.0.0.1:9".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn dtls_handshake_survives_loss() {
+        let server = DtlsTransport::listen("127.0.0.1:0", psk_context(b"0123456789abcdef")).unwrap();
+        // the flight after the cookie exchange is lost once
+        let (relay, recorded) = relay(server.local_addr().unwrap(), 1);
+        let client = DtlsTransport::connect(relay, psk_context(b"0123456789abcdef").build()).unwrap();
+        client.send_to(b"through", relay).unwrap();
+        let mut buf = [0u8; 64];
+        let (len, _) = server.recv_from(&mut buf, Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(&buf[..len], b"through");
+
+        let recorded = recorded.lock().unwrap();
+        // a handshake record whose first message is a HelloVerifyRequest
+        assert_eq!((recorded[0][0], recorded[0][13]), (22, 3));
+        // the lost flight was sent again
+        assert!(recorded.len() >= 3);
+    }
+
+    #[test]
+    fn idle_dtls_sessions_are_closed() {
+        let server = DtlsTransport::listen("127.0.0.1:0", psk_context(b"0123456789abcdef")).unwrap();
+        server.set_idle_timeout(Duration::from_millis(200));
+        let addr = server.local_addr().unwrap();
+        let client = DtlsTransport::connect(addr, psk_context(b"0123456789abcdef").build()).unwrap();
+        client.send_to(b"hello", addr).unwrap();
+        let mut buf = [0u8; 64];
+        let (_, peer) = server.recv_from(&mut buf, Some(Duration::from_secs(5))).unwrap();
+        assert!(server.send_to(b"back", peer).is_ok());
+
+        let start = Instant::now();
+        let err = loop {
+            match server.send_to(b"back", peer) {
+                Ok(_) => assert!(start.elapsed() < Duration::from_secs(5)),
+                Err(err) => break err,
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        // the server's close_notify ends the session on the client too
+        let start = Instant::now();
+        while client.send_to(b"again", addr).is_ok() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}